@@ -0,0 +1,33 @@
+use anyhow::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::{ClientConfig, ClientRequest};
+use crate::frame::{Command, Frame};
+
+/// Sends a single request to a running server and prints its response as it was sent.
+pub async fn run(config: ClientConfig) -> Result<()> {
+    let mut stream = TcpStream::connect((config.host.as_str(), config.port)).await?;
+    let command = match config.request {
+        ClientRequest::Get { line_number } => Command::Get(line_number),
+        ClientRequest::Quit => Command::Quit,
+        ClientRequest::Shutdown => Command::Shutdown,
+    };
+    let mut request = Frame::encode(&command);
+    // the last line of the file may not have a delimiter, so the response is read until the
+    // server hangs up rather than until a newline
+    if let ClientRequest::Get { .. } = config.request {
+        request.extend_from_slice(&Frame::encode(&Command::Quit));
+    }
+    stream.write_all(&request).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let mut stdout = tokio::io::stdout();
+    stdout.write_all(&response).await?;
+    stdout.flush().await?;
+    if response.starts_with(b"ERR") {
+        anyhow::bail!("the server could not handle the request");
+    }
+    Ok(())
+}
@@ -0,0 +1,243 @@
+use crate::frame;
+use clap::{Args, Parser, Subcommand};
+use std::net::IpAddr;
+
+/// The command line, which either runs the server or, with `client`, sends it a request, or with
+/// `selftest`, checks that a file can be served.
+#[derive(Parser, Debug)]
+#[command(
+    version,
+    about = "Serves lines of an immutable text file over TCP",
+    args_conflicts_with_subcommands = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+
+    #[command(flatten)]
+    pub server: Option<Config>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CliCommand {
+    /// Send a request to a running server and print its response
+    Client(ClientConfig),
+    /// Index a file and time a sweep of GETs across it without starting a server, then exit
+    Selftest(SelftestConfig),
+}
+
+/// Command line configuration for the self-test.
+#[derive(Args, Debug)]
+pub struct SelftestConfig {
+    /// The text file to test
+    pub db_file: String,
+
+    /// How many random lines to GET on top of the first and last
+    #[arg(long, default_value_t = 1000)]
+    pub samples: u64,
+}
+
+/// Command line configuration for the built-in client.
+#[derive(Args, Debug)]
+pub struct ClientConfig {
+    /// The host the server is running on
+    #[arg(long, default_value = "localhost")]
+    pub host: String,
+
+    /// The port the server is listening on
+    #[arg(long, default_value_t = 10497)]
+    pub port: u16,
+
+    #[command(subcommand)]
+    pub request: ClientRequest,
+}
+
+#[derive(Subcommand, Debug, Clone, Copy)]
+pub enum ClientRequest {
+    /// Get a line by its number, starting at 1
+    Get { line_number: u32 },
+    /// Close the connection
+    Quit,
+    /// Shut the server down
+    Shutdown,
+}
+
+/// Command line configuration for the server.
+#[derive(Args, Debug, Clone)]
+pub struct Config {
+    /// The text file to serve lines from
+    pub db_file: String,
+
+    /// The port to listen on
+    #[arg(long, default_value_t = 10497)]
+    pub port: u16,
+
+    /// The address to listen on, IPv4 or IPv6, e.g. 127.0.0.1 or ::
+    #[arg(long, default_value = "0.0.0.0")]
+    pub bind_address: IpAddr,
+
+    /// With an IPv6 bind address of ::, take IPv4 connections as well
+    #[arg(long)]
+    pub dual_stack: bool,
+
+    /// The most new connections to accept each second, closing any more than that straight away.
+    /// A second's worth may come in at once.  Unlimited by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub accept_rate: Option<u64>,
+
+    /// The most connections that may be open at once from any one IP address, so that a single
+    /// misbehaving host can't take them all.  Unlimited by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_connections_per_ip: Option<u64>,
+
+    /// The most GETs that may be reading from the data file at once across every connection, so
+    /// that a burst of them can't swamp the disk with seeks.  Unlimited by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_concurrent_reads: Option<u64>,
+
+    /// Only serve the lines start:end of the file, both included, for sharding it across servers
+    #[arg(long, value_parser = parse_line_range)]
+    pub line_range: Option<(u64, u64)>,
+
+    /// The largest response, in bytes, that a single multi-line command may send
+    #[arg(long, default_value_t = 16 * 1024 * 1024)]
+    pub max_response_size: u64,
+
+    /// The longest args, in bytes, that a client may declare with a length prefix.  A client that
+    /// declares more is sent ERR 413 and disconnected without the args being read
+    #[arg(long, default_value_t = frame::MAX_ARGS_LEN as u64, value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_args_len: u64,
+
+    /// The most memory, in bytes, that the index and line cache may take up.  The server refuses to
+    /// start when the index is over it, and caps the line cache to fit in what's left
+    #[arg(long)]
+    pub max_memory: Option<u64>,
+
+    /// Build the index afresh on every start without loading or saving `<file>.index`
+    #[arg(long)]
+    pub no_persist_index: bool,
+
+    /// Flush the saved index to disk before it's renamed into place, and its directory after, so a
+    /// power loss right after it's saved can't leave it empty or cut short
+    #[arg(long)]
+    pub fsync_index: bool,
+
+    /// Keep the index sparse for a file with more than this many lines, with only every
+    /// --sparse-index-every lines in it, so a GET reads on from the last line before it that's kept
+    #[arg(long)]
+    pub sparse_index_above: Option<u64>,
+
+    /// How many lines apart the lines that a sparse index keeps are
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u64).range(2..))]
+    pub sparse_index_every: u64,
+
+    /// The size, in bytes, of the buffer the file is read through; larger suits files with long
+    /// lines
+    #[arg(long, default_value_t = 8 * 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    pub read_buffer_size: u64,
+
+    /// Send as many of the lines of a GET RANGE that's over --max-response-size as fit, followed by
+    /// TRUNCATED and the line to carry on from, rather than ERR 413
+    #[arg(long)]
+    pub truncate_large_ranges: bool,
+
+    /// Copy GET RANGE and GET ALL responses through the server's memory even where they could be
+    /// sent straight from the file with sendfile
+    #[arg(long)]
+    pub no_zero_copy: bool,
+
+    /// Serve GETs for the lines that have been indexed while the rest of the index is still being
+    /// built, rather than responding with ERR 503 to everything but HEALTH until it's done
+    #[arg(long)]
+    pub serve_while_building: bool,
+
+    /// Leave a UTF-8 byte order mark at the start of the file out of the first line
+    #[arg(long)]
+    pub skip_bom: bool,
+
+    /// How long, in milliseconds, a client may send nothing before it's sent IDLE as a warning.  Off
+    /// by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub idle_warning_ms: Option<u64>,
+
+    /// How long, in milliseconds, a client that was sent IDLE may keep sending nothing before it's
+    /// disconnected
+    #[arg(long, default_value_t = 30_000)]
+    pub idle_close_ms: u64,
+
+    /// How long, in milliseconds, a connection may go without reading a request or writing a
+    /// response before the server's reaper closes it.  Off by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub reap_inactive_ms: Option<u64>,
+
+    /// How long, in milliseconds, a client may send nothing before its connection closes its
+    /// handles to the data file, which are opened again by its next request.  A file that has been
+    /// replaced since the connection opened it isn't served.  Off by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub release_file_idle_ms: Option<u64>,
+
+    /// How many seconds clients are told to wait before reconnecting when the server shuts down
+    #[arg(long)]
+    pub shutdown_retry_after: Option<u64>,
+
+    /// How long, in milliseconds, a response may take to write before the client is disconnected
+    #[arg(long, default_value_t = 30_000)]
+    pub write_timeout_ms: u64,
+
+    /// How many GETs that a client has pipelined may be looked up at once; 1 handles every request
+    /// in turn
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    pub pipeline_window: u64,
+
+    /// How many threads the runtime serves connections on, one per core by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub workers: Option<u64>,
+
+    /// Count how many times each line has been sent by a GET and respond to GET with
+    /// `OK served=<count>`, which keeps a counter for every line in memory
+    #[arg(long)]
+    pub served_counts: bool,
+
+    /// Answer FIND from a map of every line's content, built on the first FIND and kept in memory
+    #[arg(long)]
+    pub find_cache: bool,
+
+    /// Keep the N lines that GETs read most recently in memory, so that popular lines don't go to
+    /// the disk.  Off by default
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    pub line_cache: Option<u64>,
+
+    /// The most detailed level to log at: error, warn, info, debug or trace
+    #[arg(long, env = "LINE_SERVER_LOG", default_value_t = log::LevelFilter::Info)]
+    pub log_level: log::LevelFilter,
+
+    /// Also log every request to this file in Common Log Format, with the client's address, the
+    /// command, the status of the response and the bytes sent
+    #[arg(long)]
+    pub access_log: Option<String>,
+
+    /// Log every request and its response at trace level, numbered within their connection, which
+    /// overrides --log-level
+    #[arg(long)]
+    pub trace_requests: bool,
+
+    /// Accept the commands that are only meant for debugging clients, like COMPUTE CHECKSUM
+    #[arg(long)]
+    pub debug_commands: bool,
+
+    /// Number the connections conn-1, conn-2, ... instead of giving them random ids, for tests
+    #[arg(long)]
+    pub sequential_conn_ids: bool,
+}
+
+fn parse_line_range(value: &str) -> Result<(u64, u64), String> {
+    let (start, end) = value
+        .split_once(':')
+        .ok_or("expected the first and last line as start:end")?;
+    let start: u64 = start.parse().map_err(|e| format!("bad start line: {e}"))?;
+    let end: u64 = end.parse().map_err(|e| format!("bad end line: {e}"))?;
+    if start == 0 || start > end {
+        return Err("lines start at 1 and the start can't be after the end".to_string());
+    }
+    Ok((start, end))
+}
@@ -1,110 +1,1342 @@
-use log::info;
-use std::collections::HashMap;
+use anyhow::Context;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Seek};
-use std::sync::Arc;
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
 
 use anyhow::Result;
 
+/// The byte that ends each line of the database file
+pub const RECORD_DELIMITER: u8 = b'\n';
+/// The format of the saved index, which is written ahead of it so that an index saved in another
+/// format is rebuilt rather than misread.  Indexes saved before the format was written are 1.
+const INDEX_FORMAT_VERSION: u32 = 2;
+/// How many times a read that fails while building the index is tried before the build fails
+const INDEX_READ_ATTEMPTS: u32 = 5;
+/// The wait before the first retry of a failed read, doubling with each retry after it
+const INDEX_READ_BACKOFF_MS: u64 = 50;
+/// How many lines are indexed between each time they're handed to the `IndexProgress`
+const PROGRESS_BATCH_LINES: usize = 4096;
+/// The UTF-8 byte order mark that some editors, mostly on Windows, start their files with
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+/// When the index is kept sparse: once the file has more than `above` lines, only every `every`th
+/// line from the first is kept.
+#[derive(Clone, Copy, Debug)]
+pub struct Sparsity {
+    pub above: u64,
+    pub every: u64,
+}
+
+/// The offset that each line starts at, keyed by line number, with an entry for the end of the file
+/// after the last line.  A sparse index only has every `every`th line from the first, along with the
+/// end of the file, and the lines in between are found by reading on from the last one it has.
+pub struct Index {
+    offsets: HashMap<u64, u64>,
+    line_count: u64,
+    every: u64,
+}
+
+impl Index {
+    /// Works out how sparse `offsets` is from the lines it has, which is how a saved index is read
+    /// back without saving anything more than the map.
+    fn new(offsets: HashMap<u64, u64>) -> Index {
+        let line_count = offsets.keys().max().map_or(0, |end| end - 1);
+        let every = if offsets.len() as u64 == line_count + 1 {
+            1
+        } else {
+            // the first line kept after line 1, unless the only other entry is the end of the file
+            offsets
+                .keys()
+                .filter(|&&line_number| line_number > 1 && line_number <= line_count)
+                .min()
+                .map_or(line_count, |line_number| line_number - 1)
+        };
+        Index {
+            offsets,
+            line_count,
+            every,
+        }
+    }
+
+    /// Whether the index is laid out the way `sparsity` would build it for this file.  Any step of
+    /// at least the line count keeps only the first line, so they're all the same.
+    fn is_laid_out_for(&self, sparsity: Option<Sparsity>) -> bool {
+        let every = match sparsity {
+            Some(sparsity) if self.line_count > sparsity.above => sparsity.every,
+            _ => 1,
+        };
+        let most = self.line_count.max(1);
+        self.every.min(most) == every.min(most)
+    }
+
+    pub fn line_count(&self) -> u64 {
+        self.line_count
+    }
+
+    /// Where the index has the file ending, which is the offset it keeps for one past the last line.
+    fn end(&self) -> Option<u64> {
+        self.offsets.get(&(self.line_count + 1)).copied()
+    }
+
+    /// Whether the index keeps the offset of `line_number`, which the end of the file always is.
+    fn keeps(&self, line_number: u64) -> bool {
+        (line_number - 1).is_multiple_of(self.every) || line_number == self.line_count + 1
+    }
+
+    /// The last line at or before `line_number` that the index keeps, along with its offset.
+    fn nearest(&self, line_number: u64) -> (u64, u64) {
+        let kept = match self.offsets.get(&line_number) {
+            Some(_) => line_number,
+            None => line_number - (line_number - 1) % self.every,
+        };
+        (kept, self.offsets[&kept])
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    /// The requested line is not in the index
+    LineNotFound,
+    /// The line is in the index but it could not be read from the file
+    Io(std::io::Error),
+    /// The cursor is already on the last line
+    EndOfFile,
+    /// The cursor is already on the first line
+    StartOfFile,
+    /// The line is in the file but outside the window of lines that this server serves
+    OutsideWindow,
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::LineNotFound => write!(f, "line number not found in index"),
+            SessionError::Io(e) => write!(f, "failed to read line from the database file: {}", e),
+            SessionError::EndOfFile => write!(f, "already at the last line"),
+            SessionError::StartOfFile => write!(f, "already at the first line"),
+            SessionError::OutsideWindow => write!(f, "line number is outside the served window"),
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+impl From<std::io::Error> for SessionError {
+    fn from(e: std::io::Error) -> Self {
+        SessionError::Io(e)
+    }
+}
+
+/// Whether a read error may go away if the read is tried again, rather than it being a problem with
+/// the file itself.
+fn is_transient(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        std::io::ErrorKind::Interrupted
+            | std::io::ErrorKind::WouldBlock
+            | std::io::ErrorKind::TimedOut
+            | std::io::ErrorKind::ResourceBusy
+    )
+}
+
+/// The device and inode of a file, which tell it apart from another one put at the same path.
+#[cfg(unix)]
+fn file_identity(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_identity(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// What LENGTH STATS sends about the lengths of the lines.
+pub struct LengthStats {
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub median: u64,
+    pub p90: u64,
+    pub p99: u64,
+}
+
+/// A connection's view of the file.  It keeps the index it was made with and its own handle to the
+/// file as it was when it was opened, so that whatever happens on disk or to the server's index
+/// afterwards, it serves the same lines for as long as it lives.
 pub struct Session {
-    reader: BufReader<File>,
-    index: Arc<HashMap<u64, u64>>,
+    // let go of while the connection is idle, and opened again by the next read
+    reader: Option<BufReader<File>>,
+    db_file: String,
+    read_buffer_size: usize,
+    // which file the session opened first, so that a reopen can tell if the path has another one
+    identity: Option<(u64, u64)>,
+    index: Arc<Index>,
+    // the line that NEXT and PREV move from, 0 being before the first line
+    cursor: u64,
+    // the first and last lines that may be read, when the server only serves part of the file
+    line_range: Option<(u64, u64)>,
 }
 
 impl Session {
-    pub async fn new(reader: BufReader<File>, index: Arc<HashMap<u64, u64>>) -> Result<Session> {
-        Ok(Session { reader, index })
+    pub async fn new(
+        db_file: &str,
+        read_buffer_size: usize,
+        index: Arc<Index>,
+        line_range: Option<(u64, u64)>,
+    ) -> Result<Session> {
+        let file = File::open(db_file)?;
+        let identity = file_identity(&file.metadata()?);
+        Ok(Session {
+            reader: Some(BufReader::with_capacity(read_buffer_size, file)),
+            db_file: String::from(db_file),
+            read_buffer_size,
+            identity,
+            index,
+            cursor: 0,
+            line_range,
+        })
+    }
+
+    pub async fn get(&mut self, line_number: u64) -> Result<String, SessionError> {
+        // the entry for the end of the file is in the index but isn't a line
+        if line_number == 0 || line_number > self.line_count() {
+            return Err(SessionError::LineNotFound);
+        }
+        self.check_window(line_number)?;
+        let offset = self.offset(line_number)?;
+        self.reader()?.seek(std::io::SeekFrom::Start(offset))?;
+        let mut line = String::new();
+        self.reader()?.read_line(&mut line)?;
+        Ok(line)
     }
 
-    pub async fn get(&mut self, line_number: u64) -> Result<String> {
-        let byte_offset = self.index.get(&(line_number));
-        match byte_offset {
-            Some(offset) => {
-                self.reader.seek(std::io::SeekFrom::Start(*offset))?;
-                let mut line = String::new();
-                self.reader.read_line(&mut line)?;
-                Ok(line)
+    /// Where `line_number` starts, which a sparse index may not have, in which case the lines after
+    /// the last one it has before it are read until it's reached.
+    fn offset(&mut self, line_number: u64) -> Result<u64, SessionError> {
+        let (mut kept, mut offset) = self.index.nearest(line_number);
+        if kept < line_number {
+            self.reader()?.seek(std::io::SeekFrom::Start(offset))?;
+            let mut buf = Vec::new();
+            while kept < line_number {
+                buf.clear();
+                offset += self.reader()?.read_until(RECORD_DELIMITER, &mut buf)? as u64;
+                kept += 1;
             }
-            None => Err(anyhow::Error::msg("line number not found in index")),
         }
+        Ok(offset)
+    }
+
+    pub fn cursor(&self) -> u64 {
+        self.cursor
+    }
+
+    /// Moves the cursor to `line_number`, or to before the first line when it's 0.
+    pub fn seek(&mut self, line_number: u64) -> Result<(), SessionError> {
+        if line_number > self.line_count() {
+            return Err(SessionError::LineNotFound);
+        }
+        self.cursor = line_number;
+        Ok(())
+    }
+
+    /// Reads the line after the cursor and advances the cursor to it.
+    pub async fn next(&mut self) -> Result<String, SessionError> {
+        if self.cursor >= self.line_count() {
+            return Err(SessionError::EndOfFile);
+        }
+        let line = self.get(self.cursor + 1).await?;
+        self.cursor += 1;
+        Ok(line)
+    }
+
+    /// Reads the line before the cursor and rewinds the cursor to it.
+    pub async fn prev(&mut self) -> Result<String, SessionError> {
+        if self.cursor <= 1 {
+            return Err(SessionError::StartOfFile);
+        }
+        let line = self.get(self.cursor - 1).await?;
+        self.cursor -= 1;
+        Ok(line)
+    }
+
+    /// The number of lines in the file.  The index also holds an entry for the offset just past the
+    /// last line (the end of the file), which isn't a line of its own.
+    pub fn line_count(&self) -> u64 {
+        self.index.line_count()
+    }
+
+    /// The number of the line whose bytes, including its delimiter, contain `offset`.  Lines start
+    /// at increasing offsets, so the lines the index has can be binary searched by their offsets.
+    pub fn line_at_byte(&mut self, offset: u64) -> Result<u64, SessionError> {
+        let line_count = self.line_count();
+        if line_count == 0
+            || offset < self.index.offsets[&1]
+            || offset >= self.index.offsets[&(line_count + 1)]
+        {
+            return Err(SessionError::LineNotFound);
+        }
+        // the line is kept by the index at every step, so the search is over the steps and closes
+        // in on the last kept line starting at or before offset
+        let every = self.index.every;
+        let (mut low, mut high) = (0, (line_count - 1) / every);
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            if self.index.offsets[&(1 + mid * every)] <= offset {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        let mut line_number = 1 + low * every;
+        if every == 1 {
+            return Ok(line_number);
+        }
+        // in a sparse index the lines after it are read until one of them contains offset
+        let mut end = self.index.offsets[&line_number];
+        self.reader()?.seek(std::io::SeekFrom::Start(end))?;
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            match self.reader()?.read_until(RECORD_DELIMITER, &mut buf)? {
+                // the file is shorter than when it was indexed
+                0 => return Err(SessionError::LineNotFound),
+                num_bytes => end += num_bytes as u64,
+            }
+            if offset < end {
+                return Ok(line_number);
+            }
+            line_number += 1;
+        }
+    }
+
+    /// The first and last lines in the window that lie wholly within the bytes `start..end`,
+    /// delimiter included, or None when no line does.  Lines that do but are all outside the window
+    /// are refused like a GET of them.
+    pub fn lines_within(
+        &mut self,
+        start: u64,
+        end: u64,
+    ) -> Result<Option<(u64, u64)>, SessionError> {
+        let data_start = self.index.offsets[&1];
+        let first = match self.line_at_byte(start) {
+            Ok(line_number) if self.offset(line_number)? == start => line_number,
+            // the line starts before the range so it isn't in it
+            Ok(line_number) => line_number + 1,
+            Err(SessionError::LineNotFound) if start < data_start => 1,
+            Err(SessionError::LineNotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        // the line that end falls in is cut short by it, unless end is past the last line
+        let last = match self.line_at_byte(end) {
+            Ok(line_number) => line_number - 1,
+            Err(SessionError::LineNotFound) if end < data_start => return Ok(None),
+            Err(SessionError::LineNotFound) => self.line_count(),
+            Err(e) => return Err(e),
+        };
+        if first > last {
+            return Ok(None);
+        }
+        let (window_first, window_last) = self.window();
+        let (first, last) = (first.max(window_first), last.min(window_last));
+        if first > last {
+            return Err(SessionError::OutsideWindow);
+        }
+        Ok(Some((first, last)))
+    }
+
+    /// The first and last lines in the file that may be read, which is all of them unless the
+    /// server was given a window.  The last line is before the first in a file with no lines, or
+    /// when the window starts past the end of the file.
+    pub fn window(&self) -> (u64, u64) {
+        match self.line_range {
+            Some((start, end)) => (start, end.min(self.line_count())),
+            None => (1, self.line_count()),
+        }
+    }
+
+    /// Splits the lines that may be read into `chunks` ranges of consecutive lines, in order, whose
+    /// sizes differ by at most one line, with the longer ones first.  There's never an empty range,
+    /// so there are fewer of them than `chunks` when there are fewer lines than that.
+    pub fn chunk_plan(&self, chunks: u64) -> impl Iterator<Item = (u64, u64)> {
+        let (first, last) = self.window();
+        let lines = (last + 1).saturating_sub(first);
+        let chunks = chunks.min(lines);
+        let (size, longer) = match chunks {
+            0 => (0, 0),
+            _ => (lines / chunks, lines % chunks),
+        };
+        (0..chunks).scan(first, move |start, chunk| {
+            let len = size + u64::from(chunk < longer);
+            let range = (*start, *start + len - 1);
+            *start += len;
+            Some(range)
+        })
+    }
+
+    /// Whether `line_number` is a line that may be read, which the index alone can tell.  The
+    /// window never starts before line 1, so line 0 is never in it.
+    pub fn exists(&self, line_number: u64) -> bool {
+        let (first, last) = self.window();
+        (first..=last).contains(&line_number)
+    }
+
+    fn check_window(&self, line_number: u64) -> Result<(), SessionError> {
+        match self.line_range {
+            Some((start, end)) if line_number < start || line_number > end => {
+                Err(SessionError::OutsideWindow)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The number of bytes taken up by the lines `start..=end`, including their delimiters.
+    pub fn range_size(&mut self, start: u64, end: u64) -> Result<u64, SessionError> {
+        if start == 0 || start > end || end > self.line_count() {
+            return Err(SessionError::LineNotFound);
+        }
+        self.check_window(start)?;
+        self.check_window(end)?;
+        Ok(self.offset(end + 1)? - self.offset(start)?)
+    }
+
+    /// Where `line_number` starts in the file and its length including its delimiter, from the
+    /// index alone when it's dense.
+    pub fn line_span(&mut self, line_number: u64) -> Result<(u64, u64), SessionError> {
+        let length = self.range_size(line_number, line_number)?;
+        Ok((self.offset(line_number)?, length))
+    }
+
+    /// Where the lines `start..=end` start and end in the file, so they can be sent as they are.
+    pub fn byte_span(&mut self, start: u64, end: u64) -> Result<(u64, u64), SessionError> {
+        self.range_size(start, end)?;
+        Ok((self.offset(start)?, self.offset(end + 1)?))
+    }
+
+    /// Reads the `len` bytes of the file starting at `offset`.
+    pub fn read_bytes(&mut self, offset: u64, len: u64) -> Result<Vec<u8>, SessionError> {
+        self.reader()?.seek(std::io::SeekFrom::Start(offset))?;
+        let mut bytes = vec![0; len as usize];
+        self.reader()?.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Opens the data file again if it was released, so that it can be sent from with `file`.
+    pub fn open_file(&mut self) -> Result<(), SessionError> {
+        self.reader().map(|_| ())
+    }
+
+    /// The data file, for sending parts of it without reading them through the session, which is
+    /// None while it's released.
+    pub fn file(&self) -> Option<&File> {
+        self.reader.as_ref().map(BufReader::get_ref)
+    }
+
+    /// The reader of the data file, which is opened again if it was released.  The path may have a
+    /// different file by then, and the session only ever serves the one it opened first, so a
+    /// reopen of anything else fails instead.
+    fn reader(&mut self) -> Result<&mut BufReader<File>, SessionError> {
+        if self.reader.is_none() {
+            let file = File::open(&self.db_file)?;
+            if file_identity(&file.metadata()?) != self.identity {
+                return Err(SessionError::Io(std::io::Error::other(format!(
+                    "{} was replaced after the session opened it",
+                    self.db_file
+                ))));
+            }
+            self.reader = Some(BufReader::with_capacity(self.read_buffer_size, file));
+        }
+        Ok(self.reader.as_mut().unwrap())
+    }
+
+    /// Closes the data file until the next read needs it, so that an idle session doesn't hold a
+    /// descriptor.  Whether there was one open to close.
+    pub fn release_file(&mut self) -> bool {
+        self.reader.take().is_some()
+    }
+
+    pub fn holds_file(&self) -> bool {
+        self.reader.is_some()
+    }
+
+    /// Reads the lines `start..=end` with a single seek, since the lines are laid out one after
+    /// another in the file.
+    pub async fn get_range(&mut self, start: u64, end: u64) -> Result<Vec<String>, SessionError> {
+        self.range_size(start, end)?;
+        let offset = self.offset(start)?;
+        self.reader()?.seek(std::io::SeekFrom::Start(offset))?;
+        let mut lines = Vec::with_capacity((end - start + 1) as usize);
+        for _ in start..=end {
+            let mut line = String::new();
+            self.reader()?.read_line(&mut line)?;
+            lines.push(line);
+        }
+        Ok(lines)
+    }
+
+    /// Streams every line in the window from the start, passing `f` each line number along with
+    /// the line's content without its delimiter.  Lines outside the window are never passed on, so
+    /// a shard only finds its own lines.
+    pub async fn scan<F: FnMut(u64, &[u8])>(&mut self, f: F) -> Result<(), SessionError> {
+        let (start, end) = self.window();
+        // a window that starts past the end of the file has no lines to stream
+        if start > end {
+            return Ok(());
+        }
+        self.stream(start, end, f)
+    }
+
+    /// Streams the lines `start..=end` like `scan`, checking that they're all in the file first.
+    pub async fn scan_range<F: FnMut(u64, &[u8])>(
+        &mut self,
+        start: u64,
+        end: u64,
+        f: F,
+    ) -> Result<(), SessionError> {
+        self.range_size(start, end)?;
+        self.stream(start, end, f)
+    }
+
+    fn stream<F: FnMut(u64, &[u8])>(
+        &mut self,
+        start: u64,
+        end: u64,
+        mut f: F,
+    ) -> Result<(), SessionError> {
+        let offset = self.offset(start)?;
+        self.reader()?.seek(std::io::SeekFrom::Start(offset))?;
+        let mut buf = Vec::new();
+        for line_number in start..=end {
+            buf.clear();
+            self.reader()?.read_until(RECORD_DELIMITER, &mut buf)?;
+            f(line_number, buf.strip_suffix(b"\n").unwrap_or(&buf));
+        }
+        Ok(())
+    }
+
+    /// The first of the lines in the window with the most bytes, or the fewest, without their
+    /// delimiters.  A dense index has every line's length, so the lines are only read when the
+    /// index is sparse.
+    pub async fn extreme_line(&mut self, longest: bool) -> Result<Option<u64>, SessionError> {
+        let mut extreme: Option<(u64, u64)> = None;
+        self.line_lengths(|line_number: u64, length: u64| {
+            let beats = |(_, extreme_length): (u64, u64)| {
+                if longest {
+                    length > extreme_length
+                } else {
+                    length < extreme_length
+                }
+            };
+            // ties go to the first line with the length
+            if extreme.is_none_or(beats) {
+                extreme = Some((line_number, length));
+            }
+        })?;
+        Ok(extreme.map(|(line_number, _)| line_number))
+    }
+
+    /// The shortest, longest and mean lengths of the lines in the window without their
+    /// delimiters, and the median, 90th and 99th percentiles by the nearest rank method, or
+    /// nothing when there are no lines.
+    pub async fn length_stats(&mut self) -> Result<Option<LengthStats>, SessionError> {
+        let mut lengths = Vec::new();
+        self.line_lengths(|_, length| lengths.push(length))?;
+        if lengths.is_empty() {
+            return Ok(None);
+        }
+        lengths.sort_unstable();
+        // the nearest rank is the smallest length that at least p percent of the lines are no
+        // longer than, which is always one of the lengths
+        let percentile = |p: u64| lengths[((p * lengths.len() as u64).div_ceil(100) - 1) as usize];
+        Ok(Some(LengthStats {
+            min: lengths[0],
+            max: lengths[lengths.len() - 1],
+            mean: lengths.iter().sum::<u64>() as f64 / lengths.len() as f64,
+            median: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+        }))
+    }
+
+    /// Passes `f` the number and length without its delimiter of each line in the window.  A dense
+    /// index has every line's length, so the lines are only read when the index is sparse.
+    fn line_lengths<F: FnMut(u64, u64)>(&mut self, mut f: F) -> Result<(), SessionError> {
+        let (first, last) = self.window();
+        if first > last {
+            return Ok(());
+        }
+        if self.index.every == 1 {
+            // every line has a delimiter but the last, which may not
+            let end = self.offset(self.line_count() + 1)?;
+            let last_delimited = self.read_bytes(end - 1, 1)? == [RECORD_DELIMITER];
+            for line_number in first..=last {
+                let span =
+                    self.index.offsets[&(line_number + 1)] - self.index.offsets[&line_number];
+                let delimited = line_number < self.line_count() || last_delimited;
+                f(line_number, span - delimited as u64);
+            }
+            Ok(())
+        } else {
+            self.stream(first, last, |line_number, line| {
+                f(line_number, line.len() as u64)
+            })
+        }
+    }
+
+    /// Streams the lines that start with `prefix`, passing `f` each one's number and content
+    /// without its delimiter.  A line shorter than the prefix never matches, and an empty prefix
+    /// matches every line.
+    pub async fn scan_prefix<F: FnMut(u64, &[u8])>(
+        &mut self,
+        prefix: &[u8],
+        mut f: F,
+    ) -> Result<(), SessionError> {
+        self.scan(|line_number, line| {
+            if line.starts_with(prefix) {
+                f(line_number, line);
+            }
+        })
+        .await
+    }
+
+    /// Streams the lines whose `column`th field, counting from 0 and split on `delimiter`, is a
+    /// number in `min..=max`, passing `f` each one's number and content without its delimiter.
+    /// Lines without that field or whose field isn't a number are skipped.
+    pub async fn scan_column<F: FnMut(u64, &[u8])>(
+        &mut self,
+        column: usize,
+        delimiter: u8,
+        min: f64,
+        max: f64,
+        mut f: F,
+    ) -> Result<(), SessionError> {
+        self.scan(|line_number, line| {
+            let value = line
+                .split(|&byte| byte == delimiter)
+                .nth(column)
+                .and_then(|field| std::str::from_utf8(field).ok())
+                .and_then(|field| field.trim().parse::<f64>().ok());
+            if value.is_some_and(|value| (min..=max).contains(&value)) {
+                f(line_number, line);
+            }
+        })
+        .await
+    }
+}
+
+/// How many lines start with each byte, counted once for FIRST BYTE HISTOGRAM.
+pub struct FirstByteCounts {
+    /// The lines with nothing before their delimiter
+    pub empty: u64,
+    pub bytes: [u64; 256],
+}
+
+/// Metadata about the database file, which is read once when the database is opened since the
+/// file is immutable.
+#[derive(Clone, Copy, Debug)]
+pub struct FileInfo {
+    pub size: u64,
+    /// Seconds since the unix epoch
+    pub modified: u64,
+}
+
+impl FileInfo {
+    fn read(db_file: &str) -> Result<FileInfo> {
+        let metadata = std::fs::metadata(db_file)?;
+        Ok(FileInfo {
+            size: metadata.len(),
+            modified: metadata
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)?
+                .as_secs(),
+        })
+    }
+}
+
+/// The lines indexed so far while the index is being built, so that they can be served before the
+/// rest of the file has been read.  They're handed over in batches to keep the lock quiet.
+#[derive(Default)]
+pub struct IndexProgress {
+    // where each line indexed so far starts, followed by where the last of them ends
+    offsets: RwLock<Vec<u64>>,
+}
+
+impl IndexProgress {
+    /// Where `line_number` starts and ends, when it has been indexed yet.
+    pub fn span(&self, line_number: u64) -> Option<(u64, u64)> {
+        let index = line_number.checked_sub(1)? as usize;
+        let offsets = self.offsets.read().unwrap();
+        Some((*offsets.get(index)?, *offsets.get(index + 1)?))
+    }
+
+    fn publish(&self, offsets: &[u64]) {
+        self.offsets.write().unwrap().extend_from_slice(offsets);
+    }
+}
+
+/// The lines that GETs read most recently, kept in memory so that popular lines skip the disk.  The
+/// line that was used longest ago is let go to make room for a new one.
+struct LineCache {
+    capacity: usize,
+    // each line along with when it was last used
+    lines: HashMap<u64, (String, u64)>,
+    // the line numbers by when they were last used, oldest first
+    uses: BTreeMap<u64, u64>,
+    clock: u64,
+}
+
+impl LineCache {
+    fn new(capacity: usize) -> LineCache {
+        LineCache {
+            capacity,
+            // grown as lines come in rather than all at once, since the capacity is only capped to
+            // --max-memory once the index has been built
+            lines: HashMap::new(),
+            uses: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn get(&mut self, line_number: u64) -> Option<String> {
+        self.clock += 1;
+        let (line, used) = self.lines.get_mut(&line_number)?;
+        self.uses.remove(used);
+        *used = self.clock;
+        self.uses.insert(self.clock, line_number);
+        Some(line.clone())
+    }
+
+    fn insert(&mut self, line_number: u64, line: &str) {
+        self.clock += 1;
+        if let Some((_, used)) = self
+            .lines
+            .insert(line_number, (line.to_string(), self.clock))
+        {
+            self.uses.remove(&used);
+        } else if self.lines.len() > self.capacity {
+            if let Some((_, oldest)) = self.uses.pop_first() {
+                self.lines.remove(&oldest);
+            }
+        }
+        self.uses.insert(self.clock, line_number);
     }
 }
 
 pub struct Database {
     db_file: String,
-    index: Arc<HashMap<u64, u64>>,
+    index: Arc<Index>,
+    file_info: FileInfo,
+    // every line's content mapped to the numbers of the lines that have it, built on the first FIND
+    content_map: OnceLock<HashMap<Vec<u8>, Vec<u64>>>,
+    // the hash of the whole file, worked out on the first FILE CHECKSUM since the file never changes
+    file_checksum: OnceLock<String>,
+    // how many words the file has, counted on the first WORD COUNT
+    word_count: OnceLock<u64>,
+    // how many lines start with each byte, counted on the first FIRST BYTE HISTOGRAM
+    first_bytes: OnceLock<FirstByteCounts>,
+    // the capacity of the buffer that every reader of the file is wrapped in
+    read_buffer_size: usize,
+    // the offset of the first line, which is past any byte order mark that's skipped
+    data_start: u64,
+    line_range: Option<(u64, u64)>,
+    // how many times each line has been sent by a GET, when they're being counted
+    served: Option<Vec<AtomicU64>>,
+    line_cache: Option<Mutex<LineCache>>,
 }
 
 impl Database {
+    #[allow(clippy::too_many_arguments)]
     async fn load_index(
         db_file: &str,
         index_filename: &str,
         serialize_index: bool,
-    ) -> Result<HashMap<u64, u64>> {
+        read_buffer_size: usize,
+        data_start: u64,
+        sparsity: Option<Sparsity>,
+        fsync_index: bool,
+        progress: Option<&IndexProgress>,
+    ) -> Result<Index> {
         let serialized_index_file = index_filename;
+        // another server building the same index at the same time would race this one's writes to
+        // it, so the saved index is only loaded or built by one server at a time
+        let _lock = if serialize_index {
+            Some(Database::lock_index(index_filename)?)
+        } else {
+            None
+        };
         if serialize_index && std::path::Path::new(serialized_index_file).exists() {
             info!(
                 "Loading the saved index from file: {}",
                 serialized_index_file
             );
+            let file_len = std::fs::metadata(db_file)
+                .with_context(|| format!("could not read the size of {db_file}"))?
+                .len();
             // load the index from the file
-            return Ok(rmp_serde::from_read(std::io::BufReader::new(
-                std::fs::File::open(serialized_index_file)?,
-            ))?);
-        } else {
-            // else create a new index
-            Ok(Database::index(db_file, index_filename, serialize_index)?)
+            match Database::read_index(serialized_index_file).map(Index::new) {
+                // an index saved with the other BOM setting starts its first line in the wrong place
+                Ok(index) if index.offsets.get(&1) != Some(&data_start) => warn!(
+                    "The saved index in file {} doesn't start at byte {}, rebuilding it.",
+                    serialized_index_file, data_start
+                ),
+                // the cheap check for an index saved for another file, which won't end where this
+                // one does unless it happens to be the same size
+                Ok(index) if index.end() != Some(file_len) => warn!(
+                    "The saved index in file {} ends at byte {} but the data file is {} bytes, so it's for another file, rebuilding it.",
+                    serialized_index_file,
+                    index.end().unwrap_or(0),
+                    file_len
+                ),
+                Ok(index) if !index.is_laid_out_for(sparsity) => warn!(
+                    "The saved index in file {} isn't as sparse as the configuration asks for, rebuilding it.",
+                    serialized_index_file
+                ),
+                Ok(index) => return Ok(index),
+                Err(e) => warn!(
+                    "Could not load the saved index from file {}, rebuilding it: {:#}",
+                    serialized_index_file, e
+                ),
+            }
+        }
+        // else create a new index
+        Database::index(
+            db_file,
+            index_filename,
+            serialize_index,
+            read_buffer_size,
+            data_start,
+            sparsity,
+            fsync_index,
+            progress,
+        )
+    }
+
+    /// Takes the advisory lock on `<index>.lock`, waiting for another server that holds it to be
+    /// done with the index, which it will have saved by then.  The lock is let go when the returned
+    /// file is dropped.
+    fn lock_index(index_filename: &str) -> Result<File> {
+        let lock_filename = format!("{index_filename}.lock");
+        let file = File::create(&lock_filename)
+            .with_context(|| format!("could not create the index lock file {lock_filename}"))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(std::fs::TryLockError::WouldBlock) => {
+                warn!(
+                    "Another server is using the index in {}, waiting for it to finish.",
+                    index_filename
+                );
+                file.lock().with_context(|| {
+                    format!("could not lock the index lock file {lock_filename}")
+                })?;
+                info!("Took the lock on the index in {}.", index_filename);
+            }
+            Err(std::fs::TryLockError::Error(e)) => {
+                return Err(e)
+                    .with_context(|| format!("could not lock the index lock file {lock_filename}"))
+            }
+        }
+        Ok(file)
+    }
+
+    fn read_index(index_filename: &str) -> Result<HashMap<u64, u64>> {
+        let mut reader = BufReader::new(File::open(index_filename)?);
+        let version: u32 = rmp_serde::from_read(&mut reader)
+            .context("the index does not start with its format version")?;
+        if version != INDEX_FORMAT_VERSION {
+            anyhow::bail!(
+                "the index is format {} but this server reads format {}",
+                version,
+                INDEX_FORMAT_VERSION
+            );
         }
+        Ok(rmp_serde::from_read(reader)?)
     }
 
-    fn index(db_file: &str, index_filename: &str, save: bool) -> Result<HashMap<u64, u64>> {
+    #[allow(clippy::too_many_arguments)]
+    fn index(
+        db_file: &str,
+        index_filename: &str,
+        save: bool,
+        read_buffer_size: usize,
+        data_start: u64,
+        sparsity: Option<Sparsity>,
+        fsync: bool,
+        progress: Option<&IndexProgress>,
+    ) -> Result<Index> {
         info!("Creating a new index for the database file: {}", db_file);
         let mut file = File::open(db_file)?;
-        let mut reader = BufReader::new(&mut file);
+        let mut reader = BufReader::with_capacity(read_buffer_size, &mut file);
+        reader.seek(std::io::SeekFrom::Start(data_start))?;
         let mut index = HashMap::<u64, u64>::new();
 
         // see dwith the first line and its offset
-        index.insert(1, 0);
+        // every line also marks where the one before it ends, so for a 0 byte file this is only
+        // the end of the file and there are no lines at all
+        index.insert(1, data_start);
         // start at line 2 since we seeded with line 1
         let mut current_line = 2;
+        // the end of the file, which is kept whether the index is sparse or not
+        let mut end = (1, data_start);
+        let mut every = 1;
+        // the offsets that haven't been handed to the progress yet, which it takes every line's of
+        // even when the index is sparse
+        let mut unpublished = vec![data_start];
 
         let mut buf = Vec::new();
-        while let Ok(num_bytes) = reader.read_until(0x0A, &mut buf) {
-            if num_bytes == 0 {
+        loop {
+            let num_bytes =
+                Database::read_line_retrying(&mut reader, &mut buf).with_context(|| {
+                    format!("could not read line {} of {}", current_line - 1, db_file)
+                })?;
+            // a retried read may have already put part of the line in buf before it failed
+            if num_bytes == 0 && buf.is_empty() {
                 break;
             }
-            index.insert(current_line, reader.stream_position()?); // TODO: handle the error here
+            let offset = reader.stream_position()?; // TODO: handle the error here
+            end = (current_line, offset);
+            if let Some(sparsity) = sparsity {
+                // once the file is past the line count, the lines that aren't kept are let go
+                if every == 1 && current_line - 1 > sparsity.above {
+                    info!(
+                        "The file has more than {} lines, so the index only keeps every {}th line.",
+                        sparsity.above, sparsity.every
+                    );
+                    every = sparsity.every;
+                    index.retain(|line_number, _| (line_number - 1).is_multiple_of(every));
+                    index.shrink_to_fit();
+                }
+            }
+            if (current_line - 1).is_multiple_of(every) {
+                index.insert(current_line, offset);
+            }
+            if let Some(progress) = progress {
+                unpublished.push(offset);
+                if unpublished.len() >= PROGRESS_BATCH_LINES {
+                    progress.publish(&unpublished);
+                    unpublished.clear();
+                }
+            }
             current_line += 1;
             buf = Vec::new();
         }
+        index.insert(end.0, end.1);
+        if let Some(progress) = progress {
+            progress.publish(&unpublished);
+        }
 
         if save {
             // save the index to a file
             info!("Saving the index to file: {}", index_filename);
-            let mut file = std::fs::File::create(index_filename)?;
-            // TODO: bufwriter? is that a thing here?
-            rmp_serde::encode::write(&mut file, &index)?;
+            Database::save_index(index_filename, &index, fsync)
+                .with_context(|| format!("could not save the index to {index_filename}"))?;
+        }
+
+        Ok(Index::new(index))
+    }
+
+    /// Writes the index under a temporary name and renames it into place, so a server that stops
+    /// while writing it never leaves part of an index behind.  With `fsync` the index is flushed to
+    /// disk before the rename, and its directory after it, so that a power loss can't either.
+    fn save_index(index_filename: &str, index: &HashMap<u64, u64>, fsync: bool) -> Result<()> {
+        let temp_filename = format!("{index_filename}.tmp");
+        let file = std::fs::File::create(&temp_filename)?;
+        let file = match Database::write_index(file, index) {
+            Ok(file) => file,
+            Err(e) => {
+                // whatever part of it was written is never renamed into place, so it's only litter
+                let _ = std::fs::remove_file(&temp_filename);
+                return Err(e);
+            }
+        };
+        if fsync {
+            file.sync_all()?;
         }
+        drop(file);
+        std::fs::rename(&temp_filename, index_filename)?;
+        if fsync {
+            // the rename is only on disk once the directory that holds the index is
+            let dir = match std::path::Path::new(index_filename).parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => std::path::Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+            info!("Flushed the index to disk.");
+        }
+        Ok(())
+    }
+
+    /// Encodes the index to `writer` through a buffer, which is flushed before `writer` is handed
+    /// back so that a write that fails part of the way through, like on a full disk, is an error
+    /// rather than an index that's silently cut short.
+    fn write_index<W: Write>(writer: W, index: &HashMap<u64, u64>) -> Result<W> {
+        let mut writer = BufWriter::new(writer);
+        rmp_serde::encode::write(&mut writer, &INDEX_FORMAT_VERSION)?;
+        rmp_serde::encode::write(&mut writer, index)?;
+        writer.flush()?;
+        Ok(writer.into_inner().map_err(|e| e.into_error())?)
+    }
 
-        Ok(index)
+    /// Reads up to the next delimiter, retrying errors that may clear up on their own, like a
+    /// network filesystem timing out, with a growing backoff.  Whatever was read before an error
+    /// stays in `buf`, so the retries carry on where the failed read left off.
+    fn read_line_retrying<R: BufRead>(reader: &mut R, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        let mut backoff = std::time::Duration::from_millis(INDEX_READ_BACKOFF_MS);
+        let mut attempt = 1;
+        loop {
+            match reader.read_until(RECORD_DELIMITER, buf) {
+                Err(e) if attempt < INDEX_READ_ATTEMPTS && is_transient(&e) => {
+                    warn!(
+                        "Reading the database file failed on attempt {} of {}, retrying in {:?}: {}",
+                        attempt, INDEX_READ_ATTEMPTS, backoff, e
+                    );
+                    std::thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         db_file: &str,
         index_filename: &str,
         serialize_index: bool,
+        read_buffer_size: usize,
+        skip_bom: bool,
+        line_range: Option<(u64, u64)>,
+        count_served: bool,
+        sparsity: Option<Sparsity>,
+        fsync_index: bool,
+        line_cache: Option<u64>,
+        progress: Option<&IndexProgress>,
     ) -> Result<Database> {
+        Database::check_regular_file(db_file)?;
+        let data_start = Database::data_start(db_file, skip_bom)?;
         // TODO: logging about the index whether it was loaded or created and saved etc.
-        let index = Database::load_index(db_file, index_filename, serialize_index).await?;
+        let index = Database::load_index(
+            db_file,
+            index_filename,
+            serialize_index,
+            read_buffer_size,
+            data_start,
+            sparsity,
+            fsync_index,
+            progress,
+        )
+        .await?;
+        let served =
+            count_served.then(|| (0..index.line_count()).map(|_| AtomicU64::new(0)).collect());
         Ok(Database {
             db_file: String::from(db_file),
             index: Arc::new(index),
+            file_info: FileInfo::read(db_file)?,
+            content_map: OnceLock::new(),
+            file_checksum: OnceLock::new(),
+            word_count: OnceLock::new(),
+            first_bytes: OnceLock::new(),
+            read_buffer_size,
+            data_start,
+            line_range,
+            served,
+            line_cache: line_cache.map(|capacity| Mutex::new(LineCache::new(capacity as usize))),
         })
     }
 
+    /// Fails unless `db_file` is a regular file, since lines are found by seeking to their offsets
+    /// and a pipe or device can't be seeked.  It's checked without opening the file, which would
+    /// block on a named pipe until something wrote to it.
+    fn check_regular_file(db_file: &str) -> Result<()> {
+        let file_type = std::fs::metadata(db_file)
+            .with_context(|| format!("could not read the metadata of {db_file}"))?
+            .file_type();
+        if file_type.is_file() {
+            return Ok(());
+        }
+        let kind = if file_type.is_dir() {
+            "a directory"
+        } else {
+            Database::special_file_kind(file_type)
+        };
+        anyhow::bail!("{db_file} is not a regular file, it's {kind}");
+    }
+
+    #[cfg(unix)]
+    fn special_file_kind(file_type: std::fs::FileType) -> &'static str {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            "a named pipe"
+        } else if file_type.is_char_device() {
+            "a character device"
+        } else if file_type.is_block_device() {
+            "a block device"
+        } else if file_type.is_socket() {
+            "a socket"
+        } else {
+            "a special file"
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn special_file_kind(_file_type: std::fs::FileType) -> &'static str {
+        "a special file"
+    }
+
+    /// Where the first line starts, which is past the byte order mark when the file has one and
+    /// it's being skipped.
+    fn data_start(db_file: &str, skip_bom: bool) -> Result<u64> {
+        if !skip_bom {
+            return Ok(0);
+        }
+        let mut start = Vec::with_capacity(UTF8_BOM.len());
+        File::open(db_file)?
+            .take(UTF8_BOM.len() as u64)
+            .read_to_end(&mut start)?;
+        if start == UTF8_BOM {
+            info!("Skipping the byte order mark at the start of {}", db_file);
+            Ok(UTF8_BOM.len() as u64)
+        } else {
+            Ok(0)
+        }
+    }
+
+    /// Counts another GET of `line_number` and returns how many times it has been served now, or
+    /// None when the server isn't counting them.
+    pub fn record_served(&self, line_number: u64) -> Option<u64> {
+        let served = self
+            .served
+            .as_ref()?
+            .get(line_number.checked_sub(1)? as usize)?;
+        Some(served.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    /// `line_number` if it's in the line cache.
+    pub fn cached_line(&self, line_number: u64) -> Option<String> {
+        self.line_cache.as_ref()?.lock().unwrap().get(line_number)
+    }
+
+    /// Keeps `line` in the line cache, when there is one.
+    pub fn cache_line(&self, line_number: u64, line: &str) {
+        if let Some(line_cache) = &self.line_cache {
+            line_cache.lock().unwrap().insert(line_number, line);
+        }
+    }
+
+    /// The numbers of the lines in the line cache in order, which is none without one.
+    pub fn cached_line_numbers(&self) -> Vec<u64> {
+        let Some(line_cache) = &self.line_cache else {
+            return Vec::new();
+        };
+        let mut line_numbers: Vec<u64> = line_cache.lock().unwrap().lines.keys().copied().collect();
+        line_numbers.sort_unstable();
+        line_numbers
+    }
+
+    pub fn file_info(&self) -> FileInfo {
+        self.file_info
+    }
+
+    /// Looks up the lines whose content is exactly `content` in a map of the whole file, which is
+    /// built the first time it's needed and holds every line in memory.  The lines outside the
+    /// window are left out of what's found, just as FIND without the map never reads them.
+    pub fn find_cached(&self, content: &[u8]) -> Result<Vec<u64>> {
+        if self.content_map.get().is_none() {
+            info!("Building the content map for FIND.");
+            let mut reader =
+                BufReader::with_capacity(self.read_buffer_size, File::open(&self.db_file)?);
+            reader.seek(std::io::SeekFrom::Start(self.data_start))?;
+            let mut content_map = HashMap::<Vec<u8>, Vec<u64>>::new();
+            let mut buf = Vec::new();
+            for line_number in 1..=self.index.line_count() {
+                buf.clear();
+                reader.read_until(RECORD_DELIMITER, &mut buf)?;
+                let line = buf.strip_suffix(b"\n").unwrap_or(&buf);
+                content_map
+                    .entry(line.to_vec())
+                    .or_default()
+                    .push(line_number);
+            }
+            // another connection may have beaten us to it, in which case the maps are the same
+            let _ = self.content_map.set(content_map);
+        }
+        let in_window = |line_number: &u64| match self.line_range {
+            Some((start, end)) => (start..=end).contains(line_number),
+            None => true,
+        };
+        Ok(self
+            .content_map
+            .get()
+            .and_then(|map| map.get(content))
+            .map(|line_numbers| line_numbers.iter().copied().filter(in_window).collect())
+            .unwrap_or_default())
+    }
+
+    /// The SHA-256 of every byte of the file in hex, read the first time it's asked for and
+    /// remembered after that.
+    pub fn file_checksum(&self) -> Result<&str> {
+        if let Some(checksum) = self.file_checksum.get() {
+            return Ok(checksum);
+        }
+        info!("Computing the checksum of the data file.");
+        let mut reader =
+            BufReader::with_capacity(self.read_buffer_size, File::open(&self.db_file)?);
+        let mut hasher = Sha256::new();
+        loop {
+            let buf = reader.fill_buf()?;
+            if buf.is_empty() {
+                break;
+            }
+            hasher.update(buf);
+            let len = buf.len();
+            reader.consume(len);
+        }
+        let checksum = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        // another connection may have beaten us to it, in which case the checksums are the same
+        Ok(self.file_checksum.get_or_init(|| checksum))
+    }
+
+    /// How many words the lines in the window have, counted the way `wc -w` counts them as the runs
+    /// of bytes between whitespace, the first time it's asked for through `session` and remembered
+    /// after that.  Every session of the database has the same window, so they share the count.
+    pub async fn word_count(&self, session: &mut Session) -> Result<u64, SessionError> {
+        if let Some(words) = self.word_count.get() {
+            return Ok(*words);
+        }
+        info!("Counting the words in the data file.");
+        let mut words = 0;
+        // the delimiter is whitespace too, so no word runs on from one line to the next
+        session
+            .scan(|_, line| {
+                let mut in_word = false;
+                for byte in line {
+                    let is_space = matches!(byte, b' ' | b'\t' | b'\n' | 0x0b | 0x0c | b'\r');
+                    if !is_space && !in_word {
+                        words += 1;
+                    }
+                    in_word = !is_space;
+                }
+            })
+            .await?;
+        Ok(*self.word_count.get_or_init(|| words))
+    }
+
+    /// How many of the lines in the window start with each byte, read through `session` the first
+    /// time it's asked for and remembered after that.
+    pub async fn first_byte_counts(
+        &self,
+        session: &mut Session,
+    ) -> Result<&FirstByteCounts, SessionError> {
+        if let Some(counts) = self.first_bytes.get() {
+            return Ok(counts);
+        }
+        info!("Counting the first bytes of the lines in the data file.");
+        let mut counts = FirstByteCounts {
+            empty: 0,
+            bytes: [0; 256],
+        };
+        session
+            .scan(|_, line| match line.first() {
+                None => counts.empty += 1,
+                Some(&byte) => counts.bytes[byte as usize] += 1,
+            })
+            .await?;
+        Ok(self.first_bytes.get_or_init(|| counts))
+    }
+
+    /// Walks the whole file checking that every line the index has starts where it says it does,
+    /// and returns the first line whose offset is wrong.
+    pub fn verify_index(&self) -> Result<Option<u64>> {
+        let mut reader =
+            BufReader::with_capacity(self.read_buffer_size, File::open(&self.db_file)?);
+        reader.seek(std::io::SeekFrom::Start(self.data_start))?;
+        let mut offset = self.data_start;
+        let mut line_number = 1;
+        let mut buf = Vec::new();
+        let every = self.index.every;
+        loop {
+            // a sparse index only has every few lines, but those it has must be right
+            if self.index.keeps(line_number)
+                && self.index.offsets.get(&line_number) != Some(&offset)
+            {
+                return Ok(Some(line_number));
+            }
+            buf.clear();
+            let num_bytes = reader.read_until(RECORD_DELIMITER, &mut buf)?;
+            if num_bytes == 0 {
+                break;
+            }
+            offset += num_bytes as u64;
+            line_number += 1;
+        }
+        // the index ends before the file does
+        if self.index.offsets.get(&line_number) != Some(&offset) {
+            return Ok(Some(line_number));
+        }
+        // every line checked out but the index also has entries past the end of the file
+        let kept_lines = (line_number - 1).div_ceil(every) + 1;
+        if self.index.offsets.len() as u64 != kept_lines {
+            return Ok(Some(line_number + 1));
+        }
+        Ok(None)
+    }
+
+    /// The index as the msgpack map of line number to offset that it's saved as, including the
+    /// entry for the end of the file.
+    pub fn serialized_index(&self) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec(&self.index.offsets)?)
+    }
+
+    /// An estimate of the memory the index takes up: every bucket the map has allocated holds a
+    /// line number, an offset and a byte of control data, whether it's in use or not.
+    pub fn index_memory(&self) -> u64 {
+        let bucket_size = std::mem::size_of::<(u64, u64)>() + 1;
+        (self.index.offsets.capacity() * bucket_size) as u64
+    }
+
+    /// An estimate of the memory each line in the line cache takes up: the line, taken to be as
+    /// long as the file's average, its bucket in the map of lines and its entry in the map of uses.
+    pub fn line_cache_entry_memory(&self) -> u64 {
+        let line_count = self.index.line_count().max(1);
+        let average_line = self.file_info.size.saturating_sub(self.data_start) / line_count;
+        let bucket_size = std::mem::size_of::<(u64, (String, u64))>() + 1;
+        // a btree node carries about as much again as its entries
+        let use_size = 2 * std::mem::size_of::<(u64, u64)>();
+        average_line + (bucket_size + use_size) as u64
+    }
+
+    /// How many lines the line cache holds, if there is one.
+    pub fn line_cache_capacity(&self) -> Option<u64> {
+        self.line_cache
+            .as_ref()
+            .map(|line_cache| line_cache.lock().unwrap().capacity as u64)
+    }
+
+    /// Has the line cache hold at most `capacity` lines, or turns it off when that's none.  It's
+    /// only done before the database is shared, while the cache is still empty.
+    pub fn cap_line_cache(&mut self, capacity: u64) {
+        self.line_cache = (capacity > 0).then(|| Mutex::new(LineCache::new(capacity as usize)));
+    }
+
+    /// Opens the file for a new session, which is pinned to this database's index.  Connections
+    /// hold on to the database they started with too, so that its caches always go with the index
+    /// their sessions have.
     pub async fn get_session(&self) -> Result<Session> {
-        let file = File::open(&self.db_file)?;
-        let reader = BufReader::new(file);
-        Session::new(reader, self.index.clone()).await
+        Session::new(
+            &self.db_file,
+            self.read_buffer_size,
+            self.index.clone(),
+            self.line_range,
+        )
+        .await
     }
 }
@@ -1,15 +1,98 @@
 use std::convert::TryFrom;
 use std::fmt;
 
+/// The version of the frame protocol, bumped whenever a change would break existing clients
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The longest regex pattern that a client may send
+pub const MAX_PATTERN_LEN: usize = 1024;
+/// The longest args that a client may send to any other length prefixed command, unless the server
+/// is given another limit with --max-args-len
+pub const MAX_ARGS_LEN: usize = 64 * 1024;
+/// The most ranges that a client may ask for in a single MULTI RANGE
+pub const MAX_RANGES: usize = 64;
+/// The most needles that a client may look for with a single SEARCH ANY
+pub const MAX_NEEDLES: usize = 64;
+/// The most bytes that the needles of a SEARCH ANY may have between them
+pub const MAX_NEEDLES_LEN: usize = 4 * 1024;
+/// The bit of HELLO's args above the checksum algorithm that has the connection follow reloads
+pub const HELLO_FOLLOW_RELOADS: u32 = 0x100;
+
+/// The byte and name of every command, in the order PERMISSIONS lists them
+pub const COMMANDS: &[(u8, &str)] = &[
+    (b'0', "GET"),
+    (b'c', "GET IF CHANGED"),
+    (b't', "GET TRANSFORM"),
+    (b'z', "GET FROM END"),
+    (b'v', "GET WITH HINT"),
+    (b'4', "GET WITH CRC"),
+    (b'1', "QUIT"),
+    (b'2', "SHUTDOWN"),
+    (b'D', "DRAIN"),
+    (b'A', "GET ALL"),
+    (b'R', "REGEX"),
+    (b'x', "EXTRACT"),
+    (b'k', "SECTION"),
+    (b'N', "NEXT"),
+    (b'P', "PREV"),
+    (b'S', "SEEK"),
+    (b'C', "LINECOUNT"),
+    (b'K', "CAPABILITIES"),
+    (b'a', "HELLO"),
+    (b'Q', "PERMISSIONS"),
+    (b'X', "RANDOM"),
+    (b'O', "GET INDEX"),
+    (b'H', "HEALTH"),
+    (b'u', "UPTIME"),
+    (b'd', "DISK CHECK"),
+    (b'i', "CACHE INFO"),
+    (b'e', "ECHO"),
+    (b'W', "LENGTH HISTOGRAM"),
+    (b'5', "FIRST BYTE HISTOGRAM"),
+    (b'6', "LENGTH STATS"),
+    (b'7', "CHUNK PLAN"),
+    (b'8', "EXISTS"),
+    (b'9', "OUTLINE"),
+    (b'?', "GET RANGE CACHED"),
+    (b'l', "EXTREME"),
+    (b'b', "BOUNDS"),
+    (b's', "STATS"),
+    (b'F', "FILEINFO"),
+    (b'h', "FILE CHECKSUM"),
+    (b'o', "WORD COUNT"),
+    (b'G', "GET RANGE"),
+    (b'g', "GET RANGE REVERSE"),
+    (b'w', "JOIN RANGE"),
+    (b'p', "PAGE"),
+    (b'r', "RAW SPAN"),
+    (b'm', "META RANGE"),
+    (b'y', "OFFSET TABLE"),
+    (b'M', "MULTI RANGE"),
+    (b'U', "UNIQUE RUNS"),
+    (b'Y', "LINE AT BYTE"),
+    (b'E', "GET BYTE RANGE"),
+    (b'V', "VERIFY INDEX"),
+    (b'I', "FIND"),
+    (b'B', "FILTER PREFIX"),
+    (b'n', "SEARCH"),
+    (b'q', "SEARCH ANY"),
+    (b'T', "COUNT PREFIX"),
+    (b'f', "FILTER COLUMN"),
+    (b'Z', "COMPUTE CHECKSUM"),
+    (b'J', "FAIL"),
+    (b'L', "LINE NUMBERS"),
+    (b'j', "JSON MODE"),
+];
+
 #[derive(Debug)]
 pub enum FrameError {
     // TODO: is this the right way to make errors?  do we still want to call it frame error?
     // TODO: make this error more general
     InvalidChecksum,
     // You can add other variants here for other types of errors
-    LineIndexOutOfBounds, // TODO: should lineindexoutofbounds be a separate "requesterror" type or something?
     ParseError, // TODO: definitely need to review the structure of this error enum... there's gotta be a better way to do this
     ClientDisconnected,
+    Incomplete, // a length prefixed payload contained the delimiter so the rest of the frame is still to be read
+    TooLarge,   // a length prefix declared more args than the server takes, so they're never read
 }
 
 impl fmt::Display for FrameError {
@@ -17,19 +100,158 @@ impl fmt::Display for FrameError {
         match self {
             FrameError::InvalidChecksum => write!(f, "Invalid checksum"),
             // Handle other variants here
-            FrameError::LineIndexOutOfBounds => write!(f, "Line index out of bounds"),
             FrameError::ParseError => write!(f, "Parse error"),
             FrameError::ClientDisconnected => write!(f, "Client disconnected"),
+            FrameError::Incomplete => write!(f, "Incomplete frame"),
+            FrameError::TooLarge => write!(f, "Args over the length limit"),
         }
     }
 }
 
 impl std::error::Error for FrameError {}
 
+const CRC16: crc::Crc<u16> = crc::Crc::<u16>::new(&crc::CRC_16_XMODEM);
+const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// How the checksum at the end of a frame is worked out, which each connection picks for itself
+/// with HELLO.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChecksumAlgorithm {
+    /// No checksum at all, for clients that trust the transport to get the bytes there
+    None,
+    /// The sum of the bytes mod 256, which every connection starts out with
+    #[default]
+    Sum,
+    /// CRC-16/XMODEM
+    Crc16,
+    /// CRC-32 as zlib has it
+    Crc32,
+}
+
+impl ChecksumAlgorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::None => "none",
+            ChecksumAlgorithm::Sum => "sum",
+            ChecksumAlgorithm::Crc16 => "crc16",
+            ChecksumAlgorithm::Crc32 => "crc32",
+        }
+    }
+
+    /// How many bytes the checksum takes up in a frame.
+    pub fn width(&self) -> usize {
+        match self {
+            ChecksumAlgorithm::None => 0,
+            ChecksumAlgorithm::Sum => 1,
+            ChecksumAlgorithm::Crc16 => 2,
+            ChecksumAlgorithm::Crc32 => 4,
+        }
+    }
+
+    /// The checksum of `cmd_bytes`, which is sent big endian in the last `width` bytes before the
+    /// delimiter.
+    pub fn compute(&self, cmd_bytes: &[u8]) -> u32 {
+        match self {
+            ChecksumAlgorithm::None => 0,
+            ChecksumAlgorithm::Sum => checksum(cmd_bytes) as u32,
+            ChecksumAlgorithm::Crc16 => CRC16.checksum(cmd_bytes) as u32,
+            ChecksumAlgorithm::Crc32 => CRC32.checksum(cmd_bytes),
+        }
+    }
+}
+
+/// What GET TRANSFORM does to a line before sending it.
+#[derive(Clone, Copy, Debug)]
+pub enum Transform {
+    /// Takes the whitespace off both ends
+    Trim,
+    Uppercase,
+    /// Encodes every byte of the line so that it's safe to send whatever it holds
+    Base64,
+}
+
+impl Transform {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Transform::Trim => "trim",
+            Transform::Uppercase => "uppercase",
+            Transform::Base64 => "base64",
+        }
+    }
+}
+
 pub enum Command {
     Get(u32),
     Quit,
     Shutdown,
+    Drain,
+    GetAll,
+    RegexSearch(String),
+    Next,
+    Prev,
+    Seek(u32),
+    FileInfo,
+    GetRange(u32, u32),
+    LineNumbers(Option<u8>),
+    VerifyIndex,
+    Find(Vec<u8>),
+    LineCount,
+    Capabilities,
+    Random(Option<u32>),
+    FilterPrefix(Vec<u8>),
+    ComputeChecksum(Vec<u8>),
+    GetIndex,
+    LineAtByte(u64),
+    Health,
+    LengthHistogram,
+    FirstByteHistogram,
+    LengthStats,
+    ChunkPlan(u32),
+    Exists(u32),
+    Outline(u32, u32),
+    GetRangeCached(u32, u32),
+    CountPrefix(Vec<u8>),
+    GetByteRange(u64, u64),
+    UniqueRuns(u32, u32),
+    Fail,
+    MultiRange(Vec<(u32, u32)>),
+    Permissions,
+    GetRangeReverse(u32, u32),
+    Bounds,
+    Stats,
+    GetIfChanged(u32, u32),
+    Echo(Vec<u8>),
+    MetaRange(u32, u32),
+    OffsetTable(u32, u32),
+    /// The column, the byte the columns are split on, and the lowest and highest values to match
+    FilterColumn(u32, u8, f64, f64),
+    DiskCheck,
+    CacheInfo,
+    Extract(String),
+    /// Whether it's the longest line that's asked for rather than the shortest
+    Extreme(bool),
+    /// The marker lines that start and end the section, and whether the end marker is sent with it
+    Section(Vec<u8>, Vec<u8>, bool),
+    /// The first line of the page and the most lines it may have
+    Page(u32, u32),
+    RawSpan(u32, u32),
+    FileChecksum,
+    WordCount,
+    /// Whether GETs are answered with JSON objects rather than the plain responses
+    JsonMode(bool),
+    Search(Vec<u8>),
+    SearchAny(Vec<Vec<u8>>),
+    Uptime,
+    GetTransform(u32, Transform),
+    /// How far from the end the line is, where 1 is the last line
+    GetFromEnd(u32),
+    GetWithHint(u32),
+    GetWithCrc(u32),
+    /// The first and last lines of the range and what to put between them
+    JoinRange(u32, u32, Vec<u8>),
+    /// The checksum that the client's frames will have from here on, and whether the connection
+    /// moves on to the file the server reloads rather than keeping its snapshot
+    Hello(ChecksumAlgorithm, bool),
 }
 
 impl TryFrom<&[u8]> for Command {
@@ -45,40 +267,730 @@ impl TryFrom<&[u8]> for Command {
             }
             '1' => Ok(Command::Quit),
             '2' => Ok(Command::Shutdown),
+            'D' => Ok(Command::Drain),
+            'A' => Ok(Command::GetAll),
+            'N' => Ok(Command::Next),
+            'P' => Ok(Command::Prev),
+            'S' => {
+                let line_number = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                Ok(Command::Seek(line_number))
+            }
+            'F' => Ok(Command::FileInfo),
+            'h' => Ok(Command::FileChecksum),
+            'o' => Ok(Command::WordCount),
+            'V' => Ok(Command::VerifyIndex),
+            'C' => Ok(Command::LineCount),
+            'K' => Ok(Command::Capabilities),
+            'Q' => Ok(Command::Permissions),
+            'b' => Ok(Command::Bounds),
+            's' => Ok(Command::Stats),
+            'd' => Ok(Command::DiskCheck),
+            'l' => match u32::from_be_bytes([value[1], value[2], value[3], value[4]]) {
+                0 => Ok(Command::Extreme(false)),
+                1 => Ok(Command::Extreme(true)),
+                _ => Err(FrameError::ParseError),
+            },
+            'i' => Ok(Command::CacheInfo),
+            'j' => match u32::from_be_bytes([value[1], value[2], value[3], value[4]]) {
+                0 => Ok(Command::JsonMode(false)),
+                1 => Ok(Command::JsonMode(true)),
+                _ => Err(FrameError::ParseError),
+            },
+            'H' => Ok(Command::Health),
+            'u' => Ok(Command::Uptime),
+            'a' => {
+                let args = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                // the bit above the algorithm asks to follow reloads
+                let follow_reloads = args & HELLO_FOLLOW_RELOADS != 0;
+                let algorithm = match args & !HELLO_FOLLOW_RELOADS {
+                    0 => ChecksumAlgorithm::None,
+                    1 => ChecksumAlgorithm::Sum,
+                    2 => ChecksumAlgorithm::Crc16,
+                    3 => ChecksumAlgorithm::Crc32,
+                    _ => return Err(FrameError::ParseError),
+                };
+                Ok(Command::Hello(algorithm, follow_reloads))
+            }
+            '4' => {
+                let line_number = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                Ok(Command::GetWithCrc(line_number))
+            }
+            'v' => {
+                let line_number = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                Ok(Command::GetWithHint(line_number))
+            }
+            'z' => {
+                let n = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                Ok(Command::GetFromEnd(n))
+            }
+            'W' => Ok(Command::LengthHistogram),
+            '5' => Ok(Command::FirstByteHistogram),
+            '6' => Ok(Command::LengthStats),
+            '7' => {
+                let chunks = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                Ok(Command::ChunkPlan(chunks))
+            }
+            '8' => {
+                let line_number = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                Ok(Command::Exists(line_number))
+            }
+            '9' => {
+                let center = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                let radius = u32::from_be_bytes([value[5], value[6], value[7], value[8]]);
+                Ok(Command::Outline(center, radius))
+            }
+            '?' => {
+                let start = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                let end = u32::from_be_bytes([value[5], value[6], value[7], value[8]]);
+                Ok(Command::GetRangeCached(start, end))
+            }
+            'J' => Ok(Command::Fail),
+            'O' => Ok(Command::GetIndex),
+            'X' => Ok(Command::Random(
+                match u32::from_be_bytes([value[1], value[2], value[3], value[4]]) {
+                    0 => None,
+                    seed => Some(seed),
+                },
+            )),
+            'I' => Ok(Command::Find(value[5..].to_vec())),
+            'B' => Ok(Command::FilterPrefix(value[5..].to_vec())),
+            'n' => Ok(Command::Search(value[5..].to_vec())),
+            'q' => {
+                // a u32 count of needles, then each needle as a u32 length followed by its bytes
+                let payload = &value[5..];
+                if payload.len() < 4 {
+                    return Err(FrameError::ParseError);
+                }
+                let count = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                if count == 0 || count as usize > MAX_NEEDLES {
+                    return Err(FrameError::ParseError);
+                }
+                let mut needles = Vec::with_capacity(count as usize);
+                let mut rest = &payload[4..];
+                for _ in 0..count {
+                    if rest.len() < 4 {
+                        return Err(FrameError::ParseError);
+                    }
+                    let len = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+                    rest = &rest[4..];
+                    if rest.len() < len {
+                        return Err(FrameError::ParseError);
+                    }
+                    needles.push(rest[..len].to_vec());
+                    rest = &rest[len..];
+                }
+                if !rest.is_empty() || needles.iter().map(Vec::len).sum::<usize>() > MAX_NEEDLES_LEN
+                {
+                    return Err(FrameError::ParseError);
+                }
+                Ok(Command::SearchAny(needles))
+            }
+            'T' => Ok(Command::CountPrefix(value[5..].to_vec())),
+            'Z' => Ok(Command::ComputeChecksum(value[5..].to_vec())),
+            'e' => Ok(Command::Echo(value[5..].to_vec())),
+            'w' => {
+                // the range's two line numbers, then the separator takes up the rest
+                let payload = &value[5..];
+                if payload.len() < 8 {
+                    return Err(FrameError::ParseError);
+                }
+                let start = u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                let end = u32::from_be_bytes([payload[4], payload[5], payload[6], payload[7]]);
+                Ok(Command::JoinRange(start, end, payload[8..].to_vec()))
+            }
+            'k' => {
+                // a flag byte for whether to send the end marker, then the markers split by the
+                // delimiter that no line can contain
+                let (&include_end, markers) =
+                    value[5..].split_first().ok_or(FrameError::ParseError)?;
+                let split = markers
+                    .iter()
+                    .position(|&byte| byte == b'\n')
+                    .ok_or(FrameError::ParseError)?;
+                let include_end = match include_end {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(FrameError::ParseError),
+                };
+                Ok(Command::Section(
+                    markers[..split].to_vec(),
+                    markers[split + 1..].to_vec(),
+                    include_end,
+                ))
+            }
+            'G' => {
+                let start = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                let end = u32::from_be_bytes([value[5], value[6], value[7], value[8]]);
+                Ok(Command::GetRange(start, end))
+            }
+            'g' => {
+                let start = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                let end = u32::from_be_bytes([value[5], value[6], value[7], value[8]]);
+                Ok(Command::GetRangeReverse(start, end))
+            }
+            't' => {
+                let line_number = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                let transform = match u32::from_be_bytes([value[5], value[6], value[7], value[8]]) {
+                    0 => Transform::Trim,
+                    1 => Transform::Uppercase,
+                    2 => Transform::Base64,
+                    _ => return Err(FrameError::ParseError),
+                };
+                Ok(Command::GetTransform(line_number, transform))
+            }
+            'c' => {
+                let line_number = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                let hash = u32::from_be_bytes([value[5], value[6], value[7], value[8]]);
+                Ok(Command::GetIfChanged(line_number, hash))
+            }
+            'f' => {
+                let column = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                let min = f64::from_be_bytes(value[6..14].try_into().unwrap());
+                let max = f64::from_be_bytes(value[14..22].try_into().unwrap());
+                Ok(Command::FilterColumn(column, value[5], min, max))
+            }
+            'p' => {
+                let start = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                let page_size = u32::from_be_bytes([value[5], value[6], value[7], value[8]]);
+                Ok(Command::Page(start, page_size))
+            }
+            'r' => {
+                let start = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                let end = u32::from_be_bytes([value[5], value[6], value[7], value[8]]);
+                Ok(Command::RawSpan(start, end))
+            }
+            'm' => {
+                let start = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                let end = u32::from_be_bytes([value[5], value[6], value[7], value[8]]);
+                Ok(Command::MetaRange(start, end))
+            }
+            'y' => {
+                let start = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                let end = u32::from_be_bytes([value[5], value[6], value[7], value[8]]);
+                Ok(Command::OffsetTable(start, end))
+            }
+            'U' => {
+                let start = u32::from_be_bytes([value[1], value[2], value[3], value[4]]);
+                let end = u32::from_be_bytes([value[5], value[6], value[7], value[8]]);
+                Ok(Command::UniqueRuns(start, end))
+            }
+            'Y' => Ok(Command::LineAtByte(u64::from_be_bytes([
+                value[1], value[2], value[3], value[4], value[5], value[6], value[7], value[8],
+            ]))),
+            'E' => {
+                let start = u64::from_be_bytes(value[1..9].try_into().unwrap());
+                let end = u64::from_be_bytes(value[9..17].try_into().unwrap());
+                Ok(Command::GetByteRange(start, end))
+            }
+            'M' => Ok(Command::MultiRange(
+                value[5..]
+                    .chunks_exact(8)
+                    .map(|pair| {
+                        (
+                            u32::from_be_bytes([pair[0], pair[1], pair[2], pair[3]]),
+                            u32::from_be_bytes([pair[4], pair[5], pair[6], pair[7]]),
+                        )
+                    })
+                    .collect(),
+            )),
+            'L' => Ok(Command::LineNumbers(match value[4] {
+                0 => None,
+                separator => Some(separator),
+            })),
+            'R' => {
+                let pattern =
+                    String::from_utf8(value[5..].to_vec()).map_err(|_| FrameError::ParseError)?;
+                Ok(Command::RegexSearch(pattern))
+            }
+            'x' => {
+                let pattern =
+                    String::from_utf8(value[5..].to_vec()).map_err(|_| FrameError::ParseError)?;
+                Ok(Command::Extract(pattern))
+            }
             _ => Err(FrameError::ParseError),
         }
     }
 }
 
 impl Command {
-    fn as_bytes(&self) -> Vec<u8> {
+    /// Whether the command's args are a u32 length followed by that many bytes of payload rather
+    /// than a plain u32.
+    fn is_length_prefixed(cmd: u8) -> bool {
+        matches!(
+            cmd,
+            b'R' | b'x' | b'I' | b'B' | b'T' | b'Z' | b'e' | b'k' | b'n' | b'q' | b'w'
+        )
+    }
+
+    /// The length of the whole frame starting with `value`, including the checksum and delimiter.
+    /// The longest frame that any command can have when length prefixed args may be up to
+    /// `max_args_len` bytes, so that a client can't make the server read on without end.
+    pub fn max_frame_len(max_args_len: usize) -> usize {
+        // the command byte, the length or count, the widest checksum and the delimiter
+        1 + 4 + max_args_len.max(MAX_RANGES * 8) + 4 + 1
+    }
+
+    /// How long the frame that starts with `value` is with the one byte sum checksum.  A length
+    /// prefix over `max_args_len` is refused before anything is made for it.
+    fn frame_len(value: &[u8], max_args_len: usize) -> Result<usize, FrameError> {
+        if matches!(
+            value[0],
+            b'G' | b'g' | b'm' | b'U' | b'Y' | b'c' | b'p' | b'r' | b't' | b'y' | b'9' | b'?'
+        ) {
+            return Ok(11);
+        }
+        if value[0] == b'E' {
+            return Ok(19);
+        }
+        if value[0] == b'f' {
+            return Ok(24);
+        }
+        if value[0] == b'M' {
+            if value.len() < 5 {
+                return Err(FrameError::Incomplete);
+            }
+            // the args are a u32 count of ranges followed by a pair of u32s for each
+            let count = u32::from_be_bytes([value[1], value[2], value[3], value[4]]) as usize;
+            if count > MAX_RANGES {
+                return Err(FrameError::ParseError);
+            }
+            return Ok(5 + count * 8 + 2);
+        }
+        if !Command::is_length_prefixed(value[0]) {
+            return Ok(7);
+        }
+        if value.len() < 5 {
+            return Err(FrameError::Incomplete);
+        }
+        let payload_len = u32::from_be_bytes([value[1], value[2], value[3], value[4]]) as usize;
+        let max_payload_len = match value[0] {
+            b'R' | b'x' => MAX_PATTERN_LEN.min(max_args_len),
+            _ => max_args_len,
+        };
+        if payload_len > max_payload_len {
+            return Err(FrameError::TooLarge);
+        }
+        Ok(5 + payload_len + 2)
+    }
+
+    /// Whether the command with the byte `cmd` is only there with --debug-commands.
+    pub fn is_debug_only(cmd: u8) -> bool {
+        matches!(cmd, b'Z' | b'J')
+    }
+
+    /// The name of the command as it's written in the logs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Get(_) => "GET",
+            Command::Quit => "QUIT",
+            Command::Shutdown => "SHUTDOWN",
+            Command::Drain => "DRAIN",
+            Command::GetAll => "GET ALL",
+            Command::RegexSearch(_) => "REGEX",
+            Command::Next => "NEXT",
+            Command::Prev => "PREV",
+            Command::Seek(_) => "SEEK",
+            Command::FileInfo => "FILEINFO",
+            Command::GetRange(_, _) => "GET RANGE",
+            Command::LineNumbers(_) => "LINE NUMBERS",
+            Command::VerifyIndex => "VERIFY INDEX",
+            Command::Find(_) => "FIND",
+            Command::LineCount => "LINECOUNT",
+            Command::Capabilities => "CAPABILITIES",
+            Command::Random(_) => "RANDOM",
+            Command::FilterPrefix(_) => "FILTER PREFIX",
+            Command::ComputeChecksum(_) => "COMPUTE CHECKSUM",
+            Command::GetIndex => "GET INDEX",
+            Command::LineAtByte(_) => "LINE AT BYTE",
+            Command::Health => "HEALTH",
+            Command::LengthHistogram => "LENGTH HISTOGRAM",
+            Command::FirstByteHistogram => "FIRST BYTE HISTOGRAM",
+            Command::LengthStats => "LENGTH STATS",
+            Command::ChunkPlan(_) => "CHUNK PLAN",
+            Command::Exists(_) => "EXISTS",
+            Command::Outline(_, _) => "OUTLINE",
+            Command::GetRangeCached(_, _) => "GET RANGE CACHED",
+            Command::CountPrefix(_) => "COUNT PREFIX",
+            Command::GetByteRange(_, _) => "GET BYTE RANGE",
+            Command::UniqueRuns(_, _) => "UNIQUE RUNS",
+            Command::Fail => "FAIL",
+            Command::MultiRange(_) => "MULTI RANGE",
+            Command::Permissions => "PERMISSIONS",
+            Command::GetRangeReverse(_, _) => "GET RANGE REVERSE",
+            Command::Bounds => "BOUNDS",
+            Command::Stats => "STATS",
+            Command::GetIfChanged(_, _) => "GET IF CHANGED",
+            Command::Echo(_) => "ECHO",
+            Command::MetaRange(_, _) => "META RANGE",
+            Command::OffsetTable(_, _) => "OFFSET TABLE",
+            Command::FilterColumn(..) => "FILTER COLUMN",
+            Command::DiskCheck => "DISK CHECK",
+            Command::CacheInfo => "CACHE INFO",
+            Command::Extract(_) => "EXTRACT",
+            Command::Extreme(_) => "EXTREME",
+            Command::Section(..) => "SECTION",
+            Command::Page(_, _) => "PAGE",
+            Command::RawSpan(_, _) => "RAW SPAN",
+            Command::FileChecksum => "FILE CHECKSUM",
+            Command::WordCount => "WORD COUNT",
+            Command::JsonMode(_) => "JSON MODE",
+            Command::Search(_) => "SEARCH",
+            Command::SearchAny(_) => "SEARCH ANY",
+            Command::Uptime => "UPTIME",
+            Command::GetTransform(_, _) => "GET TRANSFORM",
+            Command::GetFromEnd(_) => "GET FROM END",
+            Command::GetWithHint(_) => "GET WITH HINT",
+            Command::GetWithCrc(_) => "GET WITH CRC",
+            Command::Hello(..) => "HELLO",
+            Command::JoinRange(..) => "JOIN RANGE",
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
         match self {
             Command::Get(line_number) => {
-                let mut bytes = vec!['0' as u8]; // TODO: clean this up maybe... this is ugly
+                let mut bytes = vec![b'0']; // TODO: clean this up maybe... this is ugly
+                bytes.extend_from_slice(&line_number.to_be_bytes());
+                bytes
+            }
+            Command::Quit => vec![b'1'],
+            Command::Shutdown => vec![b'2'],
+            Command::Drain => vec![b'D'],
+            Command::GetAll => vec![b'A'],
+            Command::RegexSearch(pattern) => {
+                let mut bytes = vec![b'R'];
+                bytes.extend_from_slice(&(pattern.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(pattern.as_bytes());
+                bytes
+            }
+            Command::Extract(pattern) => {
+                let mut bytes = vec![b'x'];
+                bytes.extend_from_slice(&(pattern.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(pattern.as_bytes());
+                bytes
+            }
+            Command::Next => vec![b'N'],
+            Command::Prev => vec![b'P'],
+            Command::Seek(line_number) => {
+                let mut bytes = vec![b'S'];
+                bytes.extend_from_slice(&line_number.to_be_bytes());
+                bytes
+            }
+            Command::FileInfo => vec![b'F'],
+            Command::FileChecksum => vec![b'h'],
+            Command::WordCount => vec![b'o'],
+            Command::VerifyIndex => vec![b'V'],
+            Command::LineCount => vec![b'C'],
+            Command::Capabilities => vec![b'K'],
+            Command::Permissions => vec![b'Q'],
+            Command::Bounds => vec![b'b'],
+            Command::Stats => vec![b's'],
+            Command::DiskCheck => vec![b'd'],
+            Command::Extreme(longest) => {
+                let mut bytes = vec![b'l'];
+                bytes.extend_from_slice(&(*longest as u32).to_be_bytes());
+                bytes
+            }
+            Command::CacheInfo => vec![b'i'],
+            Command::JsonMode(json) => {
+                let mut bytes = vec![b'j'];
+                bytes.extend_from_slice(&(*json as u32).to_be_bytes());
+                bytes
+            }
+            Command::GetTransform(line_number, transform) => {
+                let mut bytes = vec![b't'];
+                bytes.extend_from_slice(&line_number.to_be_bytes());
+                bytes.extend_from_slice(&(*transform as u32).to_be_bytes());
+                bytes
+            }
+            Command::Hello(algorithm, follow_reloads) => {
+                let mut bytes = vec![b'a'];
+                let follow = if *follow_reloads {
+                    HELLO_FOLLOW_RELOADS
+                } else {
+                    0
+                };
+                bytes.extend_from_slice(&(*algorithm as u32 | follow).to_be_bytes());
+                bytes
+            }
+            Command::GetWithCrc(line_number) => {
+                let mut bytes = vec![b'4'];
+                bytes.extend_from_slice(&line_number.to_be_bytes());
+                bytes
+            }
+            Command::GetWithHint(line_number) => {
+                let mut bytes = vec![b'v'];
                 bytes.extend_from_slice(&line_number.to_be_bytes());
                 bytes
             }
-            Command::Quit => vec!['1' as u8],
-            Command::Shutdown => vec!['2' as u8],
+            Command::GetFromEnd(n) => {
+                let mut bytes = vec![b'z'];
+                bytes.extend_from_slice(&n.to_be_bytes());
+                bytes
+            }
+            Command::GetIfChanged(line_number, hash) => {
+                let mut bytes = vec![b'c'];
+                bytes.extend_from_slice(&line_number.to_be_bytes());
+                bytes.extend_from_slice(&hash.to_be_bytes());
+                bytes
+            }
+            Command::Health => vec![b'H'],
+            Command::Uptime => vec![b'u'],
+            Command::LengthHistogram => vec![b'W'],
+            Command::FirstByteHistogram => vec![b'5'],
+            Command::LengthStats => vec![b'6'],
+            Command::ChunkPlan(chunks) => {
+                let mut bytes = vec![b'7'];
+                bytes.extend_from_slice(&chunks.to_be_bytes());
+                bytes
+            }
+            Command::Exists(line_number) => {
+                let mut bytes = vec![b'8'];
+                bytes.extend_from_slice(&line_number.to_be_bytes());
+                bytes
+            }
+            Command::Outline(center, radius) => {
+                let mut bytes = vec![b'9'];
+                bytes.extend_from_slice(&center.to_be_bytes());
+                bytes.extend_from_slice(&radius.to_be_bytes());
+                bytes
+            }
+            Command::GetRangeCached(start, end) => {
+                let mut bytes = vec![b'?'];
+                bytes.extend_from_slice(&start.to_be_bytes());
+                bytes.extend_from_slice(&end.to_be_bytes());
+                bytes
+            }
+            Command::Fail => vec![b'J'],
+            Command::GetIndex => vec![b'O'],
+            Command::GetByteRange(start, end) => {
+                let mut bytes = vec![b'E'];
+                bytes.extend_from_slice(&start.to_be_bytes());
+                bytes.extend_from_slice(&end.to_be_bytes());
+                bytes
+            }
+            Command::LineAtByte(offset) => {
+                let mut bytes = vec![b'Y'];
+                bytes.extend_from_slice(&offset.to_be_bytes());
+                bytes
+            }
+            Command::Random(seed) => {
+                let mut bytes = vec![b'X'];
+                bytes.extend_from_slice(&seed.unwrap_or(0).to_be_bytes());
+                bytes
+            }
+            Command::Find(content) => {
+                let mut bytes = vec![b'I'];
+                bytes.extend_from_slice(&(content.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(content);
+                bytes
+            }
+            Command::FilterPrefix(prefix) => {
+                let mut bytes = vec![b'B'];
+                bytes.extend_from_slice(&(prefix.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(prefix);
+                bytes
+            }
+            Command::Search(needle) => {
+                let mut bytes = vec![b'n'];
+                bytes.extend_from_slice(&(needle.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(needle);
+                bytes
+            }
+            Command::SearchAny(needles) => {
+                let mut payload = (needles.len() as u32).to_be_bytes().to_vec();
+                for needle in needles {
+                    payload.extend_from_slice(&(needle.len() as u32).to_be_bytes());
+                    payload.extend_from_slice(needle);
+                }
+                let mut bytes = vec![b'q'];
+                bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(&payload);
+                bytes
+            }
+            Command::CountPrefix(prefix) => {
+                let mut bytes = vec![b'T'];
+                bytes.extend_from_slice(&(prefix.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(prefix);
+                bytes
+            }
+            Command::FilterColumn(column, delimiter, min, max) => {
+                let mut bytes = vec![b'f'];
+                bytes.extend_from_slice(&column.to_be_bytes());
+                bytes.push(*delimiter);
+                bytes.extend_from_slice(&min.to_be_bytes());
+                bytes.extend_from_slice(&max.to_be_bytes());
+                bytes
+            }
+            Command::MetaRange(start, end) => {
+                let mut bytes = vec![b'm'];
+                bytes.extend_from_slice(&start.to_be_bytes());
+                bytes.extend_from_slice(&end.to_be_bytes());
+                bytes
+            }
+            Command::OffsetTable(start, end) => {
+                let mut bytes = vec![b'y'];
+                bytes.extend_from_slice(&start.to_be_bytes());
+                bytes.extend_from_slice(&end.to_be_bytes());
+                bytes
+            }
+            Command::Echo(payload) => {
+                let mut bytes = vec![b'e'];
+                bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(payload);
+                bytes
+            }
+            Command::Section(start_marker, end_marker, include_end) => {
+                let len = 1 + start_marker.len() + 1 + end_marker.len();
+                let mut bytes = vec![b'k'];
+                bytes.extend_from_slice(&(len as u32).to_be_bytes());
+                bytes.push(*include_end as u8);
+                bytes.extend_from_slice(start_marker);
+                bytes.push(b'\n');
+                bytes.extend_from_slice(end_marker);
+                bytes
+            }
+            Command::JoinRange(start, end, separator) => {
+                let mut bytes = vec![b'w'];
+                bytes.extend_from_slice(&(8 + separator.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(&start.to_be_bytes());
+                bytes.extend_from_slice(&end.to_be_bytes());
+                bytes.extend_from_slice(separator);
+                bytes
+            }
+            Command::ComputeChecksum(cmd_bytes) => {
+                let mut bytes = vec![b'Z'];
+                bytes.extend_from_slice(&(cmd_bytes.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(cmd_bytes);
+                bytes
+            }
+            Command::GetRange(start, end) => {
+                let mut bytes = vec![b'G'];
+                bytes.extend_from_slice(&start.to_be_bytes());
+                bytes.extend_from_slice(&end.to_be_bytes());
+                bytes
+            }
+            Command::GetRangeReverse(start, end) => {
+                let mut bytes = vec![b'g'];
+                bytes.extend_from_slice(&start.to_be_bytes());
+                bytes.extend_from_slice(&end.to_be_bytes());
+                bytes
+            }
+            Command::Page(start, page_size) => {
+                let mut bytes = vec![b'p'];
+                bytes.extend_from_slice(&start.to_be_bytes());
+                bytes.extend_from_slice(&page_size.to_be_bytes());
+                bytes
+            }
+            Command::RawSpan(start, end) => {
+                let mut bytes = vec![b'r'];
+                bytes.extend_from_slice(&start.to_be_bytes());
+                bytes.extend_from_slice(&end.to_be_bytes());
+                bytes
+            }
+            Command::UniqueRuns(start, end) => {
+                let mut bytes = vec![b'U'];
+                bytes.extend_from_slice(&start.to_be_bytes());
+                bytes.extend_from_slice(&end.to_be_bytes());
+                bytes
+            }
+            Command::MultiRange(ranges) => {
+                let mut bytes = vec![b'M'];
+                bytes.extend_from_slice(&(ranges.len() as u32).to_be_bytes());
+                for (start, end) in ranges {
+                    bytes.extend_from_slice(&start.to_be_bytes());
+                    bytes.extend_from_slice(&end.to_be_bytes());
+                }
+                bytes
+            }
+            Command::LineNumbers(separator) => vec![b'L', 0, 0, 0, separator.unwrap_or(0)],
         }
     }
 }
 
+/// The SUM of the command byte and its args MOD 256.
+pub fn checksum(cmd_bytes: &[u8]) -> u8 {
+    let mut checksum: u32 = 0;
+    for byte in cmd_bytes.iter() {
+        checksum += *byte as u32;
+    }
+    checksum %= 256; // TODO: these conversions are ugly
+    checksum as u8
+}
+
 pub struct Frame {
     pub cmd: Command,
-    checksum: u8, // TODO could make this smaller? not sure how checksums are normally done
+    checksum: u32,
 }
 
 impl Frame {
-    fn validate_checksum(&self) -> bool {
-        let cmd_bytes = self.cmd.as_bytes();
-        let mut checksum: u32 = 0;
-        for byte in cmd_bytes.iter() {
-            checksum += *byte as u32;
+    /// Whether the frame's checksum is right for the `cmd_bytes` it came with.
+    fn validate_checksum(&self, algorithm: ChecksumAlgorithm, cmd_bytes: &[u8]) -> bool {
+        match algorithm {
+            ChecksumAlgorithm::None => true,
+            // the sum is over the command as it's understood, so args it doesn't use must be 0
+            ChecksumAlgorithm::Sum => checksum(&self.cmd.as_bytes()) as u32 == self.checksum,
+            ChecksumAlgorithm::Crc16 | ChecksumAlgorithm::Crc32 => {
+                algorithm.compute(cmd_bytes) == self.checksum
+            }
+        }
+    }
+
+    /// The bytes to send for `cmd`, with its checksum and delimiter.
+    pub fn encode(cmd: &Command) -> Vec<u8> {
+        Frame::encode_with(cmd, ChecksumAlgorithm::Sum)
+    }
+
+    /// The bytes to send for `cmd` on a connection that said HELLO with `algorithm`.
+    pub fn encode_with(cmd: &Command, algorithm: ChecksumAlgorithm) -> Vec<u8> {
+        let mut bytes = cmd.as_bytes();
+        // the original commands' args are always 4 bytes even when they're unused
+        bytes.resize(bytes.len().max(5), 0);
+        let checksum = algorithm.compute(&bytes).to_be_bytes();
+        bytes.extend_from_slice(&checksum[4 - algorithm.width()..]);
+        bytes.push(b'\n');
+        bytes
+    }
+
+    /// Parses a frame whose checksum was worked out with `algorithm` and whose length prefixed args,
+    /// if it has them, may be up to `max_args_len` bytes.
+    pub fn parse(
+        value: &[u8],
+        algorithm: ChecksumAlgorithm,
+        max_args_len: usize,
+    ) -> Result<Frame, FrameError> {
+        // created frame
+        // validate_checksum of the created frame
+        // validate that GET has non-zero and other commands have 0x00
+        if value.is_empty() {
+            return Err(FrameError::ClientDisconnected);
         }
-        checksum = checksum as u32 % 256 as u32; // TODO: these conversions are ugly
-                                                 // println!("{checksum} == {}", self.checksum);
-        checksum as u8 == self.checksum
+        // the lengths of the commands are all given with the one byte sum
+        let sum_frame_len = Command::frame_len(value, max_args_len)?;
+        let frame_len = sum_frame_len - 1 + algorithm.width();
+        // the original commands all fit in 7 bytes and are taken as they arrive, but the args of
+        // anything longer may contain the delimiter so it's read until it's whole, as is any frame
+        // whose checksum is wide enough to contain it
+        if value.len() < frame_len && (sum_frame_len > 7 || algorithm.width() > 1) {
+            return Err(FrameError::Incomplete);
+        }
+        if value.len() != frame_len {
+            return Err(FrameError::ParseError);
+        }
+        let cmd_len = frame_len - 1 - algorithm.width();
+        let cmd_bytes = &value[..cmd_len];
+        let command = Command::try_from(cmd_bytes)?;
+        let checksum = value[cmd_len..frame_len - 1]
+            .iter()
+            .fold(0, |checksum, byte| checksum << 8 | *byte as u32);
+        let frame = Frame {
+            cmd: command,
+            checksum,
+        };
+        if !frame.validate_checksum(algorithm, cmd_bytes) {
+            return Err(FrameError::InvalidChecksum);
+        }
+        Ok(frame)
     }
 }
 
@@ -86,25 +998,8 @@ impl TryFrom<&[u8]> for Frame {
     // TODO return some sort of data invalid error
     type Error = FrameError;
 
+    /// Parses a frame with the sum checksum and the args limit that connections start out with.
     fn try_from(value: &[u8]) -> std::prelude::v1::Result<Self, Self::Error> {
-        // created frame
-        // validate_checksum of the created frame
-        // validate that GET has non-zero and other commands have 0x00
-        match value.len() {
-            0 => Err(FrameError::ClientDisconnected),
-            7 => {
-                let command = Command::try_from(&value[0..5])?;
-                let checksum = u8::from_be_bytes([value[5]]);
-                let frame = Frame {
-                    cmd: command,
-                    checksum,
-                };
-                if !frame.validate_checksum() {
-                    return Err(FrameError::InvalidChecksum);
-                }
-                Ok(frame)
-            }
-            _ => Err(FrameError::ParseError),
-        }
+        Frame::parse(value, ChecksumAlgorithm::Sum, MAX_ARGS_LEN)
     }
-}
\ No newline at end of file
+}
@@ -9,81 +9,542 @@
 // Frame:
 // | Command | Command Args | Checksum |
 // 0x0 is GET
+// c is GET IF CHANGED, whose args are a u32 line number and the u32 FNV-1a hash of the line the
+//   client has, making an 11 byte frame.  It responds with NOTMODIFIED when the line hashes the same
+//   and like GET otherwise
+// t is GET TRANSFORM, whose args are a u32 line number and a u32 for what to do to the line, 0 to
+//   trim it, 1 to uppercase it or 2 to base64 encode it, and which responds like GET with the result
+// z is GET FROM END, whose arg is a u32 count of lines from the end, 1 being the last line, and which
+//   responds like GET
+// v is GET WITH HINT, which responds like GET with how many lines come after it in the header
+// 4 is GET WITH CRC, which responds like GET with the CRC-32 of what follows the header in it
 // 0x1 is QUIT
 // 0x2 is SHUTDOWN
-// only command that has args is GET which is a u32.  if it is none then we will just send 0 because the first line in the file is 1 indexed
+// D is DRAIN
+// A is GET ALL, which responds with every line followed by END
+// R is REGEX, whose args are a u32 length followed by that many bytes of UTF-8 pattern
+//   it responds with the number of every line that matches, followed by END
+// x is EXTRACT, which takes the same args as REGEX and responds with the first capture group of every
+//   line that matches, tagged with its number, followed by END
+// p is PAGE, whose args are the u32 first line and the u32 most lines to send, and which responds
+//   with NEXT and the line to ask for next, or EOF, then the lines, followed by END
+// r is RAW SPAN, which takes the same args as GET RANGE and responds with the length of the span of
+//   the file those lines take up, delimiters and all, followed by its bytes as they are
+// k is SECTION, whose args are a u32 length followed by a flag byte and the start and end marker
+//   lines split by a delimiter.  it responds with the lines from the start marker to the end one,
+//   tagged with their numbers, followed by END
+// N is NEXT and P is PREV, which move the session's cursor one line and respond with that line
+// S is SEEK, which moves the cursor to the line number in its args (0 is before the first line)
+// C is LINECOUNT, which responds with the number of lines in the file
+// K is CAPABILITIES, which responds with the delimiter, framing and protocol version as key value
+//   lines, followed by END
+// Q is PERMISSIONS, which responds with the byte and name of each command the connection may use,
+//   followed by END
+// X is RANDOM, which responds with a uniformly random line, picked with the u32 in its args as the
+//   seed unless it's 0
+// O is GET INDEX, which responds with the length of the msgpack encoded index followed by the index
+// H is HEALTH, which responds with ready, or with building while the index is still being built
+// e is ECHO, whose args are a u32 length followed by that many bytes, which it responds with as they
+//   are to time round trips without touching the disk
+// d is DISK CHECK, which reads a random sample of lines and responds with how many it read, or with
+//   ERR 500 and the first line it couldn't read
+// i is CACHE INFO, which responds with how many lines are in the line cache and their numbers, followed
+//   by END
+// W is LENGTH HISTOGRAM, which responds with the number of lines whose length falls in each power of
+//   two, followed by END
+// 5 is FIRST BYTE HISTOGRAM, which responds with the number of lines that start with each byte and
+//   of the empty lines, counted once, followed by END
+// 6 is LENGTH STATS, which responds with the shortest, longest, mean, median, 90th and 99th
+//   percentile line lengths, followed by END
+// 7 is CHUNK PLAN, which responds with the first and last line of each of the given number of
+//   ranges that the lines are split into evenly, followed by END
+// 8 is EXISTS, which responds with whether the line is one that can be read, without reading it
+// 9 is OUTLINE, which responds with the number and offset of each line within the given radius of
+//   the given line, without their content, followed by END
+// ? is GET RANGE CACHED, which responds like GET RANGE with whether each line was sent from the line
+//   cache or read from the file
+// l is EXTREME, whose arg is 0 for the shortest line or 1 for the longest, and it responds with that
+//   line tagged with its number
+// b is BOUNDS, which responds with the first and last lines, followed by END
+// s is STATS, which responds with a line for each open connection with its id, peer address, the
+//   number of requests it has sent and when it connected, followed by END
+// u is UPTIME, which responds with when the server started and how many milliseconds ago that was
+// F is FILEINFO, which responds with the file's size in bytes and its unix mtime
+// o is WORD COUNT, which responds with the file's lines, words and bytes like wc, counting the words
+//   once
+// h is FILE CHECKSUM, which responds with the SHA-256 of the whole file, worked out once
+// G is GET RANGE, whose args are two u32s for the first and last line, each followed by END
+// g is GET RANGE REVERSE, which takes the same args as GET RANGE and responds with the lines from the
+//   last to the first, followed by END
+// w is JOIN RANGE, whose args are a u32 length followed by that many bytes: the u32 first and last
+//   lines of a range, then a separator.  it responds with the lines joined by the separator
+// m is META RANGE, which takes the same args as GET RANGE and responds with each line's number, byte
+//   offset and length instead of its content, followed by END
+// y is OFFSET TABLE, which takes the same args as GET RANGE and responds with the length of a table
+//   of each line's u64 byte offset and u64 length followed by the table
+// U is UNIQUE RUNS, whose args are two u32s for the first and last line like GET RANGE, and it
+//   responds with each run of identical lines in the range as its length and line, followed by END
+// M is MULTI RANGE, whose args are a u32 count of ranges followed by two u32s for the first and last
+//   line of each.  it responds with each range's lines after a RANGE marker, followed by END
+// Y is LINE AT BYTE, whose args are a u64 byte offset, and it responds with the number and content of
+//   the line that the offset is in
+// E is GET BYTE RANGE, whose args are two u64 byte offsets for the start and end of a range, and it
+//   responds with every line that lies wholly in start..end tagged with its number, followed by END
+// V is VERIFY INDEX, which checks every offset in the index against the file
+// I is FIND, whose args are a u32 length followed by that many bytes of line content
+//   it responds with the number of every line that is exactly that content, followed by END
+// B is FILTER PREFIX, whose args are a u32 length followed by that many bytes of prefix
+//   it responds with every line that starts with the prefix, tagged with its number, followed by END
+// n is SEARCH, whose args are a u32 length followed by that many bytes to look for.  it responds with
+//   every line that contains them, tagged with its number, sent as they're found, followed by END
+// q is SEARCH ANY, whose args are a u32 length followed by a u32 count of needles and each needle as
+//   a u32 length and its bytes.  it responds like SEARCH with every line that contains any of them,
+//   tagged with its number and the needle found first in it
+// Z is COMPUTE CHECKSUM, whose args are a u32 length followed by that many bytes of a command and
+//   its args.  it responds with the checksum of those bytes, but only with --debug-commands
+// T is COUNT PREFIX, which takes the same args as FILTER PREFIX and responds with the number of lines
+//   that start with the prefix
+// f is FILTER COLUMN, whose args are a u32 column counting from 0, the byte the columns are split on
+//   and two f64s for the lowest and highest value, making a 24 byte frame.  It responds with every
+//   line whose column is a number in that range, tagged with its number, followed by END
+// J is FAIL, which fails like an unexpected error while handling a request would, to check how
+//   clients cope with one.  it's only there with --debug-commands
+// L is LINE NUMBERS, which prefixes the lines sent on the connection with their number and the
+//   separator in the last byte of its args, or stops prefixing them when that byte is 0
+// j is JSON MODE, which answers the connection's GETs with JSON objects when its args are 1, or
+//   with the plain responses again when they're 0
+// a is HELLO, which picks the checksum that the connection's frames have after it from its args, 0
+//   for none, 1 for the sum that connections start with, 2 for CRC-16/XMODEM or 3 for CRC-32, and
+//   responds with the name of the one picked.  0x100 added to the args has the connection follow
+//   reloads of the file instead of keeping the snapshot it has
 
 // because the file is immutable we're not going to have to write to the index
 // after the first time that we read in the file and built it.
 
 // example GET
 // 0x00 | 0x00 0x00 0x00 0x01 | 0x00 | 0x0A
+mod client;
+mod config;
 mod db;
 mod frame;
+mod selftest;
+mod sendfile;
 
 use anyhow::Result;
-use db::Session;
+use base64::Engine;
+use clap::Parser;
+use config::{CliCommand, Config};
+use db::{Database, IndexProgress, Session, SessionError, Sparsity};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
     net::{TcpListener, TcpStream},
 };
 
-use frame::{Command, Frame, FrameError};
+use frame::{ChecksumAlgorithm, Command, Frame, FrameError, Transform};
 use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-use fern;
-use log::{self, error, warn};
 use log::info;
-use tokio::sync::{broadcast, mpsc};
+use log::{self, debug, error, trace, warn};
+use rand::{rngs::StdRng, RngExt, SeedableRng};
+use tokio::sync::{broadcast, mpsc, watch};
 
-static SERIALIZE_INDEX: bool = true;
-static PORT: u16 = 10497;
+// the regex crate matches in linear time so patterns can't blow up at search time, but compiling
+// one is still bounded so that a pathological pattern can't eat the server's memory
+static REGEX_SIZE_LIMIT: usize = 1 << 20;
+// how many reads in a row have to come back empty before the client is taken to have hung up
+static EMPTY_READS_BEFORE_DISCONNECT: u32 = 2;
+// the most captures that a single EXTRACT may send
+static MAX_EXTRACT_RESULTS: usize = 10_000;
+// how many lines SEARCH scans between sending what it has found so far
+static SEARCH_BATCH_LINES: u64 = 4096;
+// the most lines that a single MULTI RANGE may send across all of its ranges
+static MAX_MULTI_RANGE_LINES: u64 = 100_000;
+// the bytes that each line's offset and length take up in an OFFSET TABLE
+static OFFSET_TABLE_ROW_LEN: u64 = 16;
 
-fn setup_logger() -> Result<(), fern::InitError> {
+// the most cached line numbers that CACHE INFO lists, though it counts all of them
+static MAX_CACHE_INFO_LINES: usize = 1000;
+// how many random lines DISK CHECK reads
+static DISK_CHECK_SAMPLE_SIZE: u64 = 16;
+
+// the log target of the access log's lines, which keeps them out of the other logs
+static ACCESS_LOG_TARGET: &str = "access";
+
+fn setup_logger(level: log::LevelFilter, access_log: Option<&str>) -> Result<(), fern::InitError> {
     let log_file = "output.log";
 
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            out.finish(format_args!(
-                "{} [{}] {}",
-                chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
-                record.level(),
-                message
-            ))
-        })
-        .level(log::LevelFilter::Debug)
-        .chain(std::io::stdout())
-        .chain(fern::log_file(log_file)?)
-        .apply()?;
+    let mut dispatch = fern::Dispatch::new().chain(
+        fern::Dispatch::new()
+            .format(|out, message, record| {
+                out.finish(format_args!(
+                    "{} [{}] {}",
+                    chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+                    record.level(),
+                    message
+                ))
+            })
+            .level(level)
+            .filter(|metadata| metadata.target() != ACCESS_LOG_TARGET)
+            .chain(std::io::stdout())
+            .chain(fern::log_file(log_file)?),
+    );
+    if let Some(access_log) = access_log {
+        // the lines are already formatted, and are written whatever the level of the other logs
+        dispatch = dispatch.chain(
+            fern::Dispatch::new()
+                .level(log::LevelFilter::Info)
+                .filter(|metadata| metadata.target() == ACCESS_LOG_TARGET)
+                .chain(fern::log_file(access_log)?),
+        );
+    }
+    dispatch.apply()?;
     Ok(())
 }
 
-async fn shutdown_thread(mut cmd_rx: mpsc::Receiver<()>, shutdown_tx: broadcast::Sender<()>) {
-    cmd_rx.recv().await;
-    match shutdown_tx.send(()) {
-        Ok(_) => info!("Shutdown signal sent."),
-        Err(_) => {
-            error!("Failed to send shutdown signal. Forcing shutdown.");
-            std::process::exit(1);
+/// Why the server is shutting down, which is passed on to the clients so that they can decide
+/// whether to reconnect or back off.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ShutdownReason {
+    /// A client sent SHUTDOWN
+    Operator,
+    /// The process received SIGINT or SIGTERM
+    Signal,
+    /// The server can no longer serve the database
+    Error,
+}
+
+impl fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShutdownReason::Operator => write!(f, "operator"),
+            ShutdownReason::Signal => write!(f, "signal"),
+            ShutdownReason::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// The kind of shutdown that is broadcast to the server and its connections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ShutdownKind {
+    /// Stop accepting new connections but let the existing ones finish on their own
+    Drain,
+    /// Stop every connection at its next frame boundary
+    Immediate(ShutdownReason),
+}
+
+static SHUTDOWN_SEND_ATTEMPTS: u32 = 5;
+static SHUTDOWN_SEND_BACKOFF_MS: u64 = 10;
+
+/// Broadcasts the shutdown signal, retrying with jittered exponential backoff.  The send only fails
+/// when nothing is subscribed yet, which can be a transient race at startup.
+async fn send_shutdown_signal(
+    shutdown_tx: &broadcast::Sender<ShutdownKind>,
+    kind: ShutdownKind,
+) -> Result<(), broadcast::error::SendError<ShutdownKind>> {
+    let mut attempt = 1;
+    loop {
+        match shutdown_tx.send(kind) {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt == SHUTDOWN_SEND_ATTEMPTS => return Err(e),
+            Err(_) => {
+                let backoff = SHUTDOWN_SEND_BACKOFF_MS << (attempt - 1);
+                let delay = rand::random_range(backoff / 2..=backoff);
+                warn!(
+                    "Nothing is subscribed to the {:?} shutdown signal yet, retrying in {}ms (attempt {}/{}).",
+                    kind, delay, attempt, SHUTDOWN_SEND_ATTEMPTS
+                );
+                tokio::time::sleep(tokio::time::Duration::from_millis(delay)).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+async fn shutdown_thread(
+    mut cmd_rx: mpsc::Receiver<ShutdownKind>,
+    shutdown_tx: broadcast::Sender<ShutdownKind>,
+) {
+    // a drain can still be escalated to an immediate shutdown, so keep listening until then
+    while let Some(kind) = cmd_rx.recv().await {
+        match send_shutdown_signal(&shutdown_tx, kind).await {
+            Ok(_) => info!("{:?} shutdown signal sent.", kind),
+            Err(e) => {
+                error!(
+                    "Failed to send the {:?} shutdown signal after {} attempts ({}): nothing is listening for it. Forcing shutdown.",
+                    kind, SHUTDOWN_SEND_ATTEMPTS, e
+                );
+                log::logger().flush();
+                std::process::exit(1);
+            }
+        }
+        if matches!(kind, ShutdownKind::Immediate(_)) {
+            return;
+        }
+    }
+}
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                .expect("could not listen for SIGTERM");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+async fn signal_thread(cmd_tx: mpsc::Sender<ShutdownKind>) {
+    wait_for_signal().await;
+    info!("Received a shutdown signal.  Send it again to force the shutdown.");
+    let _ = cmd_tx
+        .send(ShutdownKind::Immediate(ShutdownReason::Signal))
+        .await;
+    // the graceful shutdown waits on clients, so a second signal gives up on them
+    wait_for_signal().await;
+    warn!("Received a second shutdown signal.  Forcing shutdown.");
+    log::logger().flush();
+    std::process::exit(1);
+}
+
+/// Reloads the data file on every SIGHUP, for when it has been replaced.  New connections get the
+/// reloaded database, and so do the connections that follow reloads from their next request, while
+/// the rest keep the snapshot they have.  A reload that fails leaves the old database in place.
+#[cfg(unix)]
+async fn reload_thread(config: Arc<Config>, databases: watch::Sender<Arc<Database>>) {
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("could not listen for SIGHUP");
+    while hangup.recv().await.is_some() {
+        info!("Received a reload signal, reloading {}.", config.db_file);
+        match Server::open_database(&config, None).await {
+            Ok(db) => {
+                info!(
+                    "Reloaded {}, which is {} bytes.",
+                    config.db_file,
+                    db.file_info().size
+                );
+                databases.send_replace(Arc::new(db));
+            }
+            Err(e) => error!(
+                "Could not reload {}, still serving the file from before: {:#}",
+                config.db_file, e
+            ),
+        }
+    }
+}
+
+/// Hands out the ids that connections are tracked and logged under.
+enum ConnectionIds {
+    /// A random UUID for each connection
+    Uuid,
+    /// `conn-1`, `conn-2`, ... so that tests can predict the ids
+    Sequential(AtomicU64),
+}
+
+impl ConnectionIds {
+    fn next_id(&self) -> String {
+        match self {
+            ConnectionIds::Uuid => uuid::Uuid::new_v4().to_string(),
+            ConnectionIds::Sequential(counter) => {
+                format!("conn-{}", counter.fetch_add(1, Ordering::Relaxed) + 1)
+            }
+        }
+    }
+}
+
+/// What an open connection publishes about itself for STATS.
+struct ConnectionStats {
+    addr: SocketAddr,
+    connected_since: chrono::DateTime<chrono::Local>,
+    // counted by the connection as it reads each request, so STATS always sees the latest count
+    requests: AtomicU64,
+    // the milliseconds since the server started at which the connection last read a request or
+    // wrote a response, for the reaper
+    last_active_ms: AtomicU64,
+}
+
+/// The stats of the open connections by id.  Each connection adds itself when it starts and takes
+/// itself out when it's dropped.
+type ConnectionTable = Arc<std::sync::Mutex<HashMap<String, Arc<ConnectionStats>>>>;
+
+/// Caps how many GETs read from the data file at once across all the connections.
+struct ReadLimiter {
+    permits: tokio::sync::Semaphore,
+    // the reads holding a permit, and the most there have been, to show how close to the limit the
+    // load gets
+    in_flight: AtomicU64,
+    peak: AtomicU64,
+}
+
+impl ReadLimiter {
+    fn new(max_reads: u64) -> ReadLimiter {
+        ReadLimiter {
+            permits: tokio::sync::Semaphore::new(max_reads as usize),
+            in_flight: AtomicU64::new(0),
+            peak: AtomicU64::new(0),
+        }
+    }
+
+    /// Gets `line_number` from `session` once there's a permit for the read.
+    async fn get(&self, session: &mut Session, line_number: u64) -> Result<String, SessionError> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("the read semaphore is never closed");
+        let in_flight = self.in_flight.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.peak.fetch_max(in_flight, Ordering::Relaxed) < in_flight {
+            debug!("The most reads in flight at once is now {}.", in_flight);
+        }
+        let line = session.get(line_number).await;
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+        line
+    }
+}
+
+/// A token bucket over the connections accepted, which fills at `rate` tokens a second up to a
+/// second's worth.
+struct AcceptLimiter {
+    rate: f64,
+    tokens: f64,
+    filled_at: tokio::time::Instant,
+    // set while connections are being closed, so the warning is only logged once for each flood
+    throttling: bool,
+}
+
+impl AcceptLimiter {
+    fn new(rate: u64) -> AcceptLimiter {
+        AcceptLimiter {
+            rate: rate as f64,
+            tokens: rate as f64,
+            filled_at: tokio::time::Instant::now(),
+            throttling: false,
+        }
+    }
+
+    /// Takes a token for a new connection, or returns false when the bucket is empty.
+    fn try_accept(&mut self) -> bool {
+        let now = tokio::time::Instant::now();
+        let elapsed = now.duration_since(self.filled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.filled_at = now;
+        if self.tokens < 1.0 {
+            return false;
         }
+        self.tokens -= 1.0;
+        true
     }
 }
 
+/// When the server started, as a time for people and as an instant to measure the uptime from.
+#[derive(Clone, Copy)]
+struct ServerStart {
+    at: chrono::DateTime<chrono::Local>,
+    instant: std::time::Instant,
+}
+
 struct Server {
-    db: db::Database,
+    started: ServerStart,
+    config: Arc<Config>,
+    // the latest database, which a reload replaces for new connections and those that follow it
+    databases: watch::Sender<Arc<Database>>,
     active_connections: HashMap<String, tokio::task::JoinHandle<()>>,
+    connection_ids: ConnectionIds,
+    accept_limiter: Option<AcceptLimiter>,
+    connection_table: ConnectionTable,
+    read_limiter: Option<Arc<ReadLimiter>>,
 }
 
 impl Server {
-    pub async fn new(db_fn: &str) -> Result<Server> {
-        let db =
-            db::Database::new(db_fn, format!("{db_fn}.index").as_str(), SERIALIZE_INDEX).await?;
+    pub async fn new(config: Config, progress: Option<&IndexProgress>) -> Result<Server> {
+        let started = ServerStart {
+            at: chrono::Local::now(),
+            instant: std::time::Instant::now(),
+        };
+        let db = Server::open_database(&config, progress).await?;
+        let connection_ids = if config.sequential_conn_ids {
+            ConnectionIds::Sequential(AtomicU64::new(0))
+        } else {
+            ConnectionIds::Uuid
+        };
         Ok(Server {
-            db,
+            started,
+            accept_limiter: config.accept_rate.map(AcceptLimiter::new),
+            read_limiter: config
+                .max_concurrent_reads
+                .map(|max_reads| Arc::new(ReadLimiter::new(max_reads))),
+            config: Arc::new(config),
+            databases: watch::Sender::new(Arc::new(db)),
             active_connections: HashMap::new(),
+            connection_ids,
+            connection_table: ConnectionTable::default(),
         })
     }
 
+    /// Opens the data file and builds or loads its index, fitting the line cache into what the
+    /// index leaves of --max-memory.  It's done on startup and again on every reload.
+    async fn open_database(config: &Config, progress: Option<&IndexProgress>) -> Result<Database> {
+        let db_fn = config.db_file.as_str();
+        let mut db = Database::new(
+            db_fn,
+            format!("{db_fn}.index").as_str(),
+            !config.no_persist_index,
+            config.read_buffer_size as usize,
+            config.skip_bom,
+            config.line_range,
+            config.served_counts,
+            config.sparse_index_above.map(|above| Sparsity {
+                above,
+                every: config.sparse_index_every,
+            }),
+            config.fsync_index,
+            config.line_cache,
+            progress,
+        )
+        .await?;
+        if let Some(max_memory) = config.max_memory {
+            let index_memory = db.index_memory();
+            if index_memory > max_memory {
+                error!(
+                    "The index needs about {} bytes which is over the {} byte memory budget.",
+                    index_memory, max_memory
+                );
+                anyhow::bail!("index does not fit in the memory budget");
+            }
+            info!(
+                "The index takes about {} of the {} byte memory budget.",
+                index_memory, max_memory
+            );
+            // the line cache gets what the index leaves of the budget
+            if let Some(capacity) = db.line_cache_capacity() {
+                let left = max_memory - index_memory;
+                let fits = left / db.line_cache_entry_memory();
+                if fits == 0 {
+                    warn!(
+                        "The line cache is off since not one line fits in the {} bytes left of the memory budget.",
+                        left
+                    );
+                    db.cap_line_cache(0);
+                } else if fits < capacity {
+                    warn!(
+                        "The line cache is capped at {} of its {} lines to fit in the {} bytes left of the memory budget.",
+                        fits, capacity, left
+                    );
+                    db.cap_line_cache(fits);
+                }
+            }
+        }
+        Ok(db)
+    }
+
     fn reap_finished_connections(&mut self) {
         let mut finished_connections = Vec::new();
         let active_connection_iter = self.active_connections.iter();
@@ -99,6 +560,35 @@ impl Server {
         }
     }
 
+    /// Closes the connections that haven't read a request or written a response for longer than
+    /// `--reap-inactive-ms`.  Aborting a connection's task drops it, which closes its socket and
+    /// takes it out of the connection table.
+    fn reap_inactive_connections(&mut self) {
+        let Some(limit) = self.config.reap_inactive_ms else {
+            return;
+        };
+        let now = self.started.instant.elapsed().as_millis() as u64;
+        let inactive = self
+            .connection_table
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(conn_id, stats)| {
+                let inactive_ms = now.saturating_sub(stats.last_active_ms.load(Ordering::Relaxed));
+                (inactive_ms > limit).then(|| (conn_id.clone(), inactive_ms))
+            })
+            .collect::<Vec<_>>();
+        for (conn_id, inactive_ms) in inactive {
+            if let Some(handle) = self.active_connections.remove(&conn_id) {
+                warn!(
+                    "Reaped connection {} after it was inactive for {}ms.",
+                    conn_id, inactive_ms
+                );
+                handle.abort();
+            }
+        }
+    }
+
     async fn finish_active_connections(&mut self) {
         let active_conn_ids = self
             .active_connections
@@ -107,56 +597,188 @@ impl Server {
             .collect::<Vec<String>>();
         for conn_id in active_conn_ids {
             if let Some(handle) = self.active_connections.remove(&conn_id) {
-                if let Err(_) = handle.await {
+                if handle.await.is_err() {
                     warn!("Tried to shut down connection {} but its thread was either cancelled or panicked.", conn_id);
                 }
             }
         }
     }
 
-    pub async fn run(&mut self) {
-        // init the TCP listener
-        let listener = TcpListener::bind(format!("0.0.0.0:{PORT}").as_str())
-            .await
-            .expect(format!("Could not bind to port {PORT}").as_str());
+    async fn drain_active_connections(
+        &mut self,
+        shutdown_rx: &mut broadcast::Receiver<ShutdownKind>,
+    ) {
+        info!(
+            "Draining {} active connections.",
+            self.active_connections.len()
+        );
+        loop {
+            self.reap_finished_connections();
+            if self.active_connections.is_empty() {
+                return;
+            }
+            // an immediate shutdown during the drain stops waiting on the clients
+            if let Ok(ShutdownKind::Immediate(_)) = shutdown_rx.try_recv() {
+                self.finish_active_connections().await;
+                return;
+            }
+            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+        }
+    }
+
+    async fn start_connection(
+        &mut self,
+        reader: BufReader<TcpStream>,
+        partial_frame: Vec<u8>,
+        addr: SocketAddr,
+        shutdown_tx: &broadcast::Sender<ShutdownKind>,
+        cmd_tx: &mpsc::Sender<ShutdownKind>,
+    ) {
+        let mut connection = match Connection::new(
+            self.connection_ids.next_id(),
+            addr,
+            reader,
+            self.databases.subscribe(),
+            self.config.clone(),
+            self.connection_table.clone(),
+            self.read_limiter.clone(),
+            self.started,
+            shutdown_tx.clone(),
+            cmd_tx.clone(),
+        )
+        .await
+        {
+            Ok(mut connection) => {
+                connection.partial_frame = partial_frame;
+                connection
+            }
+            Err(e) => {
+                error!("Could not get a session from the database. Database file missing or corrupted. Shutting down: {:#}", e);
+                let _ = cmd_tx
+                    .send(ShutdownKind::Immediate(ShutdownReason::Error))
+                    .await;
+                return;
+            }
+        };
+        self.active_connections.insert(
+            connection.conn_id.clone(),
+            tokio::spawn(async move {
+                if let Err(e) = connection.run().await {
+                    warn!("Error running connection {}: {:?}", connection.conn_id, e);
+                }
+            }),
+        );
+    }
+
+    /// Whether a new connection from `addr` is within the accept rate, warning when connections
+    /// start being closed for going over it.
+    fn accept_allowed(&mut self, addr: SocketAddr) -> bool {
+        let Some(limiter) = self.accept_limiter.as_mut() else {
+            return true;
+        };
+        if limiter.try_accept() {
+            if limiter.throttling {
+                limiter.throttling = false;
+                info!("New connections are back within the accept rate.");
+            }
+            return true;
+        }
+        if !limiter.throttling {
+            limiter.throttling = true;
+            warn!(
+                "More than {} connections a second, closing new connections until the rate drops.",
+                limiter.rate
+            );
+        }
+        debug!("Closed the connection from {} over the accept rate.", addr);
+        false
+    }
+
+    /// Whether another connection from `addr` is within --max-connections-per-ip, going by the open
+    /// connections in the table.
+    fn ip_allowed(&self, addr: SocketAddr) -> bool {
+        let Some(max_connections) = self.config.max_connections_per_ip else {
+            return true;
+        };
+        let open_connections = self
+            .connection_table
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|stats| stats.addr.ip() == addr.ip())
+            .count() as u64;
+        if open_connections < max_connections {
+            return true;
+        }
+        debug!(
+            "Refused the connection from {} which already has {} open.",
+            addr, open_connections
+        );
+        false
+    }
+
+    /// Serves the clients on `listener`, starting with those that connected while the index was
+    /// being built.
+    pub async fn run(&mut self, listener: TcpListener, waiting: Vec<WaitingConnection>) {
         // init channels
-        let (shutdown_tx, _) = broadcast::channel::<()>(1);
-        let (cmd_tx, cmd_rx) = mpsc::channel::<()>(1);
+        let (shutdown_tx, _) = broadcast::channel::<ShutdownKind>(2);
+        let (cmd_tx, cmd_rx) = mpsc::channel::<ShutdownKind>(1);
         // start the shutdown thread
         tokio::spawn(shutdown_thread(cmd_rx, shutdown_tx.clone()));
+        tokio::spawn(signal_thread(cmd_tx.clone()));
+        #[cfg(unix)]
+        tokio::spawn(reload_thread(self.config.clone(), self.databases.clone()));
         let mut master_shutdown_subscriber = shutdown_tx.subscribe();
+        for (reader, partial_frame, addr) in waiting {
+            self.start_connection(reader, partial_frame, addr, &shutdown_tx, &cmd_tx)
+                .await;
+        }
         loop {
+            // check for a shutdown on every pass so a steady stream of new connections can't starve it
+            match master_shutdown_subscriber.try_recv() {
+                Ok(ShutdownKind::Immediate(_)) => {
+                    self.finish_active_connections().await;
+                    info!("Server shutting down.  Goodbye!");
+                    log::logger().flush();
+                    return;
+                }
+                Ok(ShutdownKind::Drain) => break,
+                Err(_) => {}
+            }
             match tokio::time::timeout(tokio::time::Duration::from_millis(100), listener.accept())
                 .await
             {
                 Err(_) => {
                     self.reap_finished_connections();
-                    if let Ok(()) = master_shutdown_subscriber.try_recv() {
-                        self.finish_active_connections().await;
-                        info!("Server shutting down.  Goodbye!");
-                        return;
-                    }
+                    self.reap_inactive_connections();
                 }
                 Ok(listen_result) => match listen_result {
-                    Ok((tcpstream, _addr)) => {
-                        let mut connection = Connection::new(
-                            tcpstream,
-                            &self.db,
-                            shutdown_tx.clone(),
-                            cmd_tx.clone(),
+                    Ok((tcpstream, addr)) => {
+                        if !self.accept_allowed(addr) {
+                            // dropping the stream closes it
+                            continue;
+                        }
+                        if !self.ip_allowed(addr) {
+                            // told on the side so that a client that doesn't read can't hold up
+                            // accepting the others
+                            let write_timeout =
+                                tokio::time::Duration::from_millis(self.config.write_timeout_ms);
+                            let mut tcpstream = tcpstream;
+                            tokio::spawn(async move {
+                                let refusal =
+                                    tcpstream.write_all(b"ERR 429 too many connections\r\n");
+                                let _ = tokio::time::timeout(write_timeout, refusal).await;
+                            });
+                            continue;
+                        }
+                        self.start_connection(
+                            BufReader::new(tcpstream),
+                            Vec::new(),
+                            addr,
+                            &shutdown_tx,
+                            &cmd_tx,
                         )
                         .await;
-                        self.active_connections.insert(
-                            connection.conn_id.clone(),
-                            tokio::spawn(async move {
-                                if let Err(e) = connection.run().await {
-                                    warn!(
-                                        "Error running connection {}: {:?}",
-                                        connection.conn_id, e
-                                    );
-                                }
-                            }),
-                        );
                     }
                     Err(e) => {
                         warn!("Error accepting connection: {:?}", e);
@@ -164,9 +786,297 @@ impl Server {
                 },
             }
         }
+        // stop accepting so that new connections are refused while the existing ones finish
+        drop(listener);
+        self.drain_active_connections(&mut master_shutdown_subscriber)
+            .await;
+        info!("Server drained.  Goodbye!");
+        // the connections have all logged their last requests by now, so once the log files have
+        // them there's nothing left to lose when the process exits
+        log::logger().flush();
+    }
+}
+
+/// Binds a listener to `addr`.  An IPv6 listener only takes IPv6 connections unless `dual_stack`
+/// is set, in which case the unspecified address `::` takes IPv4 connections too, whatever the OS
+/// default for `IPV6_V6ONLY` is.
+fn bind(addr: SocketAddr, dual_stack: bool) -> std::io::Result<TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        None,
+    )?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack)?;
+    } else if dual_stack {
+        warn!("--dual-stack only applies to an IPv6 bind address, listening on IPv4 only.");
+    }
+    // like TcpListener::bind, so that a restarted server can take the port straight back
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Why the server couldn't listen on `addr`, and what to do about it for the failures that have an
+/// obvious fix.
+fn describe_bind_error(addr: SocketAddr, e: &std::io::Error) -> String {
+    match e.kind() {
+        std::io::ErrorKind::PermissionDenied if addr.port() < 1024 => format!(
+            "Could not bind to {addr}: permission denied, since ports below 1024 are privileged.  \
+             Use a --port of 1024 or above, or give the server the privilege to bind low ports, \
+             e.g. CAP_NET_BIND_SERVICE on Linux."
+        ),
+        std::io::ErrorKind::AddrInUse => format!(
+            "Could not bind to {addr}: the address is already in use, most likely by another \
+             server.  Stop whatever is listening on it or use another --port."
+        ),
+        std::io::ErrorKind::AddrNotAvailable => format!(
+            "Could not bind to {addr}: the address isn't one of this machine's.  Use another \
+             --bind-address."
+        ),
+        _ => format!("Could not bind to {addr}: {e}"),
+    }
+}
+
+/// The 32-bit FNV-1a hash of `line` without its delimiter, which GET IF CHANGED compares with the
+/// client's.
+fn line_hash(line: &str) -> u32 {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.bytes().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+/// Appends a run of `count` identical lines to a response as `<count> <line>`, like `uniq -c`.
+fn push_run(response: &mut Vec<u8>, line: &[u8], count: u64) {
+    response.extend_from_slice(format!("{count} ").as_bytes());
+    response.extend_from_slice(line);
+    response.push(b'\n');
+}
+
+/// How a request is written in the access log: the command followed by the lines or offsets that it
+/// asks for, or `-` for a frame that couldn't be parsed.
+fn describe_request(
+    buf: &[u8],
+    checksum_algorithm: ChecksumAlgorithm,
+    max_args_len: usize,
+) -> String {
+    let cmd = match Frame::parse(buf, checksum_algorithm, max_args_len) {
+        Ok(frame) => frame.cmd,
+        Err(_) => return "-".to_string(),
+    };
+    match cmd {
+        Command::Get(line_number)
+        | Command::Seek(line_number)
+        | Command::GetFromEnd(line_number)
+        | Command::GetWithHint(line_number)
+        | Command::GetWithCrc(line_number)
+        | Command::Exists(line_number) => {
+            format!("{} {}", cmd.name(), line_number)
+        }
+        Command::GetIfChanged(line_number, hash) => {
+            format!("{} {} {:08x}", cmd.name(), line_number, hash)
+        }
+        Command::GetTransform(line_number, transform) => {
+            format!("{} {} {}", cmd.name(), line_number, transform.name())
+        }
+        Command::Hello(algorithm, false) => format!("{} {}", cmd.name(), algorithm.name()),
+        Command::Hello(algorithm, true) => format!("{} {} follow", cmd.name(), algorithm.name()),
+        Command::GetRange(start, end)
+        | Command::GetRangeReverse(start, end)
+        | Command::MetaRange(start, end)
+        | Command::OffsetTable(start, end)
+        | Command::UniqueRuns(start, end)
+        | Command::Page(start, end)
+        | Command::RawSpan(start, end)
+        | Command::GetRangeCached(start, end) => {
+            format!("{} {} {}", cmd.name(), start, end)
+        }
+        Command::Outline(center, radius) => format!("{} {} {}", cmd.name(), center, radius),
+        Command::LineAtByte(offset) => format!("{} {}", cmd.name(), offset),
+        Command::ChunkPlan(chunks) => format!("{} {}", cmd.name(), chunks),
+        Command::JoinRange(start, end, ref separator) => format!(
+            "{} {} {} {}",
+            cmd.name(),
+            start,
+            end,
+            separator.escape_ascii()
+        ),
+        Command::Section(ref start_marker, ref end_marker, _) => format!(
+            "{} {} {}",
+            cmd.name(),
+            start_marker.escape_ascii(),
+            end_marker.escape_ascii()
+        ),
+        Command::FilterColumn(column, delimiter, min, max) => format!(
+            "{} {} {} {} {}",
+            cmd.name(),
+            column,
+            [delimiter].escape_ascii(),
+            min,
+            max
+        ),
+        Command::GetByteRange(start, end) => format!("{} {} {}", cmd.name(), start, end),
+        Command::MultiRange(ref ranges) => ranges
+            .iter()
+            .fold(cmd.name().to_string(), |request, (start, end)| {
+                format!("{request} {start}-{end}")
+            }),
+        _ => cmd.name().to_string(),
+    }
+}
+
+/// The status of a response for the access log, which is 200 for OK, 304 for NOTMODIFIED and the
+/// code of an ERR, or of a JSON error.  An ERR without a code is a frame that couldn't be parsed, so
+/// it's a 400.
+fn response_status(response: &[u8]) -> Option<u16> {
+    if response.starts_with(b"OK") {
+        return Some(200);
+    }
+    if response.starts_with(b"{") {
+        let response: serde_json::Value = serde_json::from_slice(response).ok()?;
+        return match response["code"].as_u64() {
+            Some(code) => u16::try_from(code).ok(),
+            None if response["status"] == "ok" => Some(200),
+            None => Some(400),
+        };
+    }
+    if response.starts_with(b"NOTMODIFIED") {
+        return Some(304);
+    }
+    let code = response.strip_prefix(b"ERR")?;
+    let code = code.strip_prefix(b" ").unwrap_or(code);
+    let digits = code.iter().take_while(|byte| byte.is_ascii_digit()).count();
+    Some(
+        std::str::from_utf8(&code[..digits])
+            .ok()
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(400),
+    )
+}
+
+/// Reads `line_number` for a GET from the line cache, or from `session` once `read_limiter` gives it
+/// a turn when the line isn't cached.
+async fn read_line(
+    db: &Database,
+    read_limiter: Option<&ReadLimiter>,
+    session: &mut Session,
+    line_number: u64,
+) -> Result<String, SessionError> {
+    read_line_noting_hit(db, read_limiter, session, line_number)
+        .await
+        .map(|(line, _)| line)
+}
+
+/// Reads a line like `read_line`, along with whether it came from the line cache rather than the
+/// file.
+async fn read_line_noting_hit(
+    db: &Database,
+    read_limiter: Option<&ReadLimiter>,
+    session: &mut Session,
+    line_number: u64,
+) -> Result<(String, bool), SessionError> {
+    if let Some(line) = db.cached_line(line_number) {
+        return Ok((line, true));
+    }
+    let line = match read_limiter {
+        Some(read_limiter) => read_limiter.get(session, line_number).await?,
+        None => session.get(line_number).await?,
+    };
+    db.cache_line(line_number, &line);
+    Ok((line, false))
+}
+
+/// Ends a multi-line response.  The last line of the file may not have a delimiter but END still
+/// needs a line of its own.
+fn push_end(response: &mut Vec<u8>) {
+    push_trailer(response, b"END\r\n");
+}
+
+/// Ends a multi-line response with `trailer`, on a line of its own like END.
+fn push_trailer(response: &mut Vec<u8>, trailer: &[u8]) {
+    if !response.ends_with(b"\n") {
+        response.push(b'\n');
+    }
+    response.extend_from_slice(trailer);
+}
+
+/// Reads the next frame from `reader` with checksums worked out with `checksum_algorithm`, which is
+/// empty once the client has gone.  No more is read than the longest frame with args of up to
+/// `max_args_len` bytes.  The frame carries on from `started`, which has already been read.
+async fn read_frame(
+    reader: &mut BufReader<TcpStream>,
+    started: Vec<u8>,
+    checksum_algorithm: ChecksumAlgorithm,
+    max_args_len: usize,
+) -> Result<Vec<u8>> {
+    let max_frame_len = Command::max_frame_len(max_args_len);
+    let mut buf = started;
+    let mut empty_reads = 0;
+    loop {
+        let limit = max_frame_len.saturating_sub(buf.len()) as u64;
+        if (&mut *reader).take(limit).read_until(0xA, &mut buf).await? == 0 {
+            // a read can come back empty without the stream being at its end, so the client
+            // has only gone once the following reads are empty too
+            empty_reads += 1;
+            if empty_reads >= EMPTY_READS_BEFORE_DISCONNECT {
+                break;
+            }
+            continue;
+        }
+        empty_reads = 0;
+        // anything that has run on this far without ending isn't a frame, so it's handed on as it
+        // is rather than read for as long as the client keeps sending
+        if buf.len() >= max_frame_len {
+            break;
+        }
+        // a length prefixed payload can contain the delimiter, so keep reading until the frame is whole
+        if !matches!(
+            Frame::parse(&buf, checksum_algorithm, max_args_len),
+            Err(FrameError::Incomplete)
+        ) {
+            break;
+        }
+    }
+    Ok(buf)
+}
+
+/// Waits for an immediate shutdown, letting drains go by since they leave the connections to finish
+/// on their own.
+async fn immediate_shutdown(shutdown_rx: &mut broadcast::Receiver<ShutdownKind>) -> ShutdownReason {
+    loop {
+        match shutdown_rx.recv().await {
+            Ok(ShutdownKind::Immediate(reason)) => return reason,
+            Ok(ShutdownKind::Drain) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            // the server has gone so there's nothing left to wait on
+            Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+/// The line number of a frame that is a valid GET, which can be looked up alongside others.
+fn pipelined_get(
+    buf: &[u8],
+    checksum_algorithm: ChecksumAlgorithm,
+    max_args_len: usize,
+) -> Option<u32> {
+    match Frame::parse(buf, checksum_algorithm, max_args_len) {
+        Ok(Frame {
+            cmd: Command::Get(line_number),
+            ..
+        }) => Some(line_number),
+        _ => None,
     }
 }
 
+struct RequestTrace {
+    seq: u64,
+    started: tokio::time::Instant,
+}
+
 enum FrameAction {
     Continue,
     EndConnection,
@@ -174,40 +1084,415 @@ enum FrameAction {
 
 struct Connection {
     conn_id: String,
-    shutdown_rx: broadcast::Receiver<()>,
-    cmd_tx: mpsc::Sender<()>,
+    addr: SocketAddr,
+    config: Arc<Config>,
+    shutdown_rx: broadcast::Receiver<ShutdownKind>,
+    cmd_tx: mpsc::Sender<ShutdownKind>,
     reader: BufReader<TcpStream>,
     session: Session,
+    db: Arc<Database>,
+    // the server's latest database, which a connection that follows reloads moves on to
+    databases: watch::Receiver<Arc<Database>>,
+    // picked with HELLO, whether the connection follows reloads rather than keeping its snapshot
+    follow_reloads: bool,
+    // when set, lines are sent prefixed with their number and this separator
+    line_number_separator: Option<u8>,
+    // when set, GETs are answered with JSON objects instead
+    json_responses: bool,
+    // picked with HELLO, what the checksums of the client's frames are worked out with
+    checksum_algorithm: ChecksumAlgorithm,
+    // the number of requests and response bytes so far, for tracing
+    request_seq: u64,
+    bytes_written: u64,
+    // set once a write to the client has failed, after which nothing more can be sent
+    write_failed: bool,
+    // the status of the response to the request being handled, for the access log
+    response_status: Option<u16>,
+    // sessions for looking up pipelined GETs alongside each other, kept between batches
+    pipeline_sessions: Vec<Session>,
+    // the start of a frame that was read while the index was being built, which the next frame
+    // read carries on from
+    partial_frame: Vec<u8>,
+    // when the client last sent a request, and whether it's been warned about being idle since
+    last_request_at: tokio::time::Instant,
+    idle_warned: bool,
+    // when the client last sent a request, which the IDLE warning doesn't move
+    file_used_at: tokio::time::Instant,
+    // this connection's entry in the table of open connections, and the table itself for STATS
+    stats: Arc<ConnectionStats>,
+    connection_table: ConnectionTable,
+    read_limiter: Option<Arc<ReadLimiter>>,
+    // for UPTIME
+    server_started: ServerStart,
 }
 
 impl Connection {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
-        stream: TcpStream,
-        db: &db::Database,
-        shutdown_tx: broadcast::Sender<()>,
-        cmd_tx: mpsc::Sender<()>,
-    ) -> Connection {
-        Connection {
-            conn_id: uuid::Uuid::new_v4().to_string(),
+        conn_id: String,
+        addr: SocketAddr,
+        reader: BufReader<TcpStream>,
+        mut databases: watch::Receiver<Arc<Database>>,
+        config: Arc<Config>,
+        connection_table: ConnectionTable,
+        read_limiter: Option<Arc<ReadLimiter>>,
+        server_started: ServerStart,
+        shutdown_tx: broadcast::Sender<ShutdownKind>,
+        cmd_tx: mpsc::Sender<ShutdownKind>,
+    ) -> Result<Connection> {
+        let db = databases.borrow_and_update().clone();
+        let session = db.get_session().await?;
+        let stats = Arc::new(ConnectionStats {
+            addr,
+            connected_since: chrono::Local::now(),
+            requests: AtomicU64::new(0),
+            last_active_ms: AtomicU64::new(server_started.instant.elapsed().as_millis() as u64),
+        });
+        connection_table
+            .lock()
+            .unwrap()
+            .insert(conn_id.clone(), stats.clone());
+        Ok(Connection {
+            conn_id,
+            addr,
+            config,
             shutdown_rx: shutdown_tx.subscribe(),
             cmd_tx,
-            reader: BufReader::new(stream),
-            session: db.get_session().await.expect(
-                "Could not get a session from the database. Database file missing or corrupted.",
-            ),
+            reader,
+            session,
+            db,
+            databases,
+            follow_reloads: false,
+            line_number_separator: None,
+            json_responses: false,
+            checksum_algorithm: ChecksumAlgorithm::default(),
+            request_seq: 0,
+            bytes_written: 0,
+            write_failed: false,
+            response_status: None,
+            pipeline_sessions: Vec::new(),
+            partial_frame: Vec::new(),
+            last_request_at: tokio::time::Instant::now(),
+            idle_warned: false,
+            file_used_at: tokio::time::Instant::now(),
+            stats,
+            connection_table,
+            read_limiter,
+            server_started,
+        })
+    }
+
+    /// Tells the reaper that the connection is still doing something.
+    fn record_activity(&self) {
+        let now = self.server_started.instant.elapsed().as_millis() as u64;
+        self.stats.last_active_ms.store(now, Ordering::Relaxed);
+    }
+
+    /// Writes a response to the client, giving up on a client that has stopped reading it so that
+    /// it can't hold the connection open forever.
+    async fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let write_timeout = tokio::time::Duration::from_millis(self.config.write_timeout_ms);
+        match tokio::time::timeout(write_timeout, self.reader.get_mut().write_all(bytes)).await {
+            Ok(Ok(())) => {
+                self.bytes_written += bytes.len() as u64;
+                self.record_activity();
+                if self.response_status.is_none() {
+                    self.response_status = response_status(bytes);
+                }
+                Ok(())
+            }
+            Ok(Err(e)) => {
+                self.write_failed = true;
+                Err(e.into())
+            }
+            Err(_) => {
+                self.write_failed = true;
+                warn!(
+                    "{} - Timed out writing to the client after {}ms, closing the connection.",
+                    self.conn_id, self.config.write_timeout_ms
+                );
+                anyhow::bail!("write timed out")
+            }
+        }
+    }
+
+    /// Writes the lines `start..=end` as they are in the file, after `OK` and followed by
+    /// `trailer`, sending them straight from the file unless that's turned off or unsupported.
+    async fn write_lines_from_file(
+        &mut self,
+        request: &str,
+        start: u64,
+        end: u64,
+        trailer: &[u8],
+    ) -> Result<()> {
+        let (offset, end_offset) = match self.session.byte_span(start, end) {
+            Ok(span) => span,
+            Err(e) => return self.write_session_error(request, e).await,
+        };
+        let len = end_offset - offset;
+        // nothing can be taken back once OK is sent, so the file is opened again before it if it
+        // was released, which fails if it was replaced
+        if let Err(e) = self.session.open_file() {
+            return self.write_session_error(request, e).await;
+        }
+        // the last line of the file may not have a delimiter, and END still needs a line of its own
+        let ends_with_delimiter = match self.session.read_bytes(end_offset - 1, 1) {
+            Ok(last_byte) => last_byte == b"\n",
+            Err(e) => return self.write_session_error(request, e).await,
+        };
+        self.write(b"OK\r\n").await?;
+        if !self.config.no_zero_copy {
+            let write_timeout = tokio::time::Duration::from_millis(self.config.write_timeout_ms);
+            let file = self
+                .session
+                .file()
+                .expect("the file is only released between requests");
+            let sent = tokio::time::timeout(
+                write_timeout,
+                sendfile::send_file(self.reader.get_ref(), file, offset, len),
+            )
+            .await;
+            match sent {
+                Ok(Ok(())) => {
+                    self.bytes_written += len;
+                    let mut response = Vec::new();
+                    if !ends_with_delimiter {
+                        response.push(b'\n');
+                    }
+                    response.extend_from_slice(trailer);
+                    return self.write(&response).await;
+                }
+                Ok(Err(e)) if e.kind() == std::io::ErrorKind::Unsupported => {
+                    debug!(
+                        "{} - The data file can't be sent with sendfile, copying it instead: {}",
+                        self.conn_id, e
+                    );
+                }
+                Ok(Err(e)) => {
+                    self.write_failed = true;
+                    return Err(e.into());
+                }
+                Err(_) => {
+                    self.write_failed = true;
+                    warn!(
+                        "{} - Timed out writing to the client after {}ms, closing the connection.",
+                        self.conn_id, self.config.write_timeout_ms
+                    );
+                    anyhow::bail!("write timed out")
+                }
+            }
+        }
+        let mut response = self.session.read_bytes(offset, len)?;
+        push_trailer(&mut response, trailer);
+        self.write(&response).await
+    }
+
+    /// Where to cut the range `start..=end`, which is over the response limit, so that the most
+    /// lines fit: the last line to send going forwards, or when `reverse` the last going backwards.
+    /// There's nowhere when even the first line on its own is over the limit.
+    fn fitting_cut(
+        &mut self,
+        start: u64,
+        end: u64,
+        reverse: bool,
+    ) -> Result<Option<u64>, SessionError> {
+        let max_response_size = self.config.max_response_size;
+        let (mut fits, mut too_large) = if reverse {
+            (end + 1, start)
+        } else {
+            (start - 1, end)
+        };
+        // the size only grows as the cut takes in more lines, so it can be binary searched
+        while fits.abs_diff(too_large) > 1 {
+            let mid = (fits + too_large) / 2;
+            let size = if reverse {
+                self.session.range_size(mid, end)?
+            } else {
+                self.session.range_size(start, mid)?
+            };
+            if size <= max_response_size {
+                fits = mid;
+            } else {
+                too_large = mid;
+            }
+        }
+        let nothing_fits = if reverse { fits > end } else { fits < start };
+        Ok((!nothing_fits).then_some(fits))
+    }
+
+    /// Responds to a request whose database read failed, logging it at a level that matches whose
+    /// fault it was.
+    async fn write_session_error(&mut self, request: &str, e: SessionError) -> Result<()> {
+        let status = self.log_session_error(request, &e);
+        self.write(format!("ERR {status}\r\n").as_bytes()).await
+    }
+
+    /// Logs a failed database read like `write_session_error` and returns the status to answer it
+    /// with after `ERR`.
+    fn log_session_error(&self, request: &str, e: &SessionError) -> &'static str {
+        match e {
+            SessionError::LineNotFound => {
+                debug!("{} - {} is not in the index", self.conn_id, request);
+                "404"
+            }
+            SessionError::Io(e) => {
+                error!(
+                    "{} - {} failed to read from the database file: {:?}",
+                    self.conn_id, request, e
+                );
+                "500"
+            }
+            SessionError::EndOfFile => {
+                debug!("{} - {} is past the last line", self.conn_id, request);
+                "EOF"
+            }
+            SessionError::StartOfFile => {
+                debug!("{} - {} is before the first line", self.conn_id, request);
+                "BOF"
+            }
+            SessionError::OutsideWindow => {
+                debug!(
+                    "{} - {} is outside the served window",
+                    self.conn_id, request
+                );
+                "421"
+            }
+        }
+    }
+
+    async fn write_get(
+        &mut self,
+        line_number: u32,
+        line: Result<String, SessionError>,
+    ) -> Result<()> {
+        if self.json_responses {
+            return self.write_json_get(line_number, line).await;
+        }
+        match line {
+            Ok(line) => {
+                let mut response = match self.db.record_served(line_number as u64) {
+                    Some(served) => format!("OK served={served}\r\n").into_bytes(),
+                    None => b"OK\r\n".to_vec(),
+                };
+                self.push_line(&mut response, line_number as u64, &line);
+                self.write(&response).await
+            }
+            Err(e) => {
+                self.write_session_error(&format!("GET {line_number}"), e)
+                    .await
+            }
+        }
+    }
+
+    /// Answers a GET with a JSON object on a line of its own, with the line's number and content or
+    /// the code and reason it couldn't be sent.
+    async fn write_json_get(
+        &mut self,
+        line_number: u32,
+        line: Result<String, SessionError>,
+    ) -> Result<()> {
+        let response = match line {
+            Ok(line) => {
+                let content = line.strip_suffix('\n').unwrap_or(&line);
+                let mut response = serde_json::json!({
+                    "status": "ok",
+                    "line": line_number,
+                    "content": content,
+                });
+                if let Some(served) = self.db.record_served(line_number as u64) {
+                    response["served"] = served.into();
+                }
+                response
+            }
+            Err(e) => {
+                let status = self.log_session_error(&format!("GET {line_number}"), &e);
+                // the statuses that aren't numbers, EOF and BOF, are sent as they are
+                let code: serde_json::Value = match status.parse::<u16>() {
+                    Ok(code) => code.into(),
+                    Err(_) => status.into(),
+                };
+                serde_json::json!({
+                    "status": "error",
+                    "code": code,
+                    "message": e.to_string(),
+                })
+            }
+        };
+        self.write(format!("{response}\r\n").as_bytes()).await
+    }
+
+    /// Gets `line_number` for the connection like `read_line`.
+    async fn get(&mut self, line_number: u64) -> Result<String, SessionError> {
+        read_line(
+            &self.db,
+            self.read_limiter.as_deref(),
+            &mut self.session,
+            line_number,
+        )
+        .await
+    }
+
+    /// Compiles a pattern sent with `request`, or responds with `ERR 400` and why when it's invalid.
+    async fn compile_regex(
+        &mut self,
+        request: &str,
+        pattern: &str,
+    ) -> Result<Option<regex::bytes::Regex>> {
+        match regex::bytes::RegexBuilder::new(pattern)
+            .size_limit(REGEX_SIZE_LIMIT)
+            .build()
+        {
+            Ok(re) => Ok(Some(re)),
+            Err(e) => {
+                debug!(
+                    "{} - {} {} is invalid: {}",
+                    self.conn_id, request, pattern, e
+                );
+                // compile errors point at the problem over several lines
+                let message = e
+                    .to_string()
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                self.write(format!("ERR 400 {message}\r\n").as_bytes())
+                    .await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Appends `line` to a response, prefixed with its line number when the client asked for them.
+    fn push_line(&self, response: &mut Vec<u8>, line_number: u64, line: &str) {
+        if let Some(separator) = self.line_number_separator {
+            response.extend_from_slice(line_number.to_string().as_bytes());
+            response.push(separator);
         }
+        response.extend_from_slice(line.as_bytes());
     }
 
     async fn handle_frame(&mut self, buf: Vec<u8>) -> Result<FrameAction> {
         // handle frame
-        let frame = match Frame::try_from(&buf[..]) {
+        let frame = match Frame::parse(&buf, self.checksum_algorithm, self.max_args_len()) {
             Ok(frame) => frame,
             Err(FrameError::ClientDisconnected) => {
                 warn!("Lost connection from {} unexpectedly.", self.conn_id);
                 return Ok(FrameAction::EndConnection);
             }
+            // the args that were declared are still on their way, and there's no telling where
+            // they end and the next frame starts without reading them, so the connection is closed
+            Err(FrameError::TooLarge) => {
+                warn!(
+                    "{} - Closing the connection after it declared args over the {} byte limit.",
+                    self.conn_id,
+                    self.max_args_len()
+                );
+                let _ = self.write(b"ERR 413\r\n").await;
+                return Ok(FrameAction::EndConnection);
+            }
             Err(_e) => {
-                if let Err(e) = self.reader.get_mut().write_all(b"ERR\r\n").await {
+                if let Err(e) = self.write(b"ERR\r\n").await {
                     warn!("Error writing to client: {:?}", e);
                     return Ok(FrameAction::EndConnection);
                 }
@@ -217,68 +1502,2125 @@ impl Connection {
 
         match frame.cmd {
             Command::Get(line_number) => {
-                info!("{} - GET {}", self.conn_id, line_number);
-                match self.session.get(line_number as u64).await {
+                debug!("{} - GET {}", self.conn_id, line_number);
+                let line = self.get(line_number as u64).await;
+                self.write_get(line_number, line).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::GetIfChanged(line_number, hash) => {
+                debug!(
+                    "{} - GET IF CHANGED {} {:08x}",
+                    self.conn_id, line_number, hash
+                );
+                match self.get(line_number as u64).await {
+                    Ok(line) if line_hash(&line) == hash => {
+                        self.write(b"NOTMODIFIED\r\n").await?;
+                    }
+                    line => self.write_get(line_number, line).await?,
+                }
+                Ok(FrameAction::Continue)
+            }
+            Command::GetWithHint(line_number) => {
+                debug!("{} - GET WITH HINT {}", self.conn_id, line_number);
+                match self.get(line_number as u64).await {
                     Ok(line) => {
-                        self.reader.get_mut().write_all(b"OK\r\n").await?;
-                        self.reader.get_mut().write_all(line.as_bytes()).await?;
+                        // a shard only counts the lines it serves
+                        let remaining = self.session.window().1 - line_number as u64;
+                        let mut response = match self.db.record_served(line_number as u64) {
+                            Some(served) => format!("OK served={served} remaining={remaining}\r\n"),
+                            None => format!("OK remaining={remaining}\r\n"),
+                        }
+                        .into_bytes();
+                        self.push_line(&mut response, line_number as u64, &line);
+                        self.write(&response).await?;
                     }
-                    Err(_) => {
-                        self.reader.get_mut().write_all(b"ERR\r\n").await?;
+                    Err(e) => {
+                        self.write_session_error(&format!("GET WITH HINT {line_number}"), e)
+                            .await?
                     }
                 }
                 Ok(FrameAction::Continue)
             }
-            Command::Quit => {
-                info!("{} - QUIT", self.conn_id);
-                let _ = self.reader.get_mut().shutdown().await;
-                Ok(FrameAction::EndConnection)
+            Command::GetWithCrc(line_number) => {
+                debug!("{} - GET WITH CRC {}", self.conn_id, line_number);
+                match self.get(line_number as u64).await {
+                    Ok(line) => {
+                        // the CRC covers everything after the header, prefix and delimiter included,
+                        // so the client can check the bytes as it got them
+                        let mut body = Vec::new();
+                        self.push_line(&mut body, line_number as u64, &line);
+                        let crc = ChecksumAlgorithm::Crc32.compute(&body);
+                        let mut response = match self.db.record_served(line_number as u64) {
+                            Some(served) => format!("OK served={served} crc={crc:08x}\r\n"),
+                            None => format!("OK crc={crc:08x}\r\n"),
+                        }
+                        .into_bytes();
+                        response.extend_from_slice(&body);
+                        self.write(&response).await?;
+                    }
+                    Err(e) => {
+                        self.write_session_error(&format!("GET WITH CRC {line_number}"), e)
+                            .await?
+                    }
+                }
+                Ok(FrameAction::Continue)
             }
-            Command::Shutdown => {
-                info!("{} - SHUTDOWN", self.conn_id);
-                if let Err(_) = self.cmd_tx.send(()).await {
-                    error!("Failed to send shutdown signal to server. Forcing shutdown.");
-                    std::process::exit(1);
+            Command::GetFromEnd(n) => {
+                debug!("{} - GET FROM END {}", self.conn_id, n);
+                // a shard counts back from the end of its window
+                let (first, last) = self.session.window();
+                let line_count = (last + 1).saturating_sub(first);
+                if n == 0 || n as u64 > line_count {
+                    self.write_session_error(
+                        &format!("GET FROM END {n}"),
+                        SessionError::LineNotFound,
+                    )
+                    .await?;
+                    return Ok(FrameAction::Continue);
                 }
-                Ok(FrameAction::EndConnection)
+                let line_number = (last - n as u64 + 1) as u32;
+                let line = self.get(line_number as u64).await;
+                self.write_get(line_number, line).await?;
+                Ok(FrameAction::Continue)
             }
-        }
-    }
-
-    pub async fn run(&mut self) -> Result<()> {
-        loop {
-            // get next message on stream
-            let mut buf = Vec::new();
-            self.reader.read_until(0xA, &mut buf).await?;
+            Command::GetTransform(line_number, transform) => {
+                let request = format!("GET TRANSFORM {} {}", line_number, transform.name());
+                debug!("{} - {}", self.conn_id, request);
+                // the line's bytes as they are, since base64 is for lines that aren't text
+                let line = self
+                    .session
+                    .line_span(line_number as u64)
+                    .and_then(|(offset, len)| self.session.read_bytes(offset, len));
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        self.write_session_error(&request, e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                let line = line.strip_suffix(b"\n").unwrap_or(&line);
+                let transformed = match transform {
+                    Transform::Base64 => base64::engine::general_purpose::STANDARD.encode(line),
+                    Transform::Trim | Transform::Uppercase => {
+                        let Ok(line) = std::str::from_utf8(line) else {
+                            // like a GET of a line that isn't text
+                            error!("{} - {} is not valid UTF-8", self.conn_id, request);
+                            self.write(b"ERR 500\r\n").await?;
+                            return Ok(FrameAction::Continue);
+                        };
+                        match transform {
+                            Transform::Trim => line.trim().to_string(),
+                            _ => line.to_uppercase(),
+                        }
+                    }
+                };
+                self.write(format!("OK\r\n{transformed}\n").as_bytes())
+                    .await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::GetRange(start, end) | Command::GetRangeReverse(start, end) => {
+                let reverse = matches!(frame.cmd, Command::GetRangeReverse(..));
+                let request = format!("{} {} {}", frame.cmd.name(), start, end);
+                info!("{} - {}", self.conn_id, request);
+                let (start, end) = (start as u64, end as u64);
+                let size = match self.session.range_size(start, end) {
+                    Ok(size) => size,
+                    Err(e) => {
+                        self.write_session_error(&request, e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                let (mut start, mut end) = (start, end);
+                let mut trailer = b"END\r\n".to_vec();
+                if size > self.config.max_response_size {
+                    let cut = if self.config.truncate_large_ranges {
+                        self.fitting_cut(start, end, reverse)
+                    } else {
+                        Ok(None)
+                    };
+                    match cut {
+                        // the client carries on from the line after the last one sent
+                        Ok(Some(cut)) => {
+                            let next = if reverse { cut - 1 } else { cut + 1 };
+                            debug!(
+                                "{} - {} of {} bytes is over the {} byte response limit, truncating it before line {}",
+                                self.conn_id, request, size, self.config.max_response_size, next
+                            );
+                            trailer = format!("TRUNCATED\r\n{next}\r\n").into_bytes();
+                            if reverse {
+                                start = cut;
+                            } else {
+                                end = cut;
+                            }
+                        }
+                        Ok(None) => {
+                            debug!(
+                                "{} - {} of {} bytes is over the {} byte response limit",
+                                self.conn_id, request, size, self.config.max_response_size
+                            );
+                            self.write(b"ERR 413\r\n").await?;
+                            return Ok(FrameAction::Continue);
+                        }
+                        Err(e) => {
+                            self.write_session_error(&request, e).await?;
+                            return Ok(FrameAction::Continue);
+                        }
+                    }
+                }
+                // lines sent forwards without numbers are the same bytes as they are in the file
+                if !reverse && self.line_number_separator.is_none() {
+                    self.write_lines_from_file(&request, start, end, &trailer)
+                        .await?;
+                    return Ok(FrameAction::Continue);
+                }
+                match self.session.get_range(start, end).await {
+                    Ok(lines) => {
+                        let mut response = b"OK\r\n".to_vec();
+                        let mut lines: Vec<_> = (start..=end).zip(lines.iter()).collect();
+                        // the lines are read forwards since that's how they're laid out in the file
+                        if reverse {
+                            lines.reverse();
+                        }
+                        for (line_number, line) in lines {
+                            self.push_line(&mut response, line_number, line);
+                            // the last line of the file may not have a delimiter, which only
+                            // matters when other lines come after it
+                            if !response.ends_with(b"\n") {
+                                response.push(b'\n');
+                            }
+                        }
+                        push_trailer(&mut response, &trailer);
+                        self.write(&response).await?;
+                    }
+                    Err(e) => self.write_session_error(&request, e).await?,
+                }
+                Ok(FrameAction::Continue)
+            }
+            Command::Page(start, page_size) => {
+                let request = format!("PAGE {start} {page_size}");
+                info!("{} - {}", self.conn_id, request);
+                if page_size == 0 {
+                    self.write(b"ERR 400 the page size must be at least 1\r\n")
+                        .await?;
+                    return Ok(FrameAction::Continue);
+                }
+                let (start, last) = (start as u64, self.session.window().1);
+                // a page past the end is empty rather than an error, so paging never overshoots
+                if start > last {
+                    self.write(b"OK\r\nEOF\r\nEND\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                let end = (start + page_size as u64 - 1).min(last);
+                let size = match self.session.range_size(start, end) {
+                    Ok(size) => size,
+                    Err(e) => {
+                        self.write_session_error(&request, e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                if size > self.config.max_response_size {
+                    debug!(
+                        "{} - {} of {} bytes is over the {} byte response limit",
+                        self.conn_id, request, size, self.config.max_response_size
+                    );
+                    self.write(b"ERR 413\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                match self.session.get_range(start, end).await {
+                    Ok(lines) => {
+                        // the token comes first so that it can't be mistaken for one of the lines
+                        let mut response = if end < last {
+                            format!("OK\r\nNEXT {}\r\n", end + 1).into_bytes()
+                        } else {
+                            b"OK\r\nEOF\r\n".to_vec()
+                        };
+                        for (line_number, line) in (start..=end).zip(lines.iter()) {
+                            self.push_line(&mut response, line_number, line);
+                            if !response.ends_with(b"\n") {
+                                response.push(b'\n');
+                            }
+                        }
+                        push_end(&mut response);
+                        self.write(&response).await?;
+                    }
+                    Err(e) => self.write_session_error(&request, e).await?,
+                }
+                Ok(FrameAction::Continue)
+            }
+            Command::RawSpan(start, end) => {
+                let request = format!("RAW SPAN {start} {end}");
+                info!("{} - {}", self.conn_id, request);
+                let (offset, end_offset) = match self.session.byte_span(start as u64, end as u64) {
+                    Ok(span) => span,
+                    Err(e) => {
+                        self.write_session_error(&request, e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                let len = end_offset - offset;
+                if len > self.config.max_response_size {
+                    debug!(
+                        "{} - {} of {} bytes is over the {} byte response limit",
+                        self.conn_id, request, len, self.config.max_response_size
+                    );
+                    self.write(b"ERR 413\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                let bytes = match self.session.read_bytes(offset, len) {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        self.write_session_error(&request, e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                // the bytes are sent with their length like GET INDEX, since the last line may not
+                // have a delimiter for END to follow
+                let mut response = format!("OK\r\n{len}\r\n").into_bytes();
+                response.extend_from_slice(&bytes);
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::JoinRange(start, end, separator) => {
+                let request = format!("JOIN RANGE {} {} {}", start, end, separator.escape_ascii());
+                info!("{} - {}", self.conn_id, request);
+                let (start, end) = (start as u64, end as u64);
+                let size = match self.session.range_size(start, end) {
+                    Ok(size) => size,
+                    Err(e) => {
+                        self.write_session_error(&request, e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                // each delimiter is swapped for a separator, bar the last line's
+                let joined_size = size + (end - start) * separator.len() as u64;
+                if joined_size > self.config.max_response_size {
+                    debug!(
+                        "{} - {} of about {} bytes is over the {} byte response limit",
+                        self.conn_id, request, joined_size, self.config.max_response_size
+                    );
+                    self.write(b"ERR 413\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                let mut response = b"OK\r\n".to_vec();
+                let scan = self.session.scan_range(start, end, |line_number, line| {
+                    if line_number > start {
+                        response.extend_from_slice(&separator);
+                    }
+                    response.extend_from_slice(line);
+                });
+                if let Err(e) = scan.await {
+                    self.write_session_error(&request, e).await?;
+                    return Ok(FrameAction::Continue);
+                }
+                response.extend_from_slice(b"\r\n");
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::MetaRange(start, end) => {
+                let request = format!("META RANGE {start} {end}");
+                info!("{} - {}", self.conn_id, request);
+                let (start, end) = (start as u64, end as u64);
+                if let Err(e) = self.session.range_size(start, end) {
+                    self.write_session_error(&request, e).await?;
+                    return Ok(FrameAction::Continue);
+                }
+                let mut response = b"OK\r\n".to_vec();
+                for line_number in start..=end {
+                    let (offset, length) = match self.session.line_span(line_number) {
+                        Ok(span) => span,
+                        Err(e) => {
+                            self.write_session_error(&request, e).await?;
+                            return Ok(FrameAction::Continue);
+                        }
+                    };
+                    response.extend_from_slice(
+                        format!("{line_number} {offset} {length}\r\n").as_bytes(),
+                    );
+                    // the rows aren't in the index's size, so the limit is checked as they grow
+                    if response.len() as u64 > self.config.max_response_size {
+                        debug!(
+                            "{} - {} is over the {} byte response limit",
+                            self.conn_id, request, self.config.max_response_size
+                        );
+                        self.write(b"ERR 413\r\n").await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                }
+                response.extend_from_slice(b"END\r\n");
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::OffsetTable(start, end) => {
+                let request = format!("OFFSET TABLE {start} {end}");
+                info!("{} - {}", self.conn_id, request);
+                let (start, end) = (start as u64, end as u64);
+                if let Err(e) = self.session.range_size(start, end) {
+                    self.write_session_error(&request, e).await?;
+                    return Ok(FrameAction::Continue);
+                }
+                let table_len = (end - start + 1) * OFFSET_TABLE_ROW_LEN;
+                if table_len > self.config.max_response_size {
+                    debug!(
+                        "{} - {} is over the {} byte response limit",
+                        self.conn_id, request, self.config.max_response_size
+                    );
+                    self.write(b"ERR 413\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                // binary like GET INDEX, so it's sent with its length rather than ended with END
+                let mut response = format!("OK\r\n{table_len}\r\n").into_bytes();
+                for line_number in start..=end {
+                    let (offset, length) = match self.session.line_span(line_number) {
+                        Ok(span) => span,
+                        Err(e) => {
+                            self.write_session_error(&request, e).await?;
+                            return Ok(FrameAction::Continue);
+                        }
+                    };
+                    response.extend_from_slice(&offset.to_be_bytes());
+                    response.extend_from_slice(&length.to_be_bytes());
+                }
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::VerifyIndex => {
+                info!("{} - VERIFY INDEX", self.conn_id);
+                let response = match self.db.verify_index() {
+                    Ok(None) => "OK\r\nok\r\n".to_string(),
+                    Ok(Some(line_number)) => {
+                        warn!(
+                            "{} - VERIFY INDEX found a mismatch at line {}",
+                            self.conn_id, line_number
+                        );
+                        format!("OK\r\nmismatch at line {line_number}\r\n")
+                    }
+                    Err(e) => {
+                        error!("{} - VERIFY INDEX failed: {:#}", self.conn_id, e);
+                        "ERR 500\r\n".to_string()
+                    }
+                };
+                self.write(response.as_bytes()).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Find(content) => {
+                info!("{} - FIND {} bytes", self.conn_id, content.len());
+                let line_numbers = if self.config.find_cache {
+                    match self.db.find_cached(&content) {
+                        Ok(line_numbers) => line_numbers,
+                        Err(e) => {
+                            error!("{} - FIND failed: {:#}", self.conn_id, e);
+                            self.write(b"ERR 500\r\n").await?;
+                            return Ok(FrameAction::Continue);
+                        }
+                    }
+                } else {
+                    let mut line_numbers = Vec::new();
+                    let scan = self.session.scan(|line_number, line| {
+                        if line == content {
+                            line_numbers.push(line_number);
+                        }
+                    });
+                    if let Err(e) = scan.await {
+                        self.write_session_error("FIND", e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                    line_numbers
+                };
+                let mut response = b"OK\r\n".to_vec();
+                for line_number in line_numbers {
+                    response.extend_from_slice(format!("{line_number}\r\n").as_bytes());
+                }
+                response.extend_from_slice(b"END\r\n");
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::FilterPrefix(prefix) => {
+                info!("{} - FILTER PREFIX {}", self.conn_id, prefix.escape_ascii());
+                let max_response_size = self.config.max_response_size as usize;
+                let mut response = b"OK\r\n".to_vec();
+                let mut too_large = false;
+                let scan = self.session.scan_prefix(&prefix, |line_number, line| {
+                    if too_large {
+                        return;
+                    }
+                    response.extend_from_slice(format!("{line_number}:").as_bytes());
+                    response.extend_from_slice(line);
+                    response.push(b'\n');
+                    too_large = response.len() > max_response_size;
+                });
+                if let Err(e) = scan.await {
+                    self.write_session_error("FILTER PREFIX", e).await?;
+                    return Ok(FrameAction::Continue);
+                }
+                if too_large {
+                    debug!(
+                        "{} - FILTER PREFIX is over the {} byte response limit",
+                        self.conn_id, max_response_size
+                    );
+                    self.write(b"ERR 413\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                push_end(&mut response);
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Section(start_marker, end_marker, include_end) => {
+                let request = format!(
+                    "SECTION {} {}",
+                    start_marker.escape_ascii(),
+                    end_marker.escape_ascii()
+                );
+                info!("{} - {}", self.conn_id, request);
+                let max_response_size = self.config.max_response_size as usize;
+                let mut response = b"OK\r\n".to_vec();
+                let (mut started, mut ended, mut too_large) = (false, false, false);
+                let scan = self.session.scan(|line_number, line| {
+                    if ended || too_large {
+                        return;
+                    }
+                    if !started {
+                        started = line == start_marker;
+                    } else if line == end_marker {
+                        ended = true;
+                        if !include_end {
+                            return;
+                        }
+                    }
+                    if !started {
+                        return;
+                    }
+                    response.extend_from_slice(format!("{line_number}:").as_bytes());
+                    response.extend_from_slice(line);
+                    response.push(b'\n');
+                    too_large = response.len() > max_response_size;
+                });
+                if let Err(e) = scan.await {
+                    self.write_session_error(&request, e).await?;
+                    return Ok(FrameAction::Continue);
+                }
+                if too_large {
+                    debug!(
+                        "{} - {} is over the {} byte response limit",
+                        self.conn_id, request, max_response_size
+                    );
+                    self.write(b"ERR 413\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                if !ended {
+                    let missing = if started { "end" } else { "start" };
+                    debug!("{} - {} has no {} marker", self.conn_id, request, missing);
+                    self.write(format!("ERR 404 {missing} marker not found\r\n").as_bytes())
+                        .await?;
+                    return Ok(FrameAction::Continue);
+                }
+                push_end(&mut response);
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Search(needle) => {
+                let request = format!("SEARCH {}", needle.escape_ascii());
+                info!("{} - {}", self.conn_id, request);
+                let finder = memchr::memmem::Finder::new(&needle);
+                let (first, last) = self.session.window();
+                // a released file that can't be opened again is an error of its own rather than
+                // one in place of the END
+                if let Err(e) = self.session.open_file() {
+                    self.write_session_error(&request, e).await?;
+                    return Ok(FrameAction::Continue);
+                }
+                self.write(b"OK\r\n").await?;
+                // the matches are sent a batch of lines at a time rather than once the whole file
+                // has been read, so a slow client only holds up its own scan, and the write
+                // timeout lets go of one that stops reading
+                let mut start = first;
+                while start <= last {
+                    let end = (start + SEARCH_BATCH_LINES - 1).min(last);
+                    let mut matches = Vec::new();
+                    let scan = self.session.scan_range(start, end, |line_number, line| {
+                        if finder.find(line).is_some() {
+                            matches.extend_from_slice(format!("{line_number}:").as_bytes());
+                            matches.extend_from_slice(line);
+                            matches.push(b'\n');
+                        }
+                    });
+                    if let Err(e) = scan.await {
+                        // the OK has already gone, so the error takes the place of the END
+                        self.write_session_error(&request, e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                    if !matches.is_empty() {
+                        self.write(&matches).await?;
+                    }
+                    start = end + 1;
+                }
+                self.write(b"END\r\n").await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::SearchAny(needles) => {
+                let request = format!(
+                    "SEARCH ANY {}",
+                    needles
+                        .iter()
+                        .map(|needle| needle.escape_ascii().to_string())
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                );
+                info!("{} - {}", self.conn_id, request);
+                // every needle is looked for in the same pass over each line
+                let searcher = match aho_corasick::AhoCorasick::builder()
+                    .match_kind(aho_corasick::MatchKind::LeftmostFirst)
+                    .build(&needles)
+                {
+                    Ok(searcher) => searcher,
+                    Err(e) => {
+                        debug!(
+                            "{} - {} can't be searched for: {}",
+                            self.conn_id, request, e
+                        );
+                        self.write(b"ERR 400 too many needles\r\n").await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                let (first, last) = self.session.window();
+                if let Err(e) = self.session.open_file() {
+                    self.write_session_error(&request, e).await?;
+                    return Ok(FrameAction::Continue);
+                }
+                self.write(b"OK\r\n").await?;
+                // sent a batch at a time like SEARCH
+                let mut start = first;
+                while start <= last {
+                    let end = (start + SEARCH_BATCH_LINES - 1).min(last);
+                    let mut matches = Vec::new();
+                    let scan = self.session.scan_range(start, end, |line_number, line| {
+                        if let Some(found) = searcher.find(line) {
+                            matches.extend_from_slice(
+                                format!("{}:{}:", line_number, found.pattern().as_usize())
+                                    .as_bytes(),
+                            );
+                            matches.extend_from_slice(line);
+                            matches.push(b'\n');
+                        }
+                    });
+                    if let Err(e) = scan.await {
+                        self.write_session_error(&request, e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                    if !matches.is_empty() {
+                        self.write(&matches).await?;
+                    }
+                    start = end + 1;
+                }
+                self.write(b"END\r\n").await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::FilterColumn(column, delimiter, min, max) => {
+                let request = format!(
+                    "FILTER COLUMN {} {} {} {}",
+                    column,
+                    [delimiter].escape_ascii(),
+                    min,
+                    max
+                );
+                info!("{} - {}", self.conn_id, request);
+                let max_response_size = self.config.max_response_size as usize;
+                let mut response = b"OK\r\n".to_vec();
+                let mut too_large = false;
+                let scan = self.session.scan_column(
+                    column as usize,
+                    delimiter,
+                    min,
+                    max,
+                    |line_number, line| {
+                        if too_large {
+                            return;
+                        }
+                        response.extend_from_slice(format!("{line_number}:").as_bytes());
+                        response.extend_from_slice(line);
+                        response.push(b'\n');
+                        too_large = response.len() > max_response_size;
+                    },
+                );
+                if let Err(e) = scan.await {
+                    self.write_session_error(&request, e).await?;
+                    return Ok(FrameAction::Continue);
+                }
+                if too_large {
+                    debug!(
+                        "{} - {} is over the {} byte response limit",
+                        self.conn_id, request, max_response_size
+                    );
+                    self.write(b"ERR 413\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                push_end(&mut response);
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::MultiRange(ranges) => {
+                info!("{} - MULTI RANGE {} ranges", self.conn_id, ranges.len());
+                let ranges: Vec<(u64, u64)> = ranges
+                    .into_iter()
+                    .map(|(start, end)| (start as u64, end as u64))
+                    .collect();
+                let (mut size, mut line_count) = (0, 0);
+                for &(start, end) in &ranges {
+                    match self.session.range_size(start, end) {
+                        Ok(range_size) => {
+                            size += range_size;
+                            line_count += end - start + 1;
+                        }
+                        Err(e) => {
+                            self.write_session_error(&format!("MULTI RANGE {start} {end}"), e)
+                                .await?;
+                            return Ok(FrameAction::Continue);
+                        }
+                    }
+                }
+                if size > self.config.max_response_size || line_count > MAX_MULTI_RANGE_LINES {
+                    debug!(
+                        "{} - MULTI RANGE of {} lines and {} bytes is over the {} line and {} byte response limit",
+                        self.conn_id,
+                        line_count,
+                        size,
+                        MAX_MULTI_RANGE_LINES,
+                        self.config.max_response_size
+                    );
+                    self.write(b"ERR 413\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                // the ranges are read in the order they're laid out in the file so that the reads
+                // only move forwards through it, then sent in the order they were asked for
+                let mut order: Vec<usize> = (0..ranges.len()).collect();
+                order.sort_by_key(|&i| ranges[i].0);
+                let mut lines = vec![Vec::new(); ranges.len()];
+                for i in order {
+                    let (start, end) = ranges[i];
+                    match self.session.get_range(start, end).await {
+                        Ok(range) => lines[i] = range,
+                        Err(e) => {
+                            self.write_session_error(&format!("MULTI RANGE {start} {end}"), e)
+                                .await?;
+                            return Ok(FrameAction::Continue);
+                        }
+                    }
+                }
+                let mut response = b"OK\r\n".to_vec();
+                for (&(start, end), range) in ranges.iter().zip(lines) {
+                    response.extend_from_slice(format!("RANGE {start} {end}\r\n").as_bytes());
+                    for (line_number, line) in (start..=end).zip(range.iter()) {
+                        self.push_line(&mut response, line_number, line);
+                    }
+                    // the last line of the file may not have a delimiter to end it
+                    if !response.ends_with(b"\n") {
+                        response.push(b'\n');
+                    }
+                }
+                push_end(&mut response);
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::UniqueRuns(start, end) => {
+                info!("{} - UNIQUE RUNS {} {}", self.conn_id, start, end);
+                let request = format!("UNIQUE RUNS {start} {end}");
+                let max_response_size = self.config.max_response_size as usize;
+                let mut response = b"OK\r\n".to_vec();
+                // the line of the run being counted and how many times it has come up so far
+                let mut run: Option<(Vec<u8>, u64)> = None;
+                let mut too_large = false;
+                let scan = self
+                    .session
+                    .scan_range(start as u64, end as u64, |_, line| match &mut run {
+                        Some((previous, count)) if previous == line => *count += 1,
+                        _ => {
+                            if let Some((previous, count)) = run.replace((line.to_vec(), 1)) {
+                                push_run(&mut response, &previous, count);
+                                too_large |= response.len() > max_response_size;
+                            }
+                        }
+                    });
+                if let Err(e) = scan.await {
+                    self.write_session_error(&request, e).await?;
+                    return Ok(FrameAction::Continue);
+                }
+                if let Some((previous, count)) = run {
+                    push_run(&mut response, &previous, count);
+                    too_large |= response.len() > max_response_size;
+                }
+                if too_large {
+                    debug!(
+                        "{} - {} is over the {} byte response limit",
+                        self.conn_id, request, max_response_size
+                    );
+                    self.write(b"ERR 413\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                push_end(&mut response);
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::CountPrefix(prefix) => {
+                info!("{} - COUNT PREFIX {}", self.conn_id, prefix.escape_ascii());
+                let mut count = 0u64;
+                let scan = self.session.scan_prefix(&prefix, |_, _| count += 1);
+                if let Err(e) = scan.await {
+                    self.write_session_error("COUNT PREFIX", e).await?;
+                    return Ok(FrameAction::Continue);
+                }
+                self.write(format!("OK\r\n{count}\r\n").as_bytes()).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Echo(payload) => {
+                debug!("{} - ECHO {} bytes", self.conn_id, payload.len());
+                let mut response = b"OK\r\n".to_vec();
+                response.extend_from_slice(&payload);
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::ComputeChecksum(cmd_bytes) => {
+                info!(
+                    "{} - COMPUTE CHECKSUM {}",
+                    self.conn_id,
+                    cmd_bytes.escape_ascii()
+                );
+                if !self.config.debug_commands {
+                    debug!("{} - COMPUTE CHECKSUM needs --debug-commands", self.conn_id);
+                    self.write(b"ERR 403\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                let response = format!("OK\r\n{}\r\n", frame::checksum(&cmd_bytes));
+                self.write(response.as_bytes()).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Fail => {
+                info!("{} - FAIL", self.conn_id);
+                if !self.config.debug_commands {
+                    debug!("{} - FAIL needs --debug-commands", self.conn_id);
+                    self.write(b"ERR 403\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                anyhow::bail!("the client asked for a failure")
+            }
+            Command::Hello(algorithm, follow_reloads) => {
+                info!(
+                    "{} - HELLO {}{}",
+                    self.conn_id,
+                    algorithm.name(),
+                    if follow_reloads { " follow" } else { "" }
+                );
+                // the HELLO itself had the old checksum, and the frames after it have the new one
+                self.checksum_algorithm = algorithm;
+                self.follow_reloads = follow_reloads;
+                let response = if follow_reloads {
+                    format!("OK\r\n{} follow\r\n", algorithm.name())
+                } else {
+                    format!("OK\r\n{}\r\n", algorithm.name())
+                };
+                self.write(response.as_bytes()).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::JsonMode(json) => {
+                info!("{} - JSON MODE {}", self.conn_id, json);
+                self.json_responses = json;
+                self.write(b"OK\r\n").await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::LineNumbers(separator) => {
+                info!("{} - LINE NUMBERS {:?}", self.conn_id, separator);
+                self.line_number_separator = separator;
+                self.write(b"OK\r\n").await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::GetAll => {
+                info!("{} - GET ALL", self.conn_id);
+                // all of the lines in the window, which is every line unless there's a --line-range
+                let (first, last) = self.session.window();
+                if first > last {
+                    self.write(b"OK\r\nEND\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                let size = match self.session.range_size(first, last) {
+                    Ok(size) => size,
+                    Err(e) => {
+                        self.write_session_error("GET ALL", e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                if size > self.config.max_response_size {
+                    debug!(
+                        "{} - GET ALL of {} bytes is over the {} byte response limit",
+                        self.conn_id, size, self.config.max_response_size
+                    );
+                    self.write(b"ERR 413\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                self.write_lines_from_file("GET ALL", first, last, b"END\r\n")
+                    .await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Next | Command::Prev => {
+                let (request, result) = match frame.cmd {
+                    Command::Next => ("NEXT", self.session.next().await),
+                    _ => ("PREV", self.session.prev().await),
+                };
+                info!("{} - {}", self.conn_id, request);
+                match result {
+                    Ok(line) => {
+                        let mut response = b"OK\r\n".to_vec();
+                        self.push_line(&mut response, self.session.cursor(), &line);
+                        self.write(&response).await?;
+                    }
+                    Err(e) => self.write_session_error(request, e).await?,
+                }
+                Ok(FrameAction::Continue)
+            }
+            Command::Seek(line_number) => {
+                info!("{} - SEEK {}", self.conn_id, line_number);
+                match self.session.seek(line_number as u64) {
+                    Ok(()) => self.write(b"OK\r\n").await?,
+                    Err(e) => {
+                        self.write_session_error(&format!("SEEK {line_number}"), e)
+                            .await?
+                    }
+                }
+                Ok(FrameAction::Continue)
+            }
+            Command::LineCount => {
+                info!("{} - LINECOUNT", self.conn_id);
+                let response = format!("OK\r\n{}\r\n", self.session.line_count());
+                self.write(response.as_bytes()).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::CacheInfo => {
+                info!("{} - CACHE INFO", self.conn_id);
+                let line_numbers = self.db.cached_line_numbers();
+                let mut response = format!("OK\r\n{}\r\n", line_numbers.len()).into_bytes();
+                for line_number in line_numbers.iter().take(MAX_CACHE_INFO_LINES) {
+                    response.extend_from_slice(format!("{line_number}\r\n").as_bytes());
+                }
+                response.extend_from_slice(b"END\r\n");
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::DiskCheck => {
+                info!("{} - DISK CHECK", self.conn_id);
+                let (first, last) = self.session.window();
+                // a file with no more lines than the sample is read whole
+                let sample: Vec<u64> = if last < first + DISK_CHECK_SAMPLE_SIZE {
+                    (first..=last).collect()
+                } else {
+                    (0..DISK_CHECK_SAMPLE_SIZE)
+                        .map(|_| rand::random_range(first..=last))
+                        .collect()
+                };
+                for &line_number in &sample {
+                    if let Err(e) = self.session.get(line_number).await {
+                        error!(
+                            "{} - DISK CHECK could not read line {}: {:?}",
+                            self.conn_id, line_number, e
+                        );
+                        self.write(format!("ERR 500 line {line_number} unreadable\r\n").as_bytes())
+                            .await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                }
+                self.write(format!("OK\r\n{} lines read\r\n", sample.len()).as_bytes())
+                    .await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Random(seed) => {
+                info!("{} - RANDOM", self.conn_id);
+                let (first, last) = self.session.window();
+                if first > last {
+                    self.write_session_error("RANDOM", SessionError::LineNotFound)
+                        .await?;
+                    return Ok(FrameAction::Continue);
+                }
+                let line_number = match seed {
+                    Some(seed) => StdRng::seed_from_u64(seed as u64).random_range(first..=last),
+                    None => rand::random_range(first..=last),
+                };
+                debug!("{} - RANDOM picked line {}", self.conn_id, line_number);
+                match self.session.get(line_number).await {
+                    Ok(line) => {
+                        let mut response = b"OK\r\n".to_vec();
+                        self.push_line(&mut response, line_number, &line);
+                        self.write(&response).await?;
+                    }
+                    Err(e) => {
+                        self.write_session_error(&format!("RANDOM {line_number}"), e)
+                            .await?;
+                    }
+                }
+                Ok(FrameAction::Continue)
+            }
+            Command::GetByteRange(start, end) => {
+                info!("{} - GET BYTE RANGE {} {}", self.conn_id, start, end);
+                let request = format!("GET BYTE RANGE {start} {end}");
+                let mut response = b"OK\r\n".to_vec();
+                let lines_within = match self.session.lines_within(start, end) {
+                    Ok(lines_within) => lines_within,
+                    Err(e) => {
+                        self.write_session_error(&request, e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                if let Some((first, last)) = lines_within {
+                    let size = match self.session.range_size(first, last) {
+                        Ok(size) => size,
+                        Err(e) => {
+                            self.write_session_error(&request, e).await?;
+                            return Ok(FrameAction::Continue);
+                        }
+                    };
+                    if size > self.config.max_response_size {
+                        debug!(
+                            "{} - {} of {} bytes is over the {} byte response limit",
+                            self.conn_id, request, size, self.config.max_response_size
+                        );
+                        self.write(b"ERR 413\r\n").await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                    match self.session.get_range(first, last).await {
+                        Ok(lines) => {
+                            for (line_number, line) in (first..=last).zip(lines) {
+                                response.extend_from_slice(format!("{line_number}:").as_bytes());
+                                response.extend_from_slice(line.as_bytes());
+                            }
+                        }
+                        Err(e) => {
+                            self.write_session_error(&request, e).await?;
+                            return Ok(FrameAction::Continue);
+                        }
+                    }
+                }
+                push_end(&mut response);
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::LineAtByte(offset) => {
+                info!("{} - LINE AT BYTE {}", self.conn_id, offset);
+                let request = format!("LINE AT BYTE {offset}");
+                let line = match self.session.line_at_byte(offset) {
+                    Ok(line_number) => self
+                        .session
+                        .get(line_number)
+                        .await
+                        .map(|line| (line_number, line)),
+                    Err(e) => Err(e),
+                };
+                match line {
+                    Ok((line_number, line)) => {
+                        let mut response = format!("OK\r\n{line_number}:").into_bytes();
+                        response.extend_from_slice(line.as_bytes());
+                        self.write(&response).await?;
+                    }
+                    Err(e) => self.write_session_error(&request, e).await?,
+                }
+                Ok(FrameAction::Continue)
+            }
+            Command::GetIndex => {
+                info!("{} - GET INDEX", self.conn_id);
+                let index = match self.db.serialized_index() {
+                    Ok(index) => index,
+                    Err(e) => {
+                        error!("{} - GET INDEX failed: {:#}", self.conn_id, e);
+                        self.write(b"ERR 500\r\n").await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                if index.len() as u64 > self.config.max_response_size {
+                    debug!(
+                        "{} - GET INDEX of {} bytes is over the {} byte response limit",
+                        self.conn_id,
+                        index.len(),
+                        self.config.max_response_size
+                    );
+                    self.write(b"ERR 413\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                // the index is binary so it's sent with its length rather than ended with END
+                let mut response = format!("OK\r\n{}\r\n", index.len()).into_bytes();
+                response.extend_from_slice(&index);
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Extreme(longest) => {
+                let request = if longest {
+                    "EXTREME longest"
+                } else {
+                    "EXTREME shortest"
+                };
+                info!("{} - {}", self.conn_id, request);
+                let line = match self.session.extreme_line(longest).await {
+                    Ok(Some(line_number)) => self
+                        .session
+                        .get(line_number)
+                        .await
+                        .map(|line| (line_number, line)),
+                    Ok(None) => Err(SessionError::LineNotFound),
+                    Err(e) => Err(e),
+                };
+                match line {
+                    Ok((line_number, line)) => {
+                        let mut response = format!("OK\r\n{line_number}:").into_bytes();
+                        response.extend_from_slice(line.as_bytes());
+                        self.write(&response).await?;
+                    }
+                    Err(e) => self.write_session_error(request, e).await?,
+                }
+                Ok(FrameAction::Continue)
+            }
+            Command::LengthHistogram => {
+                info!("{} - LENGTH HISTOGRAM", self.conn_id);
+                // bucket 0 is the empty lines and bucket k is the lengths 2^(k-1)..2^k - 1
+                let mut buckets = [0u64; 65];
+                let scan = self.session.scan(|_, line| {
+                    buckets[(u64::BITS - (line.len() as u64).leading_zeros()) as usize] += 1;
+                });
+                if let Err(e) = scan.await {
+                    self.write_session_error("LENGTH HISTOGRAM", e).await?;
+                    return Ok(FrameAction::Continue);
+                }
+                let mut response = b"OK\r\n".to_vec();
+                for (bucket, count) in buckets.iter().enumerate() {
+                    if *count == 0 {
+                        continue;
+                    }
+                    let row = match bucket {
+                        0 => format!("0 {count}\r\n"),
+                        _ => format!(
+                            "{}-{} {}\r\n",
+                            1u128 << (bucket - 1),
+                            (1u128 << bucket) - 1,
+                            count
+                        ),
+                    };
+                    response.extend_from_slice(row.as_bytes());
+                }
+                response.extend_from_slice(b"END\r\n");
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::GetRangeCached(start, end) => {
+                let request = format!("GET RANGE CACHED {start} {end}");
+                info!("{} - {}", self.conn_id, request);
+                let (start, end) = (start as u64, end as u64);
+                let size = match self.session.range_size(start, end) {
+                    Ok(size) => size,
+                    Err(e) => {
+                        self.write_session_error(&request, e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                if size > self.config.max_response_size {
+                    debug!(
+                        "{} - {} of {} bytes is over the {} byte response limit",
+                        self.conn_id, request, size, self.config.max_response_size
+                    );
+                    self.write(b"ERR 413\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                // each line goes through the cache like a GET, so the misses are cached for the
+                // next request
+                let mut response = b"OK\r\n".to_vec();
+                for line_number in start..=end {
+                    let read = read_line_noting_hit(
+                        &self.db,
+                        self.read_limiter.as_deref(),
+                        &mut self.session,
+                        line_number,
+                    )
+                    .await;
+                    let (line, hit) = match read {
+                        Ok(read) => read,
+                        Err(e) => {
+                            self.write_session_error(&request, e).await?;
+                            return Ok(FrameAction::Continue);
+                        }
+                    };
+                    let source = if hit { "hit" } else { "miss" };
+                    response.extend_from_slice(format!("{line_number}:{source}:").as_bytes());
+                    response.extend_from_slice(line.as_bytes());
+                    if !response.ends_with(b"\n") {
+                        response.push(b'\n');
+                    }
+                }
+                push_end(&mut response);
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Outline(center, radius) => {
+                let request = format!("OUTLINE {center} {radius}");
+                debug!("{} - {}", self.conn_id, request);
+                let center = center as u64;
+                if let Err(e) = self.session.range_size(center, center) {
+                    self.write_session_error(&request, e).await?;
+                    return Ok(FrameAction::Continue);
+                }
+                let (first, last) = self.session.window();
+                let start = center.saturating_sub(radius as u64).max(first);
+                let end = (center + radius as u64).min(last);
+                let mut response = b"OK\r\n".to_vec();
+                for line_number in start..=end {
+                    let offset = match self.session.line_span(line_number) {
+                        Ok((offset, _)) => offset,
+                        Err(e) => {
+                            self.write_session_error(&request, e).await?;
+                            return Ok(FrameAction::Continue);
+                        }
+                    };
+                    response.extend_from_slice(format!("{line_number} {offset}\r\n").as_bytes());
+                    if response.len() as u64 > self.config.max_response_size {
+                        debug!(
+                            "{} - {} is over the {} byte response limit",
+                            self.conn_id, request, self.config.max_response_size
+                        );
+                        self.write(b"ERR 413\r\n").await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                }
+                response.extend_from_slice(b"END\r\n");
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Exists(line_number) => {
+                debug!("{} - EXISTS {}", self.conn_id, line_number);
+                if self.session.exists(line_number as u64) {
+                    self.write(b"OK true\r\n").await?;
+                } else {
+                    self.write(b"OK false\r\n").await?;
+                }
+                Ok(FrameAction::Continue)
+            }
+            Command::ChunkPlan(chunks) => {
+                info!("{} - CHUNK PLAN {}", self.conn_id, chunks);
+                if chunks == 0 {
+                    self.write(b"ERR 400 the number of chunks must be at least 1\r\n")
+                        .await?;
+                    return Ok(FrameAction::Continue);
+                }
+                let mut response = b"OK\r\n".to_vec();
+                for (start, end) in self.session.chunk_plan(chunks as u64) {
+                    response.extend_from_slice(format!("{start} {end}\r\n").as_bytes());
+                    // there can be as many ranges as lines, so it's checked as it grows
+                    if response.len() as u64 > self.config.max_response_size {
+                        debug!(
+                            "{} - CHUNK PLAN {} is over the {} byte response limit",
+                            self.conn_id, chunks, self.config.max_response_size
+                        );
+                        self.write(b"ERR 413\r\n").await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                }
+                response.extend_from_slice(b"END\r\n");
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::LengthStats => {
+                info!("{} - LENGTH STATS", self.conn_id);
+                let stats = match self.session.length_stats().await {
+                    Ok(Some(stats)) => stats,
+                    Ok(None) => {
+                        self.write_session_error("LENGTH STATS", SessionError::LineNotFound)
+                            .await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                    Err(e) => {
+                        self.write_session_error("LENGTH STATS", e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                let response = format!(
+                    "OK\r\nmin {}\r\nmax {}\r\nmean {:.2}\r\nmedian {}\r\np90 {}\r\np99 {}\r\nEND\r\n",
+                    stats.min, stats.max, stats.mean, stats.median, stats.p90, stats.p99
+                );
+                self.write(response.as_bytes()).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::FirstByteHistogram => {
+                info!("{} - FIRST BYTE HISTOGRAM", self.conn_id);
+                let db = self.db.clone();
+                let counts = match db.first_byte_counts(&mut self.session).await {
+                    Ok(counts) => counts,
+                    Err(e) => {
+                        self.write_session_error("FIRST BYTE HISTOGRAM", e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                let mut response = b"OK\r\n".to_vec();
+                if counts.empty > 0 {
+                    response.extend_from_slice(format!("empty {}\r\n", counts.empty).as_bytes());
+                }
+                for (byte, count) in counts.bytes.iter().enumerate() {
+                    if *count > 0 {
+                        response.extend_from_slice(format!("{byte:#04x} {count}\r\n").as_bytes());
+                    }
+                }
+                response.extend_from_slice(b"END\r\n");
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Permissions => {
+                info!("{} - PERMISSIONS", self.conn_id);
+                // the debug commands are the only ones that can be turned off
+                let mut response = b"OK\r\n".to_vec();
+                for &(cmd, name) in frame::COMMANDS {
+                    if Command::is_debug_only(cmd) && !self.config.debug_commands {
+                        continue;
+                    }
+                    response.extend_from_slice(format!("{} {}\r\n", cmd as char, name).as_bytes());
+                }
+                response.extend_from_slice(b"END\r\n");
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Health => {
+                info!("{} - HEALTH", self.conn_id);
+                self.write(b"OK\r\nready\r\n").await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Capabilities => {
+                info!("{} - CAPABILITIES", self.conn_id);
+                // lines are sent exactly as they are in the file, delimiter and any \r included
+                let line_range = match self.config.line_range {
+                    Some((start, end)) => format!("{start}:{end}"),
+                    None => "all".to_string(),
+                };
+                let response = format!(
+                    "OK\r\ndelimiter {:#04x}\r\ntrims_crlf false\r\nlength_prefixed false\r\nprotocol_version {}\r\nline_range {}\r\nEND\r\n",
+                    db::RECORD_DELIMITER,
+                    frame::PROTOCOL_VERSION,
+                    line_range
+                );
+                self.write(response.as_bytes()).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Bounds => {
+                info!("{} - BOUNDS", self.conn_id);
+                // the first and last lines that are served, which are the same in a one line file
+                let (first, last) = self.session.window();
+                let lines = match self.get(first).await {
+                    Ok(first_line) => self
+                        .get(last)
+                        .await
+                        .map(|last_line| [(first, first_line), (last, last_line)]),
+                    Err(e) => Err(e),
+                };
+                match lines {
+                    Ok(lines) => {
+                        let mut response = b"OK\r\n".to_vec();
+                        for (line_number, line) in lines {
+                            self.push_line(&mut response, line_number, &line);
+                            if !response.ends_with(b"\n") {
+                                response.push(b'\n');
+                            }
+                        }
+                        push_end(&mut response);
+                        self.write(&response).await?;
+                    }
+                    Err(e) => self.write_session_error("BOUNDS", e).await?,
+                }
+                Ok(FrameAction::Continue)
+            }
+            Command::Stats => {
+                info!("{} - STATS", self.conn_id);
+                let mut connections: Vec<_> = self
+                    .connection_table
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(conn_id, stats)| (conn_id.clone(), stats.clone()))
+                    .collect();
+                // oldest first, with the id to break ties between connections made together
+                connections.sort_by(|(a_id, a), (b_id, b)| {
+                    (a.connected_since, a_id).cmp(&(b.connected_since, b_id))
+                });
+                let mut response = b"OK\r\n".to_vec();
+                for (conn_id, stats) in connections {
+                    response.extend_from_slice(
+                        format!(
+                            "{} {} {} {}\r\n",
+                            conn_id,
+                            stats.addr,
+                            stats.requests.load(Ordering::Relaxed),
+                            stats
+                                .connected_since
+                                .to_rfc3339_opts(chrono::SecondsFormat::Secs, false)
+                        )
+                        .as_bytes(),
+                    );
+                }
+                response.extend_from_slice(b"END\r\n");
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Uptime => {
+                info!("{} - UPTIME", self.conn_id);
+                let response = format!(
+                    "OK\r\n{} {}\r\n",
+                    self.server_started
+                        .at
+                        .to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+                    self.server_started.instant.elapsed().as_millis()
+                );
+                self.write(response.as_bytes()).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::FileInfo => {
+                info!("{} - FILEINFO", self.conn_id);
+                let file_info = self.db.file_info();
+                let response = format!("OK\r\n{} {}\r\n", file_info.size, file_info.modified);
+                self.write(response.as_bytes()).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::FileChecksum => {
+                info!("{} - FILE CHECKSUM", self.conn_id);
+                let response = match self.db.file_checksum() {
+                    Ok(checksum) => format!("OK\r\n{checksum}\r\n"),
+                    Err(e) => {
+                        error!("{} - FILE CHECKSUM failed: {:#}", self.conn_id, e);
+                        "ERR 500\r\n".to_string()
+                    }
+                };
+                self.write(response.as_bytes()).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::WordCount => {
+                info!("{} - WORD COUNT", self.conn_id);
+                // the lines and bytes are those of the window, like the words
+                let (first, last) = self.session.window();
+                let db = self.db.clone();
+                let counted = match db.word_count(&mut self.session).await {
+                    Ok(words) if first > last => Ok((0, words, 0)),
+                    Ok(words) => self
+                        .session
+                        .range_size(first, last)
+                        .map(|bytes| (last - first + 1, words, bytes)),
+                    Err(e) => Err(e),
+                };
+                let (lines, words, bytes) = match counted {
+                    Ok(counted) => counted,
+                    Err(e) => {
+                        self.write_session_error("WORD COUNT", e).await?;
+                        return Ok(FrameAction::Continue);
+                    }
+                };
+                let response = format!("OK\r\n{lines} {words} {bytes}\r\n");
+                self.write(response.as_bytes()).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::RegexSearch(pattern) => {
+                info!("{} - REGEX {}", self.conn_id, pattern);
+                let Some(re) = self.compile_regex("REGEX", &pattern).await? else {
+                    return Ok(FrameAction::Continue);
+                };
+                let mut response = b"OK\r\n".to_vec();
+                let scan = self.session.scan(|line_number, line| {
+                    if re.is_match(line) {
+                        response.extend_from_slice(format!("{line_number}\r\n").as_bytes());
+                    }
+                });
+                match scan.await {
+                    Ok(()) => {
+                        response.extend_from_slice(b"END\r\n");
+                        self.write(&response).await?;
+                    }
+                    Err(e) => {
+                        self.write_session_error(&format!("REGEX {pattern}"), e)
+                            .await?;
+                    }
+                }
+                Ok(FrameAction::Continue)
+            }
+            Command::Extract(pattern) => {
+                info!("{} - EXTRACT {}", self.conn_id, pattern);
+                let Some(re) = self.compile_regex("EXTRACT", &pattern).await? else {
+                    return Ok(FrameAction::Continue);
+                };
+                if re.captures_len() < 2 {
+                    debug!(
+                        "{} - EXTRACT {} has no capture group",
+                        self.conn_id, pattern
+                    );
+                    self.write(b"ERR 400 the pattern has no capture group\r\n")
+                        .await?;
+                    return Ok(FrameAction::Continue);
+                }
+                let max_response_size = self.config.max_response_size as usize;
+                let mut response = b"OK\r\n".to_vec();
+                let (mut results, mut too_large) = (0, false);
+                let scan = self.session.scan(|line_number, line| {
+                    if too_large {
+                        return;
+                    }
+                    // a line whose match leaves the group out has nothing to extract
+                    let Some(capture) = re.captures(line).and_then(|captures| captures.get(1))
+                    else {
+                        return;
+                    };
+                    response.extend_from_slice(format!("{line_number}:").as_bytes());
+                    response.extend_from_slice(capture.as_bytes());
+                    response.push(b'\n');
+                    results += 1;
+                    too_large = results > MAX_EXTRACT_RESULTS || response.len() > max_response_size;
+                });
+                if let Err(e) = scan.await {
+                    self.write_session_error(&format!("EXTRACT {pattern}"), e)
+                        .await?;
+                    return Ok(FrameAction::Continue);
+                }
+                if too_large {
+                    debug!(
+                        "{} - EXTRACT {} is over the limit of {} captures or {} bytes",
+                        self.conn_id, pattern, MAX_EXTRACT_RESULTS, max_response_size
+                    );
+                    self.write(b"ERR 413\r\n").await?;
+                    return Ok(FrameAction::Continue);
+                }
+                push_end(&mut response);
+                self.write(&response).await?;
+                Ok(FrameAction::Continue)
+            }
+            Command::Quit => {
+                info!("{} - QUIT", self.conn_id);
+                let _ = self.reader.get_mut().shutdown().await;
+                Ok(FrameAction::EndConnection)
+            }
+            Command::Shutdown => {
+                info!("{} - SHUTDOWN", self.conn_id);
+                if self
+                    .cmd_tx
+                    .send(ShutdownKind::Immediate(ShutdownReason::Operator))
+                    .await
+                    .is_err()
+                {
+                    error!("Failed to send shutdown signal to server. Forcing shutdown.");
+                    std::process::exit(1);
+                }
+                Ok(FrameAction::EndConnection)
+            }
+            Command::Drain => {
+                info!("{} - DRAIN", self.conn_id);
+                if self.cmd_tx.send(ShutdownKind::Drain).await.is_err() {
+                    error!("Failed to send drain signal to server. Forcing shutdown.");
+                    std::process::exit(1);
+                }
+                Ok(FrameAction::EndConnection)
+            }
+        }
+    }
+
+    /// Reads the next frame from the client, which is empty once the client has gone.
+    async fn read_frame(&mut self) -> Result<Vec<u8>> {
+        let max_args_len = self.max_args_len();
+        let started = std::mem::take(&mut self.partial_frame);
+        read_frame(
+            &mut self.reader,
+            started,
+            self.checksum_algorithm,
+            max_args_len,
+        )
+        .await
+    }
+
+    /// Whether the client has already sent what looks like another whole GET, which can be read
+    /// without waiting on the client.
+    fn get_is_buffered(&self) -> bool {
+        let buffered = self.reader.buffer();
+        buffered.first() == Some(&b'0') && buffered.contains(&0xA)
+    }
+
+    // pair each request with its response in the trace by its sequence number on the connection
+    fn trace_request(&mut self, buf: &[u8]) -> Option<RequestTrace> {
+        if !self.config.trace_requests {
+            return None;
+        }
+        self.request_seq += 1;
+        trace!(
+            "{} #{} request {} bytes: {}",
+            self.conn_id,
+            self.request_seq,
+            buf.len(),
+            buf.escape_ascii()
+        );
+        Some(RequestTrace {
+            seq: self.request_seq,
+            started: tokio::time::Instant::now(),
+        })
+    }
+
+    /// Traces the response to `request`, which is everything written since `bytes_written`.
+    fn trace_response(&self, request: Option<RequestTrace>, bytes_written: u64) {
+        if let Some(request) = request {
+            trace!(
+                "{} #{} response {} bytes in {:?}",
+                self.conn_id,
+                request.seq,
+                self.bytes_written - bytes_written,
+                request.started.elapsed()
+            );
+        }
+    }
+
+    /// Writes `request` to the access log along with its response, which is everything written
+    /// since `bytes_written`.
+    fn log_access(&mut self, request: Option<String>, bytes_written: u64) {
+        let status = self.response_status.take();
+        let Some(request) = request else {
+            return;
+        };
+        let bytes_sent = self.bytes_written - bytes_written;
+        info!(
+            target: ACCESS_LOG_TARGET,
+            "{} - - [{}] \"{}\" {} {}",
+            self.addr.ip(),
+            chrono::Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+            request,
+            status.map_or("-".to_string(), |status| status.to_string()),
+            // no bytes is written as - like in other servers' access logs
+            if bytes_sent == 0 {
+                "-".to_string()
+            } else {
+                bytes_sent.to_string()
+            }
+        );
+    }
+
+    /// Looks up a batch of pipelined GETs at the same time, each on a session of its own, and
+    /// responds to them in the order they were sent.
+    async fn handle_gets(&mut self, batch: Vec<(Vec<u8>, u32)>) -> Result<()> {
+        let mut lookups = Vec::with_capacity(batch.len());
+        for (buf, line_number) in batch {
+            debug!("{} - GET {}", self.conn_id, line_number);
+            self.stats.requests.fetch_add(1, Ordering::Relaxed);
+            let request = self.trace_request(&buf);
+            let mut session = match self.pipeline_sessions.pop() {
+                Some(session) => session,
+                None => self.db.get_session().await?,
+            };
+            let (db, read_limiter) = (self.db.clone(), self.read_limiter.clone());
+            let lookup = tokio::spawn(async move {
+                let line = read_line(
+                    &db,
+                    read_limiter.as_deref(),
+                    &mut session,
+                    line_number as u64,
+                )
+                .await;
+                (session, line)
+            });
+            lookups.push((line_number, request, lookup));
+        }
+        for (line_number, request, lookup) in lookups {
+            let (session, line) = lookup.await?;
+            self.pipeline_sessions.push(session);
+            let bytes_written = self.bytes_written;
+            self.write_get(line_number, line).await?;
+            self.trace_response(request, bytes_written);
+            let access = self
+                .config
+                .access_log
+                .is_some()
+                .then(|| format!("GET {line_number}"));
+            self.log_access(access, bytes_written);
+        }
+        Ok(())
+    }
+
+    /// Tells the client that the server is shutting down and why, along with how many seconds it
+    /// should wait before reconnecting when --shutdown-retry-after is set.
+    async fn write_shutdown(&mut self, reason: ShutdownReason) -> Result<()> {
+        let message = match self.config.shutdown_retry_after {
+            Some(seconds) => format!("SHUTDOWN {reason} retry-after={seconds}\r\n"),
+            None => format!("SHUTDOWN {reason}\r\n"),
+        };
+        self.write(message.as_bytes()).await
+    }
+
+    /// The longest length prefixed args the client may send.
+    fn max_args_len(&self) -> usize {
+        self.config.max_args_len as usize
+    }
+
+    /// When the client will have been idle long enough to be warned, or to be disconnected once it
+    /// has been, which is never without --idle-warning-ms.
+    fn idle_deadline(&self) -> Option<tokio::time::Instant> {
+        let idle_ms = if self.idle_warned {
+            self.config.idle_close_ms
+        } else {
+            self.config.idle_warning_ms?
+        };
+        Some(self.last_request_at + tokio::time::Duration::from_millis(idle_ms))
+    }
+
+    /// When the sessions will have held the data file unused for long enough to release it, which
+    /// is never without --release-file-idle-ms or once it's been released.
+    fn release_file_deadline(&self) -> Option<tokio::time::Instant> {
+        let idle_ms = self.config.release_file_idle_ms?;
+        let holds_file = self.session.holds_file()
+            || self
+                .pipeline_sessions
+                .iter()
+                .any(|session| session.holds_file());
+        holds_file.then(|| self.file_used_at + tokio::time::Duration::from_millis(idle_ms))
+    }
+
+    /// Moves a connection that follows reloads on to the server's latest database before its next
+    /// request, with a session of its own on it.  The pipeline sessions are of the old database's
+    /// index so they're let go.  A connection whose new session can't be opened keeps the old one.
+    async fn follow_reload(&mut self) {
+        if !self.follow_reloads || !self.databases.has_changed().unwrap_or(false) {
+            return;
+        }
+        let db = self.databases.borrow_and_update().clone();
+        match db.get_session().await {
+            Ok(session) => {
+                info!("{} - Following the reload of the data file.", self.conn_id);
+                self.session = session;
+                self.db = db;
+                self.pipeline_sessions.clear();
+            }
+            Err(e) => warn!(
+                "{} - Could not follow the reload, keeping the file from before: {:#}",
+                self.conn_id, e
+            ),
+        }
+    }
+
+    /// Closes every handle the connection's sessions have to the data file.
+    fn release_file(&mut self) {
+        let mut released = self.session.release_file() as u64;
+        for session in &mut self.pipeline_sessions {
+            released += session.release_file() as u64;
+        }
+        debug!(
+            "{} - Released {} handles to the data file after it was unused for {}ms.",
+            self.conn_id,
+            released,
+            self.config.release_file_idle_ms.unwrap_or_default()
+        );
+    }
+
+    /// Tells the client about an error that the request handling didn't expect, unless it was the
+    /// write to the client that failed.  The connection keeps going if the client could be told.
+    async fn handle_internal_error(&mut self, request: &str, e: anyhow::Error) -> FrameAction {
+        if self.write_failed {
+            warn!("{} - Error writing to client: {:#}", self.conn_id, e);
+            return FrameAction::EndConnection;
+        }
+        error!(
+            "{} - {} failed unexpectedly: {:#}",
+            self.conn_id, request, e
+        );
+        match self.write(b"ERR 500\r\n").await {
+            Ok(()) => FrameAction::Continue,
+            Err(e) => {
+                warn!("{} - Error writing to client: {:#}", self.conn_id, e);
+                FrameAction::EndConnection
+            }
+        }
+    }
+
+    pub async fn run(&mut self) -> Result<()> {
+        // a frame that was read while filling a batch of GETs but isn't one itself
+        let mut pending = None;
+        loop {
+            // get next message on stream
+            let buf = match pending.take() {
+                Some(buf) => buf,
+                None => {
+                    // wait out an idle client without taking any of what it sends, since a
+                    // warning leaves the connection open and giving up on a read part way through
+                    // a frame would lose the part of it that had been taken
+                    let idle_deadline = self.idle_deadline();
+                    let release_deadline = self.release_file_deadline();
+                    if (idle_deadline.is_some() || release_deadline.is_some())
+                        && self.reader.buffer().is_empty()
+                        && self.partial_frame.is_empty()
+                    {
+                        let now = tokio::time::Instant::now();
+                        tokio::select! {
+                            ready = self.reader.fill_buf() => {
+                                ready?;
+                            }
+                            reason = immediate_shutdown(&mut self.shutdown_rx) => {
+                                self.write_shutdown(reason)
+                                    .await?;
+                                break;
+                            }
+                            _ = tokio::time::sleep_until(release_deadline.unwrap_or(now)),
+                                if release_deadline.is_some() =>
+                            {
+                                self.release_file();
+                                continue;
+                            }
+                            _ = tokio::time::sleep_until(idle_deadline.unwrap_or(now)),
+                                if idle_deadline.is_some() =>
+                            {
+                                if self.idle_warned {
+                                    info!(
+                                        "{} - Closing the connection after it was idle for {}ms more.",
+                                        self.conn_id, self.config.idle_close_ms
+                                    );
+                                    let _ = self.reader.get_mut().shutdown().await;
+                                    break;
+                                }
+                                debug!("{} - Warning the client that it's idle.", self.conn_id);
+                                self.write(b"IDLE\r\n").await?;
+                                self.idle_warned = true;
+                                self.last_request_at = tokio::time::Instant::now();
+                                continue;
+                            }
+                        }
+                    }
+                    // responses are only written outside of this wait, so a shutdown that comes in
+                    // while one is being sent is only passed on once the client has all of it
+                    let max_args_len = self.max_args_len();
+                    let started = std::mem::take(&mut self.partial_frame);
+                    tokio::select! {
+                        read = read_frame(&mut self.reader, started, self.checksum_algorithm, max_args_len) => read?,
+                        reason = immediate_shutdown(&mut self.shutdown_rx) => {
+                            self.write_shutdown(reason)
+                                .await?;
+                            break;
+                        }
+                    }
+                }
+            };
+            self.last_request_at = tokio::time::Instant::now();
+            self.idle_warned = false;
+            self.file_used_at = self.last_request_at;
+            self.record_activity();
 
             // if we received a shutdown signal, then shutdown the client and break the loop, which shuts down the connection
-            if self.shutdown_rx.try_recv().is_ok() {
-                self.reader.get_mut().write_all(b"SHUTDOWN\r\n").await?;
+            // a drain lets the client keep going until it quits on its own
+            if let Ok(ShutdownKind::Immediate(reason)) = self.shutdown_rx.try_recv() {
+                self.write_shutdown(reason).await?;
                 break;
             }
 
-            match self.handle_frame(buf).await {
-                Ok(FrameAction::EndConnection) => break,
-                Ok(FrameAction::Continue) => continue,
-                Err(_) => {
-                    println!("got an error with a connection frame");
-                    break;
+            self.follow_reload().await;
+
+            // GETs don't depend on each other, so the ones the client has sent ahead are looked up
+            // together rather than one at a time
+            if let Some(line_number) =
+                pipelined_get(&buf, self.checksum_algorithm, self.max_args_len())
+            {
+                if self.config.pipeline_window > 1 && self.get_is_buffered() {
+                    let mut batch = vec![(buf, line_number)];
+                    while (batch.len() as u64) < self.config.pipeline_window
+                        && self.get_is_buffered()
+                    {
+                        let buf = self.read_frame().await?;
+                        match pipelined_get(&buf, self.checksum_algorithm, self.max_args_len()) {
+                            Some(line_number) => batch.push((buf, line_number)),
+                            None => {
+                                pending = Some(buf);
+                                break;
+                            }
+                        }
+                    }
+                    if let Err(e) = self.handle_gets(batch).await {
+                        let action = self.handle_internal_error("pipelined GETs", e).await;
+                        if let FrameAction::EndConnection = action {
+                            break;
+                        }
+                    }
+                    continue;
                 }
             }
+
+            let request = self.trace_request(&buf);
+            // an empty frame is the client going away rather than a request
+            let access = (self.config.access_log.is_some() && !buf.is_empty())
+                .then(|| describe_request(&buf, self.checksum_algorithm, self.max_args_len()));
+            if !buf.is_empty() {
+                self.stats.requests.fetch_add(1, Ordering::Relaxed);
+            }
+            let bytes_written = self.bytes_written;
+            let action = match self.handle_frame(buf).await {
+                Ok(action) => action,
+                Err(e) => self.handle_internal_error("Request", e).await,
+            };
+            self.trace_response(request, bytes_written);
+            self.log_access(access, bytes_written);
+
+            match action {
+                FrameAction::EndConnection => break,
+                FrameAction::Continue => continue,
+            }
         }
         info!("Server disconnects from {}", self.conn_id);
         Ok(())
     }
 }
 
-#[tokio::main]
-async fn main() {
-    // get the db filename from the command line arg
-    let args: Vec<String> = std::env::args().collect();
-    let db_fn = &args[1];
-    setup_logger().expect("could not set up logger");
-    let mut server = Server::new(db_fn)
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.connection_table.lock().unwrap().remove(&self.conn_id);
+    }
+}
+
+fn main() {
+    let cli = config::Cli::parse();
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    // without --workers tokio runs a worker per core
+    if let Some(workers) = cli.server.as_ref().and_then(|config| config.workers) {
+        runtime.worker_threads(workers as usize);
+    }
+    let runtime = runtime
+        .enable_all()
+        .build()
+        .expect("could not start the tokio runtime");
+    runtime.block_on(run(cli));
+}
+
+async fn run(cli: config::Cli) {
+    let config = match (cli.command, cli.server) {
+        (Some(CliCommand::Client(client_config)), _) => {
+            if let Err(e) = client::run(client_config).await {
+                eprintln!("Error: {e:#}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        (Some(CliCommand::Selftest(selftest_config)), _) => {
+            if let Err(e) = selftest::run(selftest_config).await {
+                eprintln!("Error: {e:#}");
+                std::process::exit(1);
+            }
+            return;
+        }
+        (None, Some(config)) => config,
+        // the server's args are required whenever there's no subcommand
+        (None, None) => unreachable!(),
+    };
+    let log_level = if config.trace_requests {
+        log::LevelFilter::Trace
+    } else {
+        config.log_level
+    };
+    setup_logger(log_level, config.access_log.as_deref()).expect("could not set up logger");
+    let addr = SocketAddr::new(config.bind_address, config.port);
+    // init the TCP listener before the index so that orchestrators can tell a server that's still
+    // building it from one that isn't up
+    let listener = match bind(addr, config.dual_stack) {
+        Ok(listener) => Arc::new(listener),
+        Err(e) => {
+            error!("{}", describe_bind_error(addr, &e));
+            log::logger().flush();
+            std::process::exit(1);
+        }
+    };
+    info!("Listening on {}", addr);
+    let (ready_tx, ready_rx) = watch::channel(false);
+    let early_lines = config.serve_while_building.then(|| {
+        Arc::new(EarlyLines {
+            progress: IndexProgress::default(),
+            db_file: config.db_file.clone(),
+            line_range: config.line_range,
+        })
+    });
+    let write_timeout = tokio::time::Duration::from_millis(config.write_timeout_ms);
+    let building = tokio::spawn(answer_while_building(
+        listener.clone(),
+        ready_rx,
+        early_lines.clone(),
+        write_timeout,
+        config.max_args_len as usize,
+    ));
+    let mut server = Server::new(config, early_lines.as_ref().map(|early| &early.progress))
         .await
         .expect("Error creating server... exiting.");
-    server.run().await;
+    info!("The index is built, ready to serve.");
+    let _ = ready_tx.send(true);
+    let waiting = building.await.unwrap_or_default();
+    let listener = Arc::into_inner(listener).expect("the listener is only used by the server now");
+    server.run(listener, waiting).await;
+}
+
+/// The lines that are indexed while the rest of the index is being built, which GETs are answered
+/// from with --serve-while-building.
+struct EarlyLines {
+    progress: IndexProgress,
+    db_file: String,
+    line_range: Option<(u64, u64)>,
+}
+
+impl EarlyLines {
+    /// Responds to a GET of `line_number` like the server would once it's ready, or with `BUILDING`
+    /// when the line hasn't been indexed yet.
+    fn get(&self, line_number: u64) -> Vec<u8> {
+        if line_number == 0 {
+            return b"ERR 404\r\n".to_vec();
+        }
+        if let Some((start, end)) = self.line_range {
+            if line_number < start || line_number > end {
+                return b"ERR 421\r\n".to_vec();
+            }
+        }
+        let Some((offset, end)) = self.progress.span(line_number) else {
+            return b"BUILDING\r\n".to_vec();
+        };
+        use std::io::{Read, Seek};
+        let read = std::fs::File::open(&self.db_file).and_then(|mut file| {
+            file.seek(std::io::SeekFrom::Start(offset))?;
+            let mut line: Vec<u8> = vec![0; (end - offset) as usize];
+            file.read_exact(&mut line)?;
+            Ok(line)
+        });
+        match read {
+            // lines are sent as text like any other GET
+            Ok(line) if std::str::from_utf8(&line).is_ok() => {
+                let mut response = b"OK\r\n".to_vec();
+                response.extend_from_slice(&line);
+                response
+            }
+            Ok(_) => {
+                error!("GET {} while building is not valid UTF-8", line_number);
+                b"ERR 500\r\n".to_vec()
+            }
+            Err(e) => {
+                error!(
+                    "GET {} while building failed to read it: {:?}",
+                    line_number, e
+                );
+                b"ERR 500\r\n".to_vec()
+            }
+        }
+    }
+}
+
+/// A connection made while the index was being built, with the start of the frame the client was
+/// part way through sending when it became ready.
+type WaitingConnection = (BufReader<TcpStream>, Vec<u8>, SocketAddr);
+
+/// Answers the connections made while the index is being built: HEALTH responds with `building`
+/// and every other request with `ERR 503`.  Once the index is ready, the connections that are still
+/// open are returned to be handed to the server, which doesn't wait on any client to do so.
+async fn answer_while_building(
+    listener: Arc<TcpListener>,
+    mut ready: watch::Receiver<bool>,
+    early_lines: Option<Arc<EarlyLines>>,
+    write_timeout: tokio::time::Duration,
+    max_args_len: usize,
+) -> Vec<WaitingConnection> {
+    let mut connections = Vec::new();
+    loop {
+        tokio::select! {
+            _ = ready.changed() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => {
+                    let answer = answer_building_connection(
+                        stream,
+                        ready.clone(),
+                        early_lines.clone(),
+                        write_timeout,
+                        max_args_len,
+                    );
+                    connections.push(tokio::spawn(async move {
+                        let (reader, partial_frame) = answer.await?;
+                        Some((reader, partial_frame, addr))
+                    }));
+                }
+                Err(e) => warn!("Error accepting connection: {:?}", e),
+            },
+        }
+    }
+    let mut waiting = Vec::new();
+    for connection in connections {
+        if let Ok(Some(connection)) = connection.await {
+            waiting.push(connection);
+        }
+    }
+    waiting
+}
+
+/// Answers a connection until the index is ready, then returns it along with anything the client
+/// has sent that hasn't been read yet and the start of a frame that has, or None when the client
+/// has gone or was dropped for not reading its response or for sending more than a frame can hold.
+async fn answer_building_connection(
+    stream: TcpStream,
+    mut ready: watch::Receiver<bool>,
+    early_lines: Option<Arc<EarlyLines>>,
+    write_timeout: tokio::time::Duration,
+    max_args_len: usize,
+) -> Option<(BufReader<TcpStream>, Vec<u8>)> {
+    let max_frame_len = Command::max_frame_len(max_args_len);
+    let mut reader = BufReader::new(stream);
+    loop {
+        if *ready.borrow() {
+            return Some((reader, Vec::new()));
+        }
+        let mut buf = Vec::new();
+        // a length prefixed payload can contain the delimiter, so keep reading until the frame is
+        // whole, and no further than the longest frame like the server does
+        let frame = loop {
+            let mut limited = (&mut reader).take(max_frame_len.saturating_sub(buf.len()) as u64);
+            let read = tokio::select! {
+                // a frame that has been read whole is answered here, so that only the start of
+                // one is ever handed on and the server doesn't wait for a rest that was already
+                // read
+                biased;
+                read = limited.read_until(0xA, &mut buf) => Some(read),
+                _ = ready.changed() => None,
+            };
+            // part of a frame may already have been taken out of the reader, and it goes with the
+            // connection rather than the client holding up the server until it's sent
+            let Some(read) = read else {
+                return Some((reader, buf));
+            };
+            match read {
+                Ok(0) | Err(_) => return None,
+                Ok(_) => {}
+            }
+            if buf.len() >= max_frame_len {
+                break Err(FrameError::TooLarge);
+            }
+            match Frame::parse(&buf, ChecksumAlgorithm::default(), max_args_len) {
+                Err(FrameError::Incomplete) => continue,
+                parsed => break parsed,
+            }
+        };
+        let response = match (frame, &early_lines) {
+            (
+                Ok(Frame {
+                    cmd: Command::Health,
+                    ..
+                }),
+                _,
+            ) => b"OK\r\nbuilding\r\n".to_vec(),
+            (
+                Ok(Frame {
+                    cmd: Command::Quit, ..
+                }),
+                _,
+            ) => return None,
+            (
+                Ok(Frame {
+                    cmd: Command::Get(line_number),
+                    ..
+                }),
+                Some(early_lines),
+            ) => early_lines.get(line_number as u64),
+            // there's no telling where the next frame starts after one that's too long, so the
+            // client is let go once it's been told
+            (Err(FrameError::TooLarge), _) => {
+                let refusal = reader.get_mut().write_all(b"ERR 413\r\n");
+                let _ = tokio::time::timeout(write_timeout, refusal).await;
+                return None;
+            }
+            _ => b"ERR 503\r\n".to_vec(),
+        };
+        // a client that doesn't read its response is let go rather than holding up the server
+        // starting, since the rest of the response can't be handed on
+        tokio::select! {
+            biased;
+            written = tokio::time::timeout(write_timeout, reader.get_mut().write_all(&response)) => {
+                written.ok()?.ok()?;
+            }
+            _ = ready.changed() => return None,
+        }
+    }
 }
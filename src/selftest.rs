@@ -0,0 +1,56 @@
+use anyhow::{Context, Result};
+use std::time::Instant;
+
+use crate::config::SelftestConfig;
+use crate::db::Database;
+
+// the server's default, since the self-test reads the file the way it would
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Builds the index for a file, then GETs its first and last lines and a sample of random ones
+/// straight from a session, printing how long it all took.  Fails on the first GET that does.  The
+/// index is neither loaded nor saved, like with --no-persist-index, so nothing is left next to the
+/// file.
+pub async fn run(config: SelftestConfig) -> Result<()> {
+    let started = Instant::now();
+    let db = Database::new(
+        &config.db_file,
+        &format!("{}.index", config.db_file),
+        false,
+        READ_BUFFER_SIZE,
+        false,
+        None,
+        false,
+        None,
+        false,
+        None,
+        None,
+    )
+    .await
+    .context("could not index the file")?;
+    let mut session = db.get_session().await?;
+    let line_count = session.line_count();
+    println!("indexed {} lines in {:?}", line_count, started.elapsed());
+    if line_count == 0 {
+        anyhow::bail!("the file has no lines to get");
+    }
+
+    let mut line_numbers = vec![1, line_count];
+    line_numbers.extend((0..config.samples).map(|_| rand::random_range(1..=line_count)));
+    let started = Instant::now();
+    for &line_number in &line_numbers {
+        session
+            .get(line_number)
+            .await
+            .with_context(|| format!("GET {line_number} failed"))?;
+    }
+    let elapsed = started.elapsed();
+    println!(
+        "{} GETs in {:?}, {:.0} GETs/s",
+        line_numbers.len(),
+        elapsed,
+        line_numbers.len() as f64 / elapsed.as_secs_f64()
+    );
+    println!("selftest passed");
+    Ok(())
+}
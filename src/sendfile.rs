@@ -0,0 +1,65 @@
+use std::fs::File;
+use std::io;
+use tokio::net::TcpStream;
+
+// the most that is handed to a single sendfile call, which Linux caps a little under 2GiB anyway
+#[cfg(target_os = "linux")]
+const MAX_CHUNK: u64 = 1 << 30;
+
+/// Sends the `len` bytes of `file` starting at `offset` to `stream` with sendfile(2), straight from
+/// the page cache rather than through the server's memory.  It fails with
+/// `ErrorKind::Unsupported` before anything is sent when the file can't be sent this way, so that
+/// the caller can fall back to copying it.
+#[cfg(target_os = "linux")]
+pub async fn send_file(stream: &TcpStream, file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let mut sent = 0;
+    while sent < len {
+        stream.writable().await?;
+        let result = stream.try_io(tokio::io::Interest::WRITABLE, || {
+            let mut file_offset = (offset + sent) as libc::off_t;
+            let count = (len - sent).min(MAX_CHUNK) as usize;
+            // SAFETY: both fds are open for as long as the borrows they came from, and the kernel
+            // only writes to file_offset, which lives across the call
+            let n = unsafe {
+                libc::sendfile(
+                    stream.as_raw_fd(),
+                    file.as_raw_fd(),
+                    &mut file_offset,
+                    count,
+                )
+            };
+            if n < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok(n as u64)
+            }
+        });
+        match result {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => sent += n,
+            // the socket's buffer filled up again since it was writable
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            // filesystems that can't be sent from say so on the first call
+            Err(e)
+                if sent == 0 && matches!(e.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) =>
+            {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, e))
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Only Linux has sendfile here, so everywhere else the caller copies the bytes itself.
+#[cfg(not(target_os = "linux"))]
+pub async fn send_file(
+    _stream: &TcpStream,
+    _file: &File,
+    _offset: u64,
+    _len: u64,
+) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
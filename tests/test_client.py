@@ -1,36 +1,77 @@
+import base64
+import binascii
+import fcntl
+import hashlib
+import json
+import os
+import re
+import signal
 import socket
+import struct
+import subprocess
+import sys
+import threading
+import time
+import zlib
+from pathlib import Path
+
 import pytest
 
+BINARY = Path(__file__).resolve().parent.parent / "target" / "release" / "line-server"
+
 
 def checksum(frame: bytes) -> int:
     return sum(frame) % 256
 
 
+def prefixed(command: bytes, payload: bytes) -> bytes:
+    """Builds the frame body for a command whose args are a length prefixed payload."""
+    return command + len(payload).to_bytes(4, "big") + payload
+
+
 class Client:
     QUIT_FRAME = b"1\x00\x00\x00\x00" + bytes([checksum(b"1\x00\x00\x00\x00")]) + b"\n"
     SHUTDOWN_FRAME = (
         b"2\x00\x00\x00\x00" + bytes([checksum(b"2\x00\x00\x00\x00")]) + b"\n"
     )
+    HEALTH = b"H\x00\x00\x00\x00"
+    DRAIN_FRAME = b"D\x00\x00\x00\x00" + bytes([checksum(b"D\x00\x00\x00\x00")]) + b"\n"
 
     s: socket.socket
 
-    def __init__(self, port: int):
-        self.s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
-        self.s.connect(("localhost", port))
+    def __init__(self, port: int, host: str = "localhost", source: str | None = None):
+        self.s = socket.socket(socket.AF_INET6 if ":" in host else socket.AF_INET, socket.SOCK_STREAM)
+        if source is not None:
+            self.s.bind((source, 0))
+        self.s.connect((host, port))
 
     def recvall(self) -> list[bytes]:
         resp = []
         while len(resp) < 2:
             data = self.s.recv(1024)
-            resp += data.strip().split(b"\n")
-            if b"ERR" in resp:
+            resp += [line.rstrip(b"\r") for line in data.strip().split(b"\n")]
+            if any(line.startswith(b"ERR") for line in resp):
                 break
         return resp
 
+    def recvuntil(self, terminator: bytes) -> list[bytes]:
+        """Receives a multi-line response that ends with the `terminator` line (or an error)."""
+        data = b""
+        while not data.endswith(terminator + b"\r\n") and not (data.startswith(b"ERR") and data.endswith(b"\r\n")):
+            chunk = self.s.recv(65536)
+            if not chunk:
+                break
+            data += chunk
+        return [line.rstrip(b"\r") for line in data.split(b"\n")[:-1]]
+
     def quit(self):
         self.s.sendall(Client.QUIT_FRAME)
         self.s.close()
 
+    def drain(self):
+        self.s.sendall(Client.DRAIN_FRAME)
+        self.s.close()
+
     def shutdown(self):
         shutdown_frame = (
             b"2\x00\x00\x00\x00" + bytes([checksum(b"2\x00\x00\x00\x00")]) + b"\n"
@@ -39,23 +80,95 @@ class Client:
         while True:
             pass
 
-    def make_request(self, frame: bytes, quit: bool = True, calc_checksum: bool = True) -> list[bytes]:
+    def make_request(
+        self, frame: bytes, quit: bool = True, calc_checksum: bool = True, until: bytes | None = None
+    ) -> list[bytes]:
         bytes_to_send = frame
         if calc_checksum:
             bytes_to_send += bytes([checksum(frame)])
         bytes_to_send += b"\n"
         self.s.sendall(bytes_to_send)
-        resp = self.recvall()
+        resp = self.recvall() if until is None else self.recvuntil(until)
         if quit:
             self.quit()
         return resp
 
 
+def free_port() -> int:
+    with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as s:
+        s.bind(("localhost", 0))
+        return s.getsockname()[1]
+
+
+class Server:
+    """A server process serving `db_file` on its own port, for tests that need their own data or flags."""
+
+    def __init__(self, db_file: Path, *args: str, host: str = "localhost"):
+        self.db_file = db_file
+        self.host = host
+        self.port = free_port()
+        self.proc = subprocess.Popen(
+            [str(BINARY), str(db_file), "--port", str(self.port), *args],
+            cwd=db_file.parent,
+            stdout=subprocess.DEVNULL,
+            stderr=subprocess.DEVNULL,
+        )
+        deadline = time.monotonic() + 10
+        while True:
+            try:
+                if Client(self.port, self.host).make_request(Client.HEALTH) == [b"OK", b"ready"]:
+                    return
+            except ConnectionRefusedError:
+                if time.monotonic() > deadline or self.proc.poll() is not None:
+                    raise
+            assert time.monotonic() < deadline, "the server never became ready"
+            time.sleep(0.05)
+
+    def client(self) -> Client:
+        return Client(self.port, self.host)
+
+    def log(self) -> str:
+        return (self.db_file.parent / "output.log").read_text()
+
+    def wait_for_log(self, text: str, timeout: float = 5):
+        deadline = time.monotonic() + timeout
+        while text not in self.log():
+            assert time.monotonic() < deadline, f"{text!r} never appeared in the log"
+            time.sleep(0.05)
+
+    def wait(self, timeout: float = 5) -> int:
+        return self.proc.wait(timeout=timeout)
+
+    def kill(self):
+        if self.proc.poll() is None:
+            self.proc.kill()
+            self.proc.wait()
+
+
 @pytest.fixture
 def client():
     return Client(10497)
 
 
+@pytest.fixture
+def spawn(tmp_path):
+    """Writes `lines` to a fresh db file and starts a server for it with the extra command line `args`."""
+    servers = []
+
+    def _spawn(lines: list[str] | bytes, *args: str) -> Server:
+        db_file = tmp_path / f"db{len(servers)}.txt"
+        if isinstance(lines, bytes):
+            db_file.write_bytes(lines)
+        else:
+            db_file.write_text("".join(f"{line}\n" for line in lines))
+        servers.append(Server(db_file, *args))
+        return servers[-1]
+
+    yield _spawn
+    for server in servers:
+        server.kill()
+
+
 def test_ok(client):
     s = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
     s.connect(("localhost", 10497))
@@ -69,7 +182,7 @@ def test_out_of_bounds(client):
     s.connect(("localhost", 10497))
     frame = b"0" + bytes([0xFF, 0xFF, 0xFF, 0xFF])  # 20D96C
     resp = client.make_request(frame)
-    assert resp[0] == b"ERR"
+    assert resp[0] == b"ERR 404"
 
 
 def test_zero_index(client):
@@ -77,7 +190,7 @@ def test_zero_index(client):
     s.connect(("localhost", 10497))
     frame = b"0\x00\x00\x00\x00"
     resp = client.make_request(frame)
-    assert resp[0] == b"ERR"
+    assert resp[0] == b"ERR 404"
     s.close()
 
 
@@ -112,3 +225,2054 @@ def test_invalid_frame(client):
     frame = b"0\x00\x00\x00"
     resp = client.make_request(frame)
     assert resp[0] == b"ERR"
+
+
+def test_drain_lets_existing_connections_finish(spawn):
+    server = spawn(["first line", "second line"])
+    existing = server.client()
+    assert existing.make_request(b"0\x00\x00\x00\x01", quit=False)[0] == b"OK"
+    server.client().drain()
+    # new connections are refused once the server has stopped accepting
+    deadline = time.monotonic() + 5
+    with pytest.raises(ConnectionRefusedError):
+        while time.monotonic() < deadline:
+            server.client().quit()
+            time.sleep(0.05)
+    resp = existing.make_request(b"0\x00\x00\x00\x02")
+    assert resp == [b"OK", b"second line"]
+    assert server.wait() == 0
+
+
+def test_get_not_in_index_is_404(spawn):
+    server = spawn(["only line"], "--log-level", "debug")
+    resp = server.client().make_request(b"0\x00\x00\x00\x09")
+    assert resp == [b"ERR 404"]
+    assert "[DEBUG]" in next(line for line in server.log().splitlines() if "GET 9" in line and "index" in line)
+
+
+def test_access_log_has_a_line_per_request(spawn, tmp_path):
+    access_log = tmp_path / "access.log"
+    server = spawn(["first line", "second line"], "--access-log", str(access_log))
+    client = server.client()
+    assert client.make_request(b"0\x00\x00\x00\x02", quit=False) == [b"OK", b"second line"]
+    assert client.make_request(b"0\x00\x00\x00\x09") == [b"ERR 404"]
+    # the readiness probe's HEALTH and QUIT come first
+    deadline = time.monotonic() + 5
+    while len(lines := access_log.read_text().splitlines()) < 5:
+        assert time.monotonic() < deadline, lines
+        time.sleep(0.05)
+    assert lines[0].endswith('"HEALTH" 200 11')
+    lines = lines[2:]
+    assert len(lines) == 3
+    assert re.fullmatch(r'127\.0\.0\.1 - - \[\d{2}/\w{3}/\d{4}:\d{2}:\d{2}:\d{2} [+-]\d{4}\] "GET 2" 200 16', lines[0])
+    assert lines[1].endswith('"GET 9" 404 9')
+    assert lines[2].endswith('"QUIT" - -')
+    # the requests aren't in the other logs' file at info level
+    assert "GET 2" not in server.log()
+
+
+def test_logs_have_the_last_requests_once_the_server_exits(spawn, tmp_path):
+    access_log = tmp_path / "access.log"
+    server = spawn(["first line", "second line"], "--access-log", str(access_log))
+    client = server.client()
+    assert client.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"OK", b"first line"]
+    client.s.sendall(Client.SHUTDOWN_FRAME)
+    assert server.wait() == 0
+    # read straight away, without waiting for anything to be written after the exit
+    lines = access_log.read_text().splitlines()
+    assert lines[-2].endswith('"GET 1" 200 15')
+    assert lines[-1].endswith('"SHUTDOWN" - -')
+    assert server.log().splitlines()[-1].endswith("Server shutting down.  Goodbye!")
+
+
+def test_served_counts_go_up_with_each_get_of_a_line(spawn):
+    server = spawn(["first line", "second line"], "--served-counts")
+    client = server.client()
+    for served in range(1, 4):
+        assert client.make_request(b"0\x00\x00\x00\x01", quit=False) == [f"OK served={served}".encode(), b"first line"]
+    # each line has a count of its own, shared by every connection
+    assert client.make_request(b"0\x00\x00\x00\x02") == [b"OK served=1", b"second line"]
+    assert server.client().make_request(b"0\x00\x00\x00\x01") == [b"OK served=4", b"first line"]
+    assert server.client().make_request(b"0\x00\x00\x00\x03") == [b"ERR 404"]
+
+
+def test_get_read_failure_is_500(spawn):
+    # the second line is not valid UTF-8 so reading it fails even though it is in the index
+    server = spawn(b"first line\n\xff\xfe broken\n")
+    resp = server.client().make_request(b"0\x00\x00\x00\x02")
+    assert resp == [b"ERR 500"]
+    assert "[ERROR]" in next(line for line in server.log().splitlines() if "GET 2 failed" in line)
+
+
+def test_cache_info_lists_the_cached_lines_until_they_are_evicted(spawn):
+    server = spawn([f"line {n}" for n in range(1, 10)], "--line-cache", "3")
+    client = server.client()
+    assert client.make_request(b"i\x00\x00\x00\x00", quit=False, until=b"END") == [b"OK", b"0", b"END"]
+    for line_number in (1, 2, 3, 1):
+        assert client.make_request(b"0" + line_number.to_bytes(4, "big"), quit=False) == [b"OK", f"line {line_number}".encode()]
+    assert client.make_request(b"i\x00\x00\x00\x00", quit=False, until=b"END") == [b"OK", b"3", b"1", b"2", b"3", b"END"]
+    # 2 was used longest ago since 1 was read again, so it makes way for 4
+    assert client.make_request(b"0\x00\x00\x00\x04", quit=False) == [b"OK", b"line 4"]
+    assert client.make_request(b"i\x00\x00\x00\x00", quit=False, until=b"END") == [b"OK", b"3", b"1", b"3", b"4", b"END"]
+    # a cached line is sent the same as one read from the file
+    assert client.make_request(b"0\x00\x00\x00\x03") == [b"OK", b"line 3"]
+
+
+def test_get_range_cached_flags_the_lines_from_the_cache(spawn):
+    server = spawn([f"line {n}" for n in range(1, 10)], "--line-cache", "5")
+    client = server.client()
+    for line_number in (2, 4):
+        assert client.make_request(b"0" + line_number.to_bytes(4, "big"), quit=False) == [b"OK", f"line {line_number}".encode()]
+    frame = b"?" + (1).to_bytes(4, "big") + (5).to_bytes(4, "big")
+    assert client.make_request(frame, quit=False, until=b"END") == [
+        b"OK", b"1:miss:line 1", b"2:hit:line 2", b"3:miss:line 3", b"4:hit:line 4", b"5:miss:line 5", b"END",
+    ]
+    # the misses were cached on the way
+    assert client.make_request(frame, until=b"END") == [
+        b"OK", b"1:hit:line 1", b"2:hit:line 2", b"3:hit:line 3", b"4:hit:line 4", b"5:hit:line 5", b"END",
+    ]
+    uncached = spawn(["a", "b"]).client()
+    assert uncached.make_request(b"?\x00\x00\x00\x01\x00\x00\x00\x02", until=b"END") == [b"OK", b"1:miss:a", b"2:miss:b", b"END"]
+
+
+def test_disk_check_reads_a_sample_of_lines(spawn):
+    server = spawn([f"line {n}" for n in range(1, 101)])
+    assert server.client().make_request(b"d\x00\x00\x00\x00") == [b"OK", b"16 lines read"]
+    assert spawn(["one", "two"]).client().make_request(b"d\x00\x00\x00\x00") == [b"OK", b"2 lines read"]
+
+
+def test_disk_check_fails_on_a_line_it_cant_read(spawn):
+    # none of the lines are valid UTF-8, so whichever are sampled can't be read
+    server = spawn(b"\xff broken\n" * 20)
+    resp = server.client().make_request(b"d\x00\x00\x00\x00")
+    assert len(resp) == 1 and re.fullmatch(rb"ERR 500 line \d+ unreadable", resp[0])
+    assert "DISK CHECK could not read line" in server.log()
+
+
+def test_warn_level_only_logs_problems(spawn):
+    server = spawn(b"first line\n\xff\xfe broken\n", "--log-level", "warn")
+    assert server.client().make_request(b"0\x00\x00\x00\x01") == [b"OK", b"first line"]
+    assert server.log() == ""
+    assert server.client().make_request(b"0\x00\x00\x00\x02") == [b"ERR 500"]
+    server.wait_for_log("GET 2 failed")
+    assert all("[ERROR]" in line for line in server.log().splitlines())
+
+
+def test_log_level_from_the_environment(tmp_path, monkeypatch):
+    monkeypatch.setenv("LINE_SERVER_LOG", "debug")
+    db_file = tmp_path / "db.txt"
+    db_file.write_text("only line\n")
+    server = Server(db_file)
+    try:
+        server.client().make_request(b"0\x00\x00\x00\x01")
+        server.wait_for_log("[DEBUG]")
+    finally:
+        server.kill()
+
+
+def test_get_all_small_file(spawn):
+    server = spawn(["first", "second", "third"])
+    resp = server.client().make_request(b"A\x00\x00\x00\x00", until=b"END")
+    assert resp == [b"OK", b"first", b"second", b"third", b"END"]
+
+
+def test_get_all_refuses_files_over_the_response_limit(spawn):
+    server = spawn(["x" * 100] * 10, "--max-response-size", "500")
+    resp = server.client().make_request(b"A\x00\x00\x00\x00", until=b"END")
+    assert resp == [b"ERR 413"]
+
+
+def test_shutdown_exits_cleanly(spawn):
+    server = spawn(["only line"])
+    server.client().s.sendall(Client.SHUTDOWN_FRAME)
+    assert server.wait() == 0
+    assert "Immediate(Operator) shutdown signal sent." in server.log()
+
+
+SEARCH_LINES = ["apple pie", "banana", "apple tart", "cherry pie"]
+
+
+def test_regex_search_matches_multiple_lines(spawn):
+    server = spawn(SEARCH_LINES)
+    # the pattern is 10 bytes long so its length prefix contains the frame delimiter
+    resp = server.client().make_request(prefixed(b"R", b"(pie|tart)"), until=b"END")
+    assert resp == [b"OK", b"1", b"3", b"4", b"END"]
+
+
+def test_regex_search_anchored_pattern(spawn):
+    server = spawn(SEARCH_LINES)
+    resp = server.client().make_request(prefixed(b"R", b"^b.*a$"), until=b"END")
+    assert resp == [b"OK", b"2", b"END"]
+
+
+def test_regex_search_invalid_pattern(spawn):
+    server = spawn(SEARCH_LINES)
+    resp = server.client().make_request(prefixed(b"R", b"apple("), until=b"END")
+    assert len(resp) == 1
+    assert resp[0].startswith(b"ERR 400 ")
+    assert b"unclosed group" in resp[0]
+
+
+def test_extract_returns_only_the_captured_part_of_matching_lines(spawn):
+    server = spawn(["user=ann id=7", "no user here", "user=bob", "id=9 user=cat7"])
+    resp = server.client().make_request(prefixed(b"x", rb"user=([a-z]+)"), until=b"END")
+    assert resp == [b"OK", b"1:ann", b"3:bob", b"4:cat", b"END"]
+    resp = server.client().make_request(prefixed(b"x", rb"id=(\d+)"), until=b"END")
+    assert resp == [b"OK", b"1:7", b"4:9", b"END"]
+    assert server.client().make_request(prefixed(b"x", b"user")) == [b"ERR 400 the pattern has no capture group"]
+    assert server.client().make_request(prefixed(b"x", b"(user"))[0].startswith(b"ERR 400 ")
+
+
+def test_cursor_walks_the_whole_file(spawn):
+    server = spawn(["one", "two", "three"])
+    client = server.client()
+    next_frame, prev_frame = b"N\x00\x00\x00\x00", b"P\x00\x00\x00\x00"
+    assert [client.make_request(next_frame, quit=False)[1] for _ in range(3)] == [b"one", b"two", b"three"]
+    # stepping past either end is refused without moving the cursor
+    assert client.make_request(next_frame, quit=False) == [b"ERR EOF"]
+    assert client.make_request(prev_frame, quit=False) == [b"OK", b"two"]
+    assert client.make_request(prev_frame, quit=False) == [b"OK", b"one"]
+    assert client.make_request(prev_frame, quit=False) == [b"ERR BOF"]
+    assert client.make_request(next_frame, quit=False) == [b"OK", b"two"]
+    assert client.make_request(b"S\x00\x00\x00\x03", quit=False, until=b"OK") == [b"OK"]
+    assert client.make_request(prev_frame, quit=False) == [b"OK", b"two"]
+    assert client.make_request(b"S\x00\x00\x00\x04", quit=False, until=b"OK") == [b"ERR 404"]
+    client.quit()
+
+
+def test_port_in_use_is_explained(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_text("only line\n")
+    with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as taken:
+        taken.bind(("0.0.0.0", 0))
+        taken.listen()
+        port = taken.getsockname()[1]
+        got = subprocess.run([str(BINARY), str(db_file), "--port", str(port)], cwd=tmp_path, capture_output=True, timeout=10)
+    assert got.returncode == 1
+    assert b"is already in use" in got.stdout and b"panicked" not in got.stderr
+    assert f"Could not bind to 0.0.0.0:{port}: the address is already in use" in (tmp_path / "output.log").read_text()
+
+
+def test_max_memory_refuses_an_index_over_budget(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_text("".join(f"line {i}\n" for i in range(1000)))
+    with pytest.raises(ConnectionRefusedError):
+        Server(db_file, "--max-memory", "1024")
+    assert "over the 1024 byte memory budget" in (tmp_path / "output.log").read_text()
+
+
+def test_max_memory_serves_an_index_within_budget(spawn):
+    server = spawn([f"line {i}" for i in range(1000)], "--max-memory", str(1024 * 1024))
+    assert server.client().make_request(b"0\x00\x00\x00\x01") == [b"OK", b"line 0"]
+    assert "of the 1048576 byte memory budget" in server.log()
+
+
+def test_max_memory_caps_the_line_cache_to_what_the_index_leaves(spawn):
+    lines = [f"line {i}" for i in range(1000)]
+    roomy = spawn(lines, "--max-memory", str(1 << 30), "--line-cache", "1000")
+    assert "line cache is capped" not in roomy.log()
+    index_memory = int(re.search(r"The index takes about (\d+)", roomy.log()).group(1))
+    server = spawn(lines, "--max-memory", str(index_memory + 2000), "--line-cache", "1000")
+    capped = re.search(r"The line cache is capped at (\d+) of its 1000 lines to fit in the 2000 bytes left", server.log())
+    assert capped
+    fits = int(capped.group(1))
+    assert 0 < fits < 100
+    client = server.client()
+    # from 11 so that no line number has a newline byte in it
+    for line_number in range(11, fits + 21):
+        assert client.make_request(b"0" + line_number.to_bytes(4, "big"), quit=False) == [b"OK", f"line {line_number - 1}".encode()]
+    assert client.make_request(b"i\x00\x00\x00\x00", until=b"END")[:2] == [b"OK", str(fits).encode()]
+    # not even a line fits once the index has the whole budget
+    off = spawn(lines, "--max-memory", str(index_memory), "--line-cache", "1000")
+    assert "The line cache is off since not one line fits in the 0 bytes left" in off.log()
+    assert off.client().make_request(b"i\x00\x00\x00\x00", until=b"END") == [b"OK", b"0", b"END"]
+
+
+def test_max_memory_shrinks_a_line_cache_too_large_to_allocate(spawn):
+    server = spawn(["line 1", "line 2"], "--line-cache", "100000000000", "--max-memory", "100000")
+    assert re.search(r"The line cache is capped at \d+ of its 100000000000 lines", server.log())
+    assert server.client().make_request(b"0\x00\x00\x00\x02") == [b"OK", b"line 2"]
+
+
+def test_sparse_index_serves_the_same_lines_as_a_dense_one(spawn):
+    lines = [f"line {n} " + "x" * (n % 7) for n in range(1, 2001)]
+    dense = spawn(lines, "--max-memory", str(1 << 30))
+    sparse = spawn(lines, "--max-memory", str(1 << 30), "--sparse-index-above", "100", "--sparse-index-every", "10")
+    requests = [(b"0" + n.to_bytes(4, "big"), None) for n in (1, 2, 9, 11, 12, 999, 1000, 1991, 2000, 2001)]
+    requests += [
+        (b"G" + (95).to_bytes(4, "big") + (123).to_bytes(4, "big"), b"END"),
+        (b"Y" + (12345).to_bytes(8, "big"), None),
+        (b"E" + (500).to_bytes(8, "big") + (800).to_bytes(8, "big"), b"END"),
+        (b"V\x00\x00\x00\x00", None),
+    ]
+    for frame, until in requests:
+        assert sparse.client().make_request(frame, until=until) == dense.client().make_request(frame, until=until)
+    assert sparse.client().make_request(b"V\x00\x00\x00\x00") == [b"OK", b"ok"]
+    # the index only has every 10th line and the end of the file
+    assert sorted(get_index(sparse)) == [*range(1, 2001, 10), 2001]
+    # both servers log to the same file, the dense one first
+    dense_memory, sparse_memory = map(int, re.findall(r"index takes about (\d+) of", sparse.log()))
+    assert sparse_memory * 5 < dense_memory
+
+
+def test_saved_index_is_rebuilt_when_the_sparsity_changes(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_text("".join(f"line {n}\n" for n in range(1, 201)))
+    Server(db_file).kill()
+    server = Server(db_file, "--sparse-index-above", "100")
+    try:
+        server.wait_for_log("isn't as sparse as the configuration asks for, rebuilding it")
+        assert server.client().make_request(b"0\x00\x00\x00\x2a") == [b"OK", b"line 42"]
+    finally:
+        server.kill()
+
+
+def test_index_waits_for_the_lock_held_by_another_server(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_text("".join(f"line {n}\n" for n in range(1, 101)))
+    port = free_port()
+    with open(tmp_path / "db.txt.index.lock", "w") as lock:
+        fcntl.flock(lock, fcntl.LOCK_EX)
+        proc = subprocess.Popen(
+            [str(BINARY), str(db_file), "--port", str(port)],
+            cwd=tmp_path,
+            stdout=subprocess.DEVNULL,
+            stderr=subprocess.DEVNULL,
+        )
+        try:
+            deadline = time.monotonic() + 5
+            log = tmp_path / "output.log"
+            while not log.exists() or "waiting for it to finish" not in log.read_text():
+                assert time.monotonic() < deadline
+                time.sleep(0.05)
+            assert Client(port).make_request(Client.HEALTH) == [b"OK", b"building"]
+            assert not (tmp_path / "db.txt.index").exists()
+            fcntl.flock(lock, fcntl.LOCK_UN)
+            deadline = time.monotonic() + 5
+            while Client(port).make_request(Client.HEALTH) != [b"OK", b"ready"]:
+                assert time.monotonic() < deadline
+                time.sleep(0.05)
+            assert Client(port).make_request(b"0\x00\x00\x00\x07") == [b"OK", b"line 7"]
+        finally:
+            proc.kill()
+            proc.wait()
+
+
+CSV_LINES = ["name,age,score", "ann,34,9.5", "bob,n/a,7", "cat,17,3", "dan,40", "eve, 21 ,8", "fay"]
+
+
+def filter_column(column: int, delimiter: bytes, low: float, high: float) -> bytes:
+    return b"f" + column.to_bytes(4, "big") + delimiter + struct.pack(">dd", low, high)
+
+
+def test_filter_column_returns_rows_in_range_and_skips_unparseable_ones(spawn):
+    server = spawn(CSV_LINES)
+    resp = server.client().make_request(filter_column(1, b",", 18, 40), until=b"END")
+    # the header, n/a and the line without the column don't parse, and 17 is under the range
+    assert resp == [b"OK", b"2:ann,34,9.5", b"5:dan,40", b"6:eve, 21 ,8", b"END"]
+    resp = server.client().make_request(filter_column(2, b",", 7, 8.5), until=b"END")
+    assert resp == [b"OK", b"3:bob,n/a,7", b"6:eve, 21 ,8", b"END"]
+    assert server.client().make_request(filter_column(5, b",", 0, 100), until=b"END") == [b"OK", b"END"]
+
+
+def test_no_persist_index_never_writes_the_index(spawn):
+    server = spawn([f"line {n}" for n in range(1, 21)], "--no-persist-index")
+    assert server.client().make_request(b"0\x00\x00\x00\x0c") == [b"OK", b"line 12"]
+    assert not server.db_file.with_name(server.db_file.name + ".index").exists()
+    assert not server.db_file.with_name(server.db_file.name + ".index.lock").exists()
+
+
+def test_file_info_reports_size_and_mtime(spawn):
+    server = spawn(["first line", "second line"])
+    resp = server.client().make_request(b"F\x00\x00\x00\x00")
+    assert resp[0] == b"OK"
+    size, mtime = (int(field) for field in resp[1].split(b" "))
+    assert size == server.db_file.stat().st_size
+    assert abs(mtime - server.db_file.stat().st_mtime) < 1
+    assert abs(mtime - time.time()) < 60
+
+
+def test_capabilities_match_how_lines_are_sent(spawn):
+    server = spawn(b"first\r\nsecond\n")
+    resp = server.client().make_request(b"K\x00\x00\x00\x00", until=b"END")
+    assert resp[0] == b"OK" and resp[-1] == b"END"
+    capabilities = dict(line.split(b" ", 1) for line in resp[1:-1])
+    assert capabilities == {
+        b"delimiter": b"0x0a",
+        b"trims_crlf": b"false",
+        b"length_prefixed": b"false",
+        b"protocol_version": b"1",
+        b"line_range": b"all",
+    }
+    # the carriage return is still on the line, as trims_crlf says
+    client = server.client()
+    client.s.sendall(b"0\x00\x00\x00\x01" + bytes([checksum(b"0\x00\x00\x00\x01")]) + b"\n")
+    data = b""
+    while len(data) < len(b"OK\r\nfirst\r\n"):
+        data += client.s.recv(1024)
+    assert data == b"OK\r\nfirst\r\n"
+
+
+def test_random_line_is_repeatable_with_a_seed(spawn):
+    lines = [f"line {n}" for n in range(1, 101)]
+    server = spawn(lines)
+    seeded = [server.client().make_request(b"X\x00\x00\x00\x2a") for _ in range(2)]
+    assert seeded[0] == seeded[1]
+    assert seeded[0][0] == b"OK" and seeded[0][1].decode() in lines
+    unseeded = server.client().make_request(b"X\x00\x00\x00\x00")
+    assert unseeded[0] == b"OK" and unseeded[1].decode() in lines
+
+
+def test_random_line_of_an_empty_file(spawn):
+    server = spawn(b"")
+    assert server.client().make_request(b"X\x00\x00\x00\x00") == [b"ERR 404"]
+
+
+def test_built_in_client_gets_a_line(spawn):
+    server = spawn(["first line", "second line"])
+    got = subprocess.run(
+        [str(BINARY), "client", "--port", str(server.port), "get", "2"], capture_output=True, timeout=10
+    )
+    assert got.returncode == 0
+    assert got.stdout == b"OK\r\nsecond line\n"
+    missing = subprocess.run(
+        [str(BINARY), "client", "--port", str(server.port), "get", "3"], capture_output=True, timeout=10
+    )
+    assert missing.returncode == 1
+    assert missing.stdout == b"ERR 404\r\n"
+
+
+def test_selftest_gets_a_sweep_of_lines(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_text("".join(f"line {n}\n" for n in range(1, 501)))
+    got = subprocess.run([str(BINARY), "selftest", str(db_file), "--samples", "200"], capture_output=True, timeout=10)
+    assert got.returncode == 0, got.stderr
+    indexed, gets, passed = got.stdout.decode().splitlines()
+    assert indexed.startswith("indexed 500 lines in ")
+    count, rate = re.fullmatch(r"(\d+) GETs in \S+, (\d+) GETs/s", gets).groups()
+    assert int(count) == 202 and int(rate) > 0
+    assert passed == "selftest passed"
+    # neither the index nor its lock is left behind
+    assert [path.name for path in tmp_path.iterdir()] == ["db.txt"]
+    empty = tmp_path / "empty.txt"
+    empty.write_text("")
+    got = subprocess.run([str(BINARY), "selftest", str(empty)], capture_output=True, timeout=10)
+    assert got.returncode == 1
+    assert b"the file has no lines" in got.stderr
+
+
+def connection_is_served(server: Server) -> bool:
+    try:
+        return server.client().make_request(Client.HEALTH) == [b"OK", b"ready"]
+    except OSError:
+        return False
+
+
+def test_accept_rate_closes_a_burst_of_connections(spawn):
+    server = spawn(["only line"], "--accept-rate", "5")
+    # let the bucket fill up again after the readiness probe
+    time.sleep(0.5)
+    served = sum(connection_is_served(server) for _ in range(30))
+    assert 5 <= served < 30
+    server.wait_for_log("closing new connections until the rate drops")
+    time.sleep(1)
+    # connections that come in slower than the rate are all served
+    for _ in range(5):
+        assert connection_is_served(server)
+        time.sleep(0.25)
+
+
+def test_single_worker_still_serves(spawn):
+    server = spawn(["first line", "second line"], "--workers", "1")
+    assert server.client().make_request(b"0\x00\x00\x00\x02") == [b"OK", b"second line"]
+
+
+def test_compute_checksum_is_the_one_the_server_accepts(spawn):
+    server = spawn([f"line {n}" for n in range(1, 301)], "--debug-commands")
+    get = b"0\x00\x00\x01\x2c"
+    resp = server.client().make_request(prefixed(b"Z", get))
+    assert resp[0] == b"OK"
+    expected = int(resp[1])
+    assert expected == checksum(get)
+    assert server.client().make_request(get + bytes([expected]), calc_checksum=False) == [b"OK", b"line 300"]
+
+
+def test_compute_checksum_needs_debug_commands(spawn):
+    server = spawn(["only line"])
+    assert server.client().make_request(prefixed(b"Z", b"0\x00\x00\x00\x01")) == [b"ERR 403"]
+
+
+def test_internal_error_is_500_and_the_connection_carries_on(spawn):
+    server = spawn(["only line"], "--debug-commands")
+    client = server.client()
+    assert client.make_request(b"J\x00\x00\x00\x00", quit=False) == [b"ERR 500"]
+    assert "[ERROR]" in next(line for line in server.log().splitlines() if "the client asked for a failure" in line)
+    assert client.make_request(b"0\x00\x00\x00\x01") == [b"OK", b"only line"]
+
+
+def test_fail_needs_debug_commands(spawn):
+    server = spawn(["only line"])
+    assert server.client().make_request(b"J\x00\x00\x00\x00") == [b"ERR 403"]
+
+
+def test_permissions_list_the_debug_commands_only_when_enabled(spawn):
+    resp = spawn(["only line"]).client().make_request(b"Q\x00\x00\x00\x00", until=b"END")
+    assert resp[0] == b"OK" and resp[-1] == b"END"
+    assert b"0 GET" in resp and b"2 SHUTDOWN" in resp and b"Q PERMISSIONS" in resp
+    assert b"Z COMPUTE CHECKSUM" not in resp and b"J FAIL" not in resp
+    resp = spawn(["only line"], "--debug-commands").client().make_request(b"Q\x00\x00\x00\x00", until=b"END")
+    assert b"Z COMPUTE CHECKSUM" in resp and b"J FAIL" in resp
+
+
+def unpack_offsets(blob: bytes) -> dict[int, int]:
+    """Decodes a msgpack map of unsigned ints, which is all the index is."""
+    pos = 0
+
+    def take(n: int) -> int:
+        nonlocal pos
+        pos += n
+        return int.from_bytes(blob[pos - n : pos], "big")
+
+    def uint() -> int:
+        tag = take(1)
+        if tag < 0x80:
+            return tag
+        return take({0xCC: 1, 0xCD: 2, 0xCE: 4, 0xCF: 8}[tag])
+
+    tag = take(1)
+    size = tag & 0x0F if tag & 0xF0 == 0x80 else take({0xDE: 2, 0xDF: 4}[tag])
+    offsets = {}
+    for _ in range(size):
+        line_number = uint()
+        offsets[line_number] = uint()
+    assert pos == len(blob)
+    return offsets
+
+
+def get_index(server: Server) -> dict[int, int]:
+    client = server.client()
+    frame = b"O\x00\x00\x00\x00"
+    client.s.sendall(frame + bytes([checksum(frame)]) + b"\n")
+    data = b""
+    while b"\r\n" not in data[4:]:
+        data += client.s.recv(65536)
+    header, _, blob = data[4:].partition(b"\r\n")
+    assert data.startswith(b"OK\r\n")
+    while len(blob) < int(header):
+        blob += client.s.recv(65536)
+    client.quit()
+    return unpack_offsets(blob)
+
+
+def test_get_index_blob_maps_lines_to_offsets(spawn):
+    lines = [f"line {n}" for n in range(1, 301)]
+    server = spawn(lines)
+    offsets = get_index(server)
+    content = server.db_file.read_bytes()
+    assert len(offsets) == len(lines) + 1
+    assert offsets[len(lines) + 1] == len(content)
+    for line_number, line in enumerate(lines, start=1):
+        offset = offsets[line_number]
+        assert content[offset : offset + len(line) + 1] == f"{line}\n".encode()
+
+
+def test_get_index_over_the_response_limit(spawn):
+    server = spawn([f"line {n}" for n in range(1, 301)], "--max-response-size", "64")
+    assert server.client().make_request(b"O\x00\x00\x00\x00") == [b"ERR 413"]
+
+
+def test_half_closed_client_gets_its_response_and_is_disconnected(spawn):
+    server = spawn(["first line", "second line"])
+    client = server.client()
+    frame = b"0\x00\x00\x00\x02"
+    client.s.sendall(frame + bytes([checksum(frame)]) + b"\n")
+    client.s.shutdown(socket.SHUT_WR)
+    client.s.settimeout(5)
+    data = b""
+    while chunk := client.s.recv(1024):
+        data += chunk
+    assert data == b"OK\r\nsecond line\n"
+    server.wait_for_log("unexpectedly")
+
+
+def test_health_is_building_until_the_index_is_ready(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_bytes(b"x\n" * 1_000_000)
+    port = free_port()
+    proc = subprocess.Popen(
+        [str(BINARY), str(db_file), "--port", str(port)],
+        cwd=tmp_path,
+        stdout=subprocess.DEVNULL,
+        stderr=subprocess.DEVNULL,
+    )
+    try:
+        health = []
+        deadline = time.monotonic() + 30
+        while health[-1:] != [[b"OK", b"ready"]]:
+            assert time.monotonic() < deadline
+            try:
+                health.append(Client(port).make_request(Client.HEALTH))
+            except ConnectionRefusedError:
+                time.sleep(0.01)
+        assert [b"OK", b"building"] in health
+        # other requests wait for the index, and are served once it's ready
+        assert Client(port).make_request(b"0\x00\x00\x00\x01") == [b"OK", b"x"]
+    finally:
+        proc.kill()
+        proc.wait()
+
+
+def test_frames_while_building_are_read_like_the_servers(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_bytes(b"x\n" * 5_000_000)
+    port = free_port()
+    proc = subprocess.Popen(
+        [str(BINARY), str(db_file), "--port", str(port), "--max-args-len", "100"],
+        cwd=tmp_path,
+        stdout=subprocess.DEVNULL,
+        stderr=subprocess.DEVNULL,
+    )
+    try:
+        deadline = time.monotonic() + 10
+        while True:
+            try:
+                client = Client(port)
+                break
+            except ConnectionRefusedError:
+                assert time.monotonic() < deadline
+                time.sleep(0.01)
+        client.s.settimeout(5)
+        assert client.make_request(Client.HEALTH, quit=False) == [b"OK", b"building"]
+        # a FIND whose content has the delimiter in it is still the one frame
+        content = b"a\nb"
+        find = b"I" + len(content).to_bytes(4, "big") + content
+        client.s.sendall(find + bytes([checksum(find)]) + b"\n" + Client.HEALTH + bytes([checksum(Client.HEALTH)]) + b"\n")
+        received = b""
+        while not received.endswith(b"building\r\n"):
+            received += client.s.recv(1024)
+        assert received == b"ERR 503\r\nOK\r\nbuilding\r\n"
+        # more than the longest frame without a delimiter is refused and the client let go
+        client.s.sendall(b"x" * 1000)
+        assert client.s.recv(1024) == b"ERR 413\r\n"
+        assert client.s.recv(1024) == b""
+        assert Client(port).make_request(Client.HEALTH) == [b"OK", b"building"]
+    finally:
+        proc.kill()
+        proc.wait()
+
+
+@pytest.mark.parametrize("stall", ["half a frame", "not reading"])
+def test_stalled_client_while_building_doesnt_hold_up_readiness(tmp_path, stall):
+    db_file = tmp_path / "db.txt"
+    db_file.write_bytes(b"x\n" * 1_000_000)
+    port = free_port()
+    proc = subprocess.Popen(
+        [str(BINARY), str(db_file), "--port", str(port)],
+        cwd=tmp_path,
+        stdout=subprocess.DEVNULL,
+        stderr=subprocess.DEVNULL,
+    )
+    try:
+        deadline = time.monotonic() + 10
+        while True:
+            try:
+                stalled = Client(port)
+                break
+            except ConnectionRefusedError:
+                assert time.monotonic() < deadline
+                time.sleep(0.01)
+
+        def request(frame: bytes) -> list[bytes]:
+            # a server held up by the stalled client would never answer
+            client = Client(port)
+            client.s.settimeout(5)
+            return client.make_request(frame)
+
+        assert request(Client.HEALTH) == [b"OK", b"building"]
+        frame = b"0\x00\x00\x00\x01" + bytes([checksum(b"0\x00\x00\x00\x01")]) + b"\n"
+        if stall == "half a frame":
+            stalled.s.sendall(frame[:3])
+        else:
+            # more ERR 503s than the socket buffers can hold, none of which are read, until the
+            # server stops reading the frames too
+            stalled.s.setsockopt(socket.SOL_SOCKET, socket.SO_RCVBUF, 4096)
+            stalled.s.settimeout(1)
+            try:
+                stalled.s.sendall(frame * 2_000_000)
+            # or the server already let it go for not reading
+            except (TimeoutError, ConnectionResetError):
+                pass
+        # the write timeout is 30s, so waiting on the stalled client would take longer than this
+        while request(Client.HEALTH) != [b"OK", b"ready"]:
+            assert time.monotonic() < deadline + 15
+            time.sleep(0.05)
+        assert request(b"0\x00\x00\x00\x01") == [b"OK", b"x"]
+        if stall == "half a frame":
+            # the start of the frame was handed over with the connection
+            stalled.s.settimeout(5)
+            stalled.s.sendall(frame[3:])
+            assert stalled.recvall() == [b"OK", b"x"]
+    finally:
+        proc.kill()
+        proc.wait()
+
+
+def test_serve_while_building_gets_the_lines_indexed_so_far(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_bytes(b"".join(b"%d\n" % n for n in range(1, 1_000_001)))
+    port = free_port()
+    proc = subprocess.Popen(
+        [str(BINARY), str(db_file), "--port", str(port), "--serve-while-building"],
+        cwd=tmp_path,
+        stdout=subprocess.DEVNULL,
+        stderr=subprocess.DEVNULL,
+    )
+    try:
+        deadline = time.monotonic() + 30
+        while True:
+            assert time.monotonic() < deadline
+            try:
+                low = Client(port).make_request(b"0\x00\x00\x00\x07")
+            except ConnectionRefusedError:
+                time.sleep(0.01)
+                continue
+            if low != [b"BUILDING"]:
+                break
+        # the first lines are indexed well before the last ones
+        assert low == [b"OK", b"7"]
+        high = Client(port).make_request((b"0" + (999_999).to_bytes(4, "big")), until=b"BUILDING")
+        assert high == [b"BUILDING"]
+        assert Client(port).make_request(Client.HEALTH) == [b"OK", b"building"]
+        while Client(port).make_request(Client.HEALTH) != [b"OK", b"ready"]:
+            assert time.monotonic() < deadline
+            time.sleep(0.05)
+        assert Client(port).make_request(b"0" + (999_999).to_bytes(4, "big")) == [b"OK", b"999999"]
+    finally:
+        proc.kill()
+        proc.wait()
+
+
+def test_requests_while_building_are_unavailable_until_ready(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_bytes(b"x\n" * 1_000_000)
+    port = free_port()
+    proc = subprocess.Popen(
+        [str(BINARY), str(db_file), "--port", str(port)],
+        cwd=tmp_path,
+        stdout=subprocess.DEVNULL,
+        stderr=subprocess.DEVNULL,
+    )
+    try:
+        deadline = time.monotonic() + 10
+        while True:
+            try:
+                client = Client(port)
+                break
+            except ConnectionRefusedError:
+                assert time.monotonic() < deadline
+                time.sleep(0.01)
+        assert client.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"ERR 503"]
+        # the connection is handed to the server once the index is ready
+        while client.make_request(Client.HEALTH, quit=False) != [b"OK", b"ready"]:
+            assert time.monotonic() < deadline + 30
+            time.sleep(0.05)
+        assert client.make_request(b"0\x00\x00\x00\x01") == [b"OK", b"x"]
+    finally:
+        proc.kill()
+        proc.wait()
+
+
+def test_length_histogram(spawn):
+    lengths = [0, 1, 2, 3, 3, 4, 7, 8, 100, 0, 4096]
+    server = spawn(["x" * length for length in lengths])
+    resp = server.client().make_request(b"W\x00\x00\x00\x00", until=b"END")
+    assert resp == [
+        b"OK",
+        b"0 2",
+        b"1-1 1",
+        b"2-3 3",
+        b"4-7 2",
+        b"8-15 1",
+        b"64-127 1",
+        b"4096-8191 1",
+        b"END",
+    ]
+
+
+def test_line_range_only_serves_its_window(spawn):
+    server = spawn([f"line {n}" for n in range(1, 11)], "--line-range", "3:5")
+    assert server.client().make_request(b"0\x00\x00\x00\x03") == [b"OK", b"line 3"]
+    assert server.client().make_request(b"0\x00\x00\x00\x05") == [b"OK", b"line 5"]
+    # lines outside the window are in the file, unlike lines past its end
+    assert server.client().make_request(b"0\x00\x00\x00\x02") == [b"ERR 421"]
+    assert server.client().make_request(b"0\x00\x00\x00\x06") == [b"ERR 421"]
+    assert server.client().make_request(b"0\x00\x00\x00\x0b") == [b"ERR 404"]
+    get_all = server.client().make_request(b"A\x00\x00\x00\x00", until=b"END")
+    assert get_all == [b"OK", b"line 3", b"line 4", b"line 5", b"END"]
+    get_range = b"G" + (4).to_bytes(4, "big") + (6).to_bytes(4, "big")
+    assert server.client().make_request(get_range, until=b"END") == [b"ERR 421"]
+    assert b"line_range 3:5" in server.client().make_request(b"K\x00\x00\x00\x00", until=b"END")
+
+
+def test_connections_over_the_per_ip_limit_are_refused(spawn):
+    server = spawn(["only line"], "--max-connections-per-ip", "2")
+    # the check that the server was ready may not have been counted out yet
+    first = server.client()
+    deadline = time.monotonic() + 5
+    while len(first.make_request(b"s\x00\x00\x00\x00", quit=False, until=b"END")) != 3:
+        assert time.monotonic() < deadline, "the other connections never closed"
+        time.sleep(0.05)
+    second = server.client()
+    assert second.make_request(Client.HEALTH, quit=False) == [b"OK", b"ready"]
+    refused = server.client()
+    refused.s.settimeout(5)
+    assert refused.s.recv(1024) == b"ERR 429 too many connections\r\n"
+    assert refused.s.recv(1024) == b""
+    # another address on the loopback isn't held to the first one's connections
+    other = Client(server.port, "127.0.0.1", source="127.0.0.2")
+    assert other.make_request(Client.HEALTH) == [b"OK", b"ready"]
+    # the first host gets its place back once one of its connections has closed
+    first.quit()
+    deadline = time.monotonic() + 5
+    while (resp := server.client().make_request(Client.HEALTH, quit=False)) != [b"OK", b"ready"]:
+        assert resp == [b"ERR 429 too many connections"]
+        assert time.monotonic() < deadline, "the closed connection was never counted out"
+        time.sleep(0.05)
+
+
+def test_shutdown_command_tells_clients_the_operator_asked(spawn):
+    server = spawn(["only line"])
+    existing = server.client()
+    assert existing.make_request(b"0\x00\x00\x00\x01", quit=False)[0] == b"OK"
+    server.client().s.sendall(Client.SHUTDOWN_FRAME)
+    server.wait_for_log("shutdown signal sent")
+    resp = existing.make_request(b"0\x00\x00\x00\x01", quit=False, until=b"SHUTDOWN operator")
+    assert resp == [b"SHUTDOWN operator"]
+    assert server.wait() == 0
+
+
+def test_idle_client_is_told_about_a_shutdown_without_sending_anything(spawn):
+    server = spawn(["only line"])
+    existing = server.client()
+    assert existing.make_request(b"0\x00\x00\x00\x01", quit=False)[0] == b"OK"
+    server.client().s.sendall(Client.SHUTDOWN_FRAME)
+    existing.s.settimeout(5)
+    assert existing.recvuntil(b"SHUTDOWN operator") == [b"SHUTDOWN operator"]
+    assert server.wait() == 0
+
+
+def test_idle_client_is_warned_and_then_disconnected(spawn):
+    server = spawn(["only line"], "--idle-warning-ms", "300", "--idle-close-ms", "300")
+    client = server.client()
+    client.s.settimeout(5)
+    started = time.monotonic()
+    assert client.recvuntil(b"IDLE") == [b"IDLE"]
+    assert time.monotonic() - started >= 0.25
+    # a request after the warning starts the wait over
+    assert client.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"OK", b"only line"]
+    assert client.recvuntil(b"IDLE") == [b"IDLE"]
+    warned = time.monotonic()
+    assert client.s.recv(1024) == b""
+    assert time.monotonic() - warned >= 0.25
+    server.wait_for_log("after it was idle for 300ms more")
+
+
+def test_inactive_connection_is_reaped(spawn):
+    server = spawn(["only line"], "--reap-inactive-ms", "300")
+    busy = server.client()
+    quiet = server.client()
+    assert quiet.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"OK", b"only line"]
+    quiet.s.settimeout(5)
+    started = time.monotonic()
+    # a connection that keeps sending requests is left alone
+    while time.monotonic() - started < 0.6:
+        assert busy.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"OK", b"only line"]
+        time.sleep(0.1)
+    assert quiet.s.recv(1024) == b""
+    assert time.monotonic() - started < 2
+    server.wait_for_log("after it was inactive for")
+    assert busy.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"OK", b"only line"]
+
+
+@pytest.mark.parametrize("args, message", [([], b"SHUTDOWN operator"), (["--shutdown-retry-after", "5"], b"SHUTDOWN operator retry-after=5")])
+def test_shutdown_tells_clients_when_to_reconnect_only_when_configured(spawn, args, message):
+    server = spawn(["only line"], *args)
+    existing = server.client()
+    assert existing.make_request(b"0\x00\x00\x00\x01", quit=False)[0] == b"OK"
+    server.client().s.sendall(Client.SHUTDOWN_FRAME)
+    existing.s.settimeout(5)
+    assert existing.recvuntil(message) == [message]
+    assert server.wait() == 0
+
+
+def test_shutdown_during_a_large_response_lets_it_finish(spawn):
+    lines = [f"line {n} " + "x" * 100 for n in range(1, 20001)]
+    server = spawn(lines)
+    existing = server.client()
+    existing.s.setsockopt(socket.SOL_SOCKET, socket.SO_RCVBUF, 4096)
+    existing.s.sendall(b"A\x00\x00\x00\x00" + bytes([checksum(b"A\x00\x00\x00\x00")]) + b"\n")
+    # leave most of the response unread so the server is still writing it when the shutdown comes
+    data = existing.s.recv(1024)
+    server.client().s.sendall(Client.SHUTDOWN_FRAME)
+    server.wait_for_log("shutdown signal sent")
+    existing.s.settimeout(5)
+    while not data.endswith(b"SHUTDOWN operator\r\n"):
+        chunk = existing.s.recv(65536)
+        assert chunk, "the connection closed before the shutdown was sent"
+        data += chunk
+    assert data.split(b"\r\n") == [b"OK", "\n".join(lines).encode() + b"\nEND", b"SHUTDOWN operator", b""]
+    assert server.wait() == 0
+
+
+def test_signal_tells_clients_about_the_signal(spawn):
+    server = spawn(["only line"])
+    existing = server.client()
+    assert existing.make_request(b"0\x00\x00\x00\x01", quit=False)[0] == b"OK"
+    server.proc.send_signal(signal.SIGTERM)
+    server.wait_for_log("shutdown signal sent")
+    resp = existing.make_request(b"0\x00\x00\x00\x01", quit=False, until=b"SHUTDOWN signal")
+    assert resp == [b"SHUTDOWN signal"]
+    assert server.wait() == 0
+
+
+def test_losing_the_data_file_tells_clients_about_the_error(spawn):
+    server = spawn(["only line"])
+    existing = server.client()
+    assert existing.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"OK", b"only line"]
+    server.db_file.unlink()
+    # the next connection can't open the file, which shuts the server down
+    server.client()
+    server.wait_for_log("shutdown signal sent")
+    resp = existing.make_request(b"0\x00\x00\x00\x01", quit=False, until=b"SHUTDOWN error")
+    assert resp == [b"SHUTDOWN error"]
+    assert server.wait() == 0
+
+
+def multi_range(*ranges: tuple[int, int]) -> bytes:
+    frame = b"M" + len(ranges).to_bytes(4, "big")
+    for start, end in ranges:
+        frame += start.to_bytes(4, "big") + end.to_bytes(4, "big")
+    return frame
+
+
+def test_multi_range_groups_each_range_in_the_order_asked(spawn):
+    server = spawn([f"line {n}" for n in range(1, 31)])
+    resp = server.client().make_request(multi_range((20, 22), (1, 2), (29, 30)), until=b"END")
+    assert resp == [
+        b"OK",
+        b"RANGE 20 22", b"line 20", b"line 21", b"line 22",
+        b"RANGE 1 2", b"line 1", b"line 2",
+        b"RANGE 29 30", b"line 29", b"line 30",
+        b"END",
+    ]
+    assert server.client().make_request(multi_range((1, 2), (29, 31)), until=b"END") == [b"ERR 404"]
+    assert server.client().make_request(multi_range(*[(1, 1)] * 65), until=b"END") == [b"ERR"]
+
+
+def test_unique_runs_count_adjacent_duplicates(spawn):
+    server = spawn(["a", "a", "a", "b", "a", "a", "c", "c", ""])
+    unique_runs = b"U" + (1).to_bytes(4, "big") + (9).to_bytes(4, "big")
+    resp = server.client().make_request(unique_runs, until=b"END")
+    # the a after the b is another run, not more of the first one
+    assert resp == [b"OK", b"3 a", b"1 b", b"2 a", b"2 c", b"1 ", b"END"]
+    unique_runs = b"U" + (2).to_bytes(4, "big") + (5).to_bytes(4, "big")
+    assert server.client().make_request(unique_runs, until=b"END") == [b"OK", b"2 a", b"1 b", b"1 a", b"END"]
+    unique_runs = b"U" + (5).to_bytes(4, "big") + (10).to_bytes(4, "big")
+    assert server.client().make_request(unique_runs, until=b"END") == [b"ERR 404"]
+
+
+def recv_response(client: Client) -> bytes:
+    data = b""
+    while not (data.endswith(b"END\r\n") or (data.startswith(b"ERR") and data.endswith(b"\r\n"))):
+        chunk = client.s.recv(65536)
+        assert chunk
+        data += chunk
+    return data
+
+
+def test_zero_copy_ranges_match_the_copied_ones(spawn):
+    contents = b"".join(b"line %d %s\n" % (n, b"x" * (n % 50)) for n in range(1, 5001)) + b"no delimiter"
+    zero_copy = spawn(contents, "--log-level", "debug")
+    copied = spawn(contents, "--no-zero-copy")
+    frames = [b"G" + start.to_bytes(4, "big") + end.to_bytes(4, "big") for start, end in [(1, 1), (2, 3), (100, 4000), (4990, 5001)]]
+    frames.append(b"A\x00\x00\x00\x00")
+    for frame in frames:
+        responses = []
+        for server in (zero_copy, copied):
+            client = server.client()
+            client.s.sendall(frame + bytes([checksum(frame)]) + b"\n")
+            responses.append(recv_response(client))
+            client.quit()
+        assert responses[0] == responses[1]
+        assert responses[0].startswith(b"OK\r\n") and responses[0].endswith(b"\nEND\r\n")
+    assert responses[0] == b"OK\r\n" + contents + b"\nEND\r\n"
+    # the file in the test's directory can be sent with sendfile, so nothing was copied
+    if sys.platform.startswith("linux"):
+        assert "copying it instead" not in zero_copy.log()
+
+
+def test_reversed_range_is_the_forward_range_backwards(spawn):
+    lines = [f"line {n}" for n in range(1, 21)]
+    server = spawn(lines)
+    for start, end in [(1, 5), (8, 12), (16, 20), (1, 20), (7, 7)]:
+        forward = server.client().make_request(b"G" + start.to_bytes(4, "big") + end.to_bytes(4, "big"), until=b"END")
+        backward = server.client().make_request(b"g" + start.to_bytes(4, "big") + end.to_bytes(4, "big"), until=b"END")
+        assert backward == [b"OK", *reversed(forward[1:-1]), b"END"]
+        assert backward[1:-1] == [line.encode() for line in reversed(lines[start - 1 : end])]
+    assert server.client().make_request(b"g" + (18).to_bytes(4, "big") + (21).to_bytes(4, "big"), until=b"END") == [b"ERR 404"]
+
+
+def test_meta_range_matches_the_file_layout(spawn):
+    lines = ["a", "", "three", "a longer fourth line", "5", "six"]
+    server = spawn(b"".join(line.encode() + b"\n" for line in lines[:-1]) + b"six")
+    data = server.db_file.read_bytes()
+    resp = server.client().make_request(b"m" + (2).to_bytes(4, "big") + (6).to_bytes(4, "big"), until=b"END")
+    assert resp[0] == b"OK" and resp[-1] == b"END"
+    rows = [tuple(int(field) for field in row.split(b" ")) for row in resp[1:-1]]
+    assert [row[0] for row in rows] == [2, 3, 4, 5, 6]
+    for line_number, offset, length in rows:
+        # the last line has no delimiter to count
+        assert data[offset : offset + length] == lines[line_number - 1].encode() + (b"\n" if line_number < 6 else b"")
+    assert server.client().make_request(b"m" + (5).to_bytes(4, "big") + (7).to_bytes(4, "big"), until=b"END") == [b"ERR 404"]
+
+
+def test_reversed_range_ends_the_last_line_without_a_delimiter(spawn):
+    server = spawn(b"one\ntwo\nthree")
+    client = server.client()
+    assert client.make_request(b"L\x00\x00\x00:", quit=False, until=b"OK") == [b"OK"]
+    resp = client.make_request(b"g" + (2).to_bytes(4, "big") + (3).to_bytes(4, "big"), until=b"END")
+    assert resp == [b"OK", b"3:three", b"2:two", b"END"]
+
+
+def fnv1a(line: bytes) -> int:
+    hash = 0x811C9DC5
+    for byte in line:
+        hash = ((hash ^ byte) * 0x01000193) % (1 << 32)
+    return hash
+
+
+def test_get_if_changed_only_sends_a_line_whose_hash_differs(spawn):
+    server = spawn(["first", "second", "third"])
+    cached = b"c" + (2).to_bytes(4, "big") + fnv1a(b"second").to_bytes(4, "big")
+    stale = b"c" + (2).to_bytes(4, "big") + fnv1a(b"old second").to_bytes(4, "big")
+    # a newline in the args would end the frame early
+    assert b"\n" not in cached + bytes([checksum(cached)]) + stale + bytes([checksum(stale)])
+    client = server.client()
+    assert client.make_request(cached, quit=False, until=b"NOTMODIFIED") == [b"NOTMODIFIED"]
+    assert client.make_request(stale, quit=False) == [b"OK", b"second"]
+    missing = b"c" + (4).to_bytes(4, "big") + fnv1a(b"second").to_bytes(4, "big")
+    assert client.make_request(missing) == [b"ERR 404"]
+
+
+def test_echo_sends_back_exactly_the_payload(spawn):
+    server = spawn(["only line"])
+    payload = bytes(range(256)) + b"\r\nEND\r\n" + b"\x00\n" * 100
+    client = server.client()
+    client.s.sendall(prefixed(b"e", payload) + bytes([checksum(prefixed(b"e", payload))]) + b"\n")
+    expected = b"OK\r\n" + payload
+    resp = b""
+    while len(resp) < len(expected):
+        chunk = client.s.recv(65536)
+        assert chunk
+        resp += chunk
+    assert resp == expected
+    # an empty payload is just the OK
+    assert client.make_request(prefixed(b"e", b""), until=b"OK") == [b"OK"]
+
+
+@pytest.mark.parametrize("args", [[], ["--sparse-index-above", "2", "--sparse-index-every", "2"]])
+def test_extreme_finds_the_first_shortest_and_longest_lines(spawn, args):
+    # the longest is the last line, which has no delimiter, and the ties go to the first
+    server = spawn(b"medium\nab\nxy\nlonger one\nabcdef\nthe longest line", *args)
+    assert server.client().make_request(b"l\x00\x00\x00\x00") == [b"OK", b"2:ab"]
+    assert server.client().make_request(b"l\x00\x00\x00\x01") == [b"OK", b"6:the longest line"]
+    server = spawn(["", "b", "ccc", "ddd"], *args)
+    assert server.client().make_request(b"l\x00\x00\x00\x00") == [b"OK", b"1:"]
+    assert server.client().make_request(b"l\x00\x00\x00\x01") == [b"OK", b"3:ccc"]
+    assert server.client().make_request(b"l\x00\x00\x00\x02") == [b"ERR"]
+    assert spawn(b"", *args).client().make_request(b"l\x00\x00\x00\x01") == [b"ERR 404"]
+
+
+@pytest.mark.parametrize("args", [[], ["--sparse-index-above", "2", "--sparse-index-every", "3"]])
+def test_length_stats_uses_the_nearest_rank(spawn, args):
+    server = spawn(["x" * length for length in [7, 3, 10, 1, 5, 9, 2, 8, 6, 4]], *args)
+    assert server.client().make_request(b"6\x00\x00\x00\x00", until=b"END") == [
+        b"OK", b"min 1", b"max 10", b"mean 5.50", b"median 5", b"p90 9", b"p99 10", b"END",
+    ]
+    server = spawn(["", "ab", "abcd"], *args)
+    assert server.client().make_request(b"6\x00\x00\x00\x00", until=b"END") == [
+        b"OK", b"min 0", b"max 4", b"mean 2.00", b"median 2", b"p90 4", b"p99 4", b"END",
+    ]
+    assert spawn(b"", *args).client().make_request(b"6\x00\x00\x00\x00") == [b"ERR 404"]
+
+
+def chunk_plan(client: Client, chunks: int) -> list[tuple[int, int]]:
+    response = client.make_request(b"7" + chunks.to_bytes(4, "big"), until=b"END")
+    assert response[0] == b"OK" and response[-1] == b"END"
+    return [tuple(int(n) for n in row.split()) for row in response[1:-1]]
+
+
+@pytest.mark.parametrize("line_count, chunks", [(12, 4), (10, 3), (10, 1), (3, 5)])
+def test_chunk_plan_covers_every_line_once(spawn, line_count, chunks):
+    server = spawn([f"line {n}" for n in range(1, line_count + 1)])
+    plan = chunk_plan(server.client(), chunks)
+    assert len(plan) == min(chunks, line_count)
+    # each chunk starts just after the one before it, and the last ends at the last line
+    assert [start for start, _ in plan] == [1] + [end + 1 for _, end in plan[:-1]]
+    assert plan[-1][1] == line_count
+    sizes = [end - start + 1 for start, end in plan]
+    assert max(sizes) - min(sizes) <= 1 and sizes == sorted(sizes, reverse=True)
+
+
+def test_chunk_plan_edge_cases(spawn):
+    server = spawn([f"line {n}" for n in range(1, 11)], "--line-range", "3:9")
+    assert chunk_plan(server.client(), 3) == [(3, 5), (6, 7), (8, 9)]
+    assert chunk_plan(spawn(b"").client(), 4) == []
+    zero = spawn(["a", "b"]).client().make_request(b"7\x00\x00\x00\x00")
+    assert zero == [b"ERR 400 the number of chunks must be at least 1"]
+
+
+
+def outline(client: Client, center: int, radius: int) -> list[tuple[int, int]]:
+    frame = b"9" + center.to_bytes(4, "big") + radius.to_bytes(4, "big")
+    response = client.make_request(frame, until=b"END")
+    assert response[0] == b"OK" and response[-1] == b"END"
+    return [tuple(int(n) for n in row.split()) for row in response[1:-1]]
+
+
+@pytest.mark.parametrize("args", [[], ["--sparse-index-above", "2", "--sparse-index-every", "3"]])
+def test_outline_is_clamped_to_the_lines(spawn, args):
+    # every line is 8 bytes with its delimiter, so line n starts at 8 * (n - 1)
+    server = spawn([f"line {n:02}" for n in range(1, 11)], *args)
+    assert outline(server.client(), 5, 2) == [(n, 8 * (n - 1)) for n in range(3, 8)]
+    assert outline(server.client(), 2, 3) == [(n, 8 * (n - 1)) for n in range(1, 6)]
+    assert outline(server.client(), 9, 3) == [(n, 8 * (n - 1)) for n in range(6, 11)]
+    assert outline(server.client(), 4, 0) == [(4, 24)]
+    assert outline(server.client(), 1, 0xFFFFFFFF) == [(n, 8 * (n - 1)) for n in range(1, 11)]
+    assert server.client().make_request(b"9\x00\x00\x00\x0b\x00\x00\x00\x01") == [b"ERR 404"]
+    windowed = spawn([f"line {n:02}" for n in range(1, 11)], "--line-range", "3:5", *args)
+    assert outline(windowed.client(), 4, 5) == [(3, 16), (4, 24), (5, 32)]
+
+
+
+@pytest.mark.parametrize("command", [b"e", b"n", b"R"])
+def test_inflated_length_prefix_is_refused_without_waiting_for_it(spawn, command):
+    server = spawn(["a", "b"])
+    client = server.client()
+    client.s.settimeout(2)
+    # nothing but the prefix is sent, so a server waiting for the args would never answer
+    frame = command + b"\xff\xff\xff\xff"
+    client.s.sendall(frame + bytes([checksum(frame)]) + b"\n")
+    assert client.s.recv(1024) == b"ERR 413\r\n"
+    assert client.s.recv(1024) == b""
+    assert "declared args over the 65536 byte limit" in server.log()
+
+
+def test_max_args_len_limits_length_prefixed_args(spawn):
+    server = spawn(["a", "b"], "--max-args-len", "16")
+    client = server.client()
+    client.s.settimeout(2)
+    for payload, expected in [(b"x" * 16, b"OK\r\n" + b"x" * 16), (b"x" * 17, b"ERR 413\r\n")]:
+        frame = prefixed(b"e", payload)
+        client.s.sendall(frame + bytes([checksum(frame)]) + b"\n")
+        resp = b""
+        while len(resp) < len(expected):
+            resp += client.s.recv(1024)
+        assert resp == expected
+    # bytes that never end in the delimiter are cut off at the longest frame there can be
+    client = server.client()
+    client.s.settimeout(2)
+    client.s.sendall(b"A" * 2000)
+    assert client.s.recv(1024).startswith(b"ERR\r\n")
+
+
+def join_range(start: int, end: int, separator: bytes) -> bytes:
+    payload = start.to_bytes(4, "big") + end.to_bytes(4, "big") + separator
+    return b"w" + len(payload).to_bytes(4, "big") + payload
+
+
+def test_join_range_joins_the_lines_with_the_separator(spawn):
+    server = spawn(["first", "aa", "bb", "cc", "last"])
+    assert server.client().make_request(join_range(2, 4, b",")) == [b"OK", b"aa,bb,cc"]
+    assert server.client().make_request(join_range(2, 4, b"")) == [b"OK", b"aabbcc"]
+    assert server.client().make_request(join_range(2, 4, b" \\n ")) == [b"OK", b"aa \\n bb \\n cc"]
+    assert server.client().make_request(join_range(4, 6, b",")) == [b"ERR 404"]
+
+
+def page(start: int, page_size: int) -> bytes:
+    return b"p" + start.to_bytes(4, "big") + page_size.to_bytes(4, "big")
+
+
+def test_page_in_the_middle_says_where_the_next_one_starts(spawn):
+    server = spawn([f"line {n}" for n in range(1, 8)])
+    resp = server.client().make_request(page(2, 3), until=b"END")
+    assert resp == [b"OK", b"NEXT 5", b"line 2", b"line 3", b"line 4", b"END"]
+
+
+def test_last_page_says_it_reaches_the_end(spawn):
+    server = spawn([f"line {n}" for n in range(1, 8)])
+    resp = server.client().make_request(page(5, 3), until=b"END")
+    assert resp == [b"OK", b"EOF", b"line 5", b"line 6", b"line 7", b"END"]
+    resp = server.client().make_request(page(6, 100), until=b"END")
+    assert resp == [b"OK", b"EOF", b"line 6", b"line 7", b"END"]
+
+
+def test_page_past_the_end_is_empty(spawn):
+    server = spawn([f"line {n}" for n in range(1, 8)])
+    assert server.client().make_request(page(8, 3), until=b"END") == [b"OK", b"EOF", b"END"]
+    assert server.client().make_request(page(1, 0), until=b"END")[0].startswith(b"ERR 400")
+
+
+def test_raw_span_is_the_bytes_in_the_file(spawn):
+    data = b"first\r\n\nthird \xff\nfourth\nlast without a delimiter"
+    server = spawn(data)
+    for start, end, expected in [(2, 4, b"\nthird \xff\nfourth\n"), (1, 1, b"first\r\n"), (3, 5, data[data.index(b"third") :])]:
+        frame = b"r" + start.to_bytes(4, "big") + end.to_bytes(4, "big")
+        client = server.client()
+        client.s.sendall(frame + bytes([checksum(frame)]) + b"\n")
+        full = b"OK\r\n%d\r\n" % len(expected) + expected
+        data_read = b""
+        while len(data_read) < len(full):
+            chunk = client.s.recv(65536)
+            assert chunk
+            data_read += chunk
+        assert data_read == full
+    r_frame = b"r\x00\x00\x00\x04\x00\x00\x00\x06"
+    assert server.client().make_request(r_frame) == [b"ERR 404"]
+
+
+def search(needle: bytes) -> bytes:
+    return b"n" + len(needle).to_bytes(4, "big") + needle
+
+
+def test_search_streams_the_matching_lines_in_order(spawn):
+    # enough lines for the matches to be sent in several batches
+    lines = [f"line {n} {'needle' if n % 1000 == 7 else 'hay'}" for n in range(1, 20001)]
+    server = spawn(lines)
+    resp = server.client().make_request(search(b"needle"), until=b"END")
+    assert resp == [b"OK"] + [f"{n}:line {n} needle".encode() for n in range(7, 20001, 1000)] + [b"END"]
+    assert server.client().make_request(search(b"missing"), until=b"END") == [b"OK", b"END"]
+
+
+def test_offset_table_matches_the_file_layout(spawn):
+    data = b"first\n\nthird line\r\nfourth\nlast without a delimiter"
+    server = spawn(data)
+    client = server.client()
+    client.s.sendall(b"y\x00\x00\x00\x02\x00\x00\x00\x05" + bytes([checksum(b"y\x00\x00\x00\x02\x00\x00\x00\x05")]) + b"\n")
+    client.s.settimeout(5)
+    header = b""
+    while header.count(b"\r\n") < 2:
+        header += client.s.recv(1)
+    status, length, _ = header.split(b"\r\n")
+    assert status == b"OK"
+    table = b""
+    while len(table) < int(length):
+        table += client.s.recv(int(length) - len(table))
+    rows = [struct.unpack(">QQ", table[n : n + 16]) for n in range(0, len(table), 16)]
+    assert rows == [(6, 1), (7, 12), (19, 7), (26, 24)]
+    assert [data[offset : offset + length] for offset, length in rows] == [b"\n", b"third line\r\n", b"fourth\n", b"last without a delimiter"]
+    assert server.client().make_request(b"y\x00\x00\x00\x04\x00\x00\x00\x06") == [b"ERR 404"]
+
+
+def search_any(*needles: bytes) -> bytes:
+    payload = len(needles).to_bytes(4, "big") + b"".join(len(needle).to_bytes(4, "big") + needle for needle in needles)
+    return prefixed(b"q", payload)
+
+
+@pytest.mark.parametrize(
+    "needles, expected",
+    [
+        # a line with several of the needles is only sent once, tagged with the leftmost of them
+        ([b"apple", b"app", b"banana"], [b"1:0:apple pie", b"2:2:banana split", b"4:0:apple banana"]),
+        ([b"nana", b"apple"], [b"1:1:apple pie", b"2:0:banana split", b"4:1:apple banana"]),
+        ([b"cherry", b"plain"], [b"3:0:cherry tart", b"5:1:plain"]),
+        ([b"missing"], []),
+    ],
+)
+def test_search_any_sends_the_union_of_the_matches(spawn, needles, expected):
+    server = spawn(["apple pie", "banana split", "cherry tart", "apple banana", "plain"])
+    assert server.client().make_request(search_any(*needles), until=b"END") == [b"OK", *expected, b"END"]
+
+
+def test_search_any_bounds_its_needles(spawn):
+    server = spawn(["apple pie"])
+    assert server.client().make_request(search_any()) == [b"ERR"]
+    assert server.client().make_request(search_any(*[b"x"] * 65)) == [b"ERR"]
+    assert server.client().make_request(search_any(b"x" * 4097)) == [b"ERR"]
+    # a needle that runs past the end of the payload
+    assert server.client().make_request(prefixed(b"q", b"\x00\x00\x00\x01\x00\x00\x00\x09apple")) == [b"ERR"]
+
+
+def section(start_marker: bytes, end_marker: bytes, include_end: bool) -> bytes:
+    payload = bytes([include_end]) + start_marker + b"\n" + end_marker
+    return b"k" + len(payload).to_bytes(4, "big") + payload
+
+
+def test_section_is_the_lines_between_the_markers(spawn):
+    server = spawn(["[client]", "name=a", "[server]", "port=80", "", "host=b", "[end]", "[server]", "port=81", "[end]"])
+    assert server.client().make_request(section(b"[server]", b"[end]", False), until=b"END") == [
+        b"OK",
+        b"3:[server]",
+        b"4:port=80",
+        b"5:",
+        b"6:host=b",
+        b"END",
+    ]
+    resp = server.client().make_request(section(b"[client]", b"[server]", True), until=b"END")
+    assert resp == [b"OK", b"1:[client]", b"2:name=a", b"3:[server]", b"END"]
+    resp = server.client().make_request(section(b"[other]", b"[end]", True), until=b"END")
+    assert resp == [b"ERR 404 start marker not found"]
+    resp = server.client().make_request(section(b"port=81", b"[client]", True), until=b"END")
+    assert resp == [b"ERR 404 end marker not found"]
+    assert server.client().make_request(b"k\x00\x00\x00\x03\x01ab", until=b"END") == [b"ERR"]
+
+
+def test_uptime_goes_up(spawn):
+    server = spawn(["only line"])
+    first = server.client().make_request(b"u\x00\x00\x00\x00")
+    time.sleep(0.2)
+    second = server.client().make_request(b"u\x00\x00\x00\x00")
+    (started, first_uptime), (started_again, second_uptime) = first[1].split(), second[1].split()
+    assert started == started_again
+    assert 0 < int(first_uptime) and int(second_uptime) - int(first_uptime) >= 200
+
+
+def test_file_checksum_is_worked_out_once(spawn):
+    data = b"\xef\xbb\xbfa line\nanother \xff\r\n\nno delimiter"
+    server = spawn(data, "--skip-bom")
+    expected = [b"OK", hashlib.sha256(data).hexdigest().encode()]
+    assert server.client().make_request(b"h\x00\x00\x00\x00") == expected
+    assert server.client().make_request(b"h\x00\x00\x00\x00") == expected
+    assert server.log().count("Computing the checksum of the data file.") == 1
+
+
+def test_first_byte_histogram_counts_how_lines_start(spawn):
+    lines = ["E disk full", "I started", "", "W slow", "I ready", "", "E crashed", "I stopping", " indented"]
+    server = spawn(lines)
+    expected = [b"OK", b"empty 2", b"0x20 1", b"0x45 2", b"0x49 3", b"0x57 1", b"END"]
+    assert server.client().make_request(b"5\x00\x00\x00\x00", until=b"END") == expected
+    assert server.client().make_request(b"5\x00\x00\x00\x00", until=b"END") == expected
+    assert server.log().count("Counting the first bytes of the lines in the data file.") == 1
+
+
+def test_first_byte_histogram_only_counts_the_lines_in_the_window(spawn):
+    server = spawn(["E one", "I two", "W three", "I four", "", "E six"], "--line-range", "2:5")
+    expected = [b"OK", b"empty 1", b"0x49 2", b"0x57 1", b"END"]
+    assert server.client().make_request(b"5\x00\x00\x00\x00", until=b"END") == expected
+
+
+def test_word_count_matches_wc(spawn):
+    # wc prints "5 7 51" for this file
+    server = spawn(b"  leading and trailing  \n\n\tone\ttab\r\n   \nlast  line\n")
+    assert server.client().make_request(b"o\x00\x00\x00\x00") == [b"OK", b"5 7 51"]
+    assert server.client().make_request(b"o\x00\x00\x00\x00") == [b"OK", b"5 7 51"]
+    assert server.log().count("Counting the words in the data file.") == 1
+
+
+def test_word_count_only_counts_the_window(spawn):
+    server = spawn(b"zero\none two\nthree\nfour five six\nseven\n", "--line-range", "3:4")
+    assert server.client().make_request(b"o\x00\x00\x00\x00") == [b"OK", b"2 4 20"]
+    # a byte order mark that's skipped isn't part of the first word, or of the bytes
+    assert spawn(b"\xef\xbb\xbfone\n", "--skip-bom").client().make_request(b"o\x00\x00\x00\x00") == [b"OK", b"1 1 4"]
+    assert spawn(b"\xef\xbb\xbf one\n").client().make_request(b"o\x00\x00\x00\x00") == [b"OK", b"1 2 8"]
+
+
+@pytest.mark.parametrize(
+    "line_number, transform, expected",
+    [
+        (1, 0, b"Hello, World!"),
+        # the client strips the whitespace at the end of the response
+        (1, 1, b"  HELLO, WORLD!"),
+        (1, 2, base64.b64encode(b"  Hello, World!\t")),
+        (2, 2, base64.b64encode(b"caf\xe9 \xff\xfe")),
+    ],
+)
+def test_get_transform(spawn, line_number, transform, expected):
+    server = spawn(b"  Hello, World!\t\ncaf\xe9 \xff\xfe\n")
+    frame = b"t" + line_number.to_bytes(4, "big") + transform.to_bytes(4, "big")
+    assert server.client().make_request(frame) == [b"OK", expected]
+
+
+def test_get_transform_of_text_needs_text(spawn):
+    server = spawn(b"caf\xe9 \xff\xfe\n")
+    assert server.client().make_request(b"t\x00\x00\x00\x01\x00\x00\x00\x01") == [b"ERR 500"]
+    assert server.client().make_request(b"t\x00\x00\x00\x01\x00\x00\x00\x03") == [b"ERR"]
+
+
+@pytest.mark.parametrize("args", [[], ["--no-zero-copy"]])
+def test_range_over_the_limit_is_truncated_where_it_can_be_resumed(spawn, args):
+    lines = [f"line {n}" for n in range(1, 10)] + ["x" * 30]
+    server = spawn(lines, "--max-response-size", "20", "--truncate-large-ranges", *args)
+
+    def get_range(cmd: bytes, start: int, end: int) -> list[bytes]:
+        # a truncated response doesn't end with END, so it's read until the server hangs up
+        frame = cmd + start.to_bytes(4, "big") + end.to_bytes(4, "big")
+        client = server.client()
+        client.s.sendall(frame + bytes([checksum(frame)]) + b"\n" + Client.QUIT_FRAME)
+        client.s.settimeout(5)
+        received = b""
+        while chunk := client.s.recv(1024):
+            received += chunk
+        return [line.rstrip(b"\r") for line in received.split(b"\n")[:-1]]
+
+    # only two of the 7 byte lines fit in 20 bytes, so the client carries on from the line after
+    assert get_range(b"G", 1, 5) == [b"OK", b"line 1", b"line 2", b"TRUNCATED", b"3"]
+    assert get_range(b"G", 3, 5) == [b"OK", b"line 3", b"line 4", b"TRUNCATED", b"5"]
+    assert get_range(b"G", 5, 5) == [b"OK", b"line 5", b"END"]
+    assert get_range(b"g", 1, 5) == [b"OK", b"line 5", b"line 4", b"TRUNCATED", b"3"]
+    # a line that's over the limit on its own can't be sent at all
+    assert get_range(b"G", 10, 10) == [b"ERR 413"]
+    assert spawn(lines, "--max-response-size", "20").client().make_request(b"G\x00\x00\x00\x01\x00\x00\x00\x05") == [b"ERR 413"]
+
+
+@pytest.mark.parametrize("n, expected", [(1, [b"OK", b"third line"]), (3, [b"OK", b"first line"]), (4, [b"ERR 404"]), (0, [b"ERR 404"])])
+def test_get_from_end(spawn, n, expected):
+    server = spawn(["first line", "second line", "third line"])
+    assert server.client().make_request(b"z" + n.to_bytes(4, "big")) == expected
+
+
+@pytest.mark.parametrize("n, expected", [(1, [b"OK", b"line 5"]), (3, [b"OK", b"line 3"]), (4, [b"ERR 404"])])
+def test_get_from_end_counts_back_from_the_end_of_the_window(spawn, n, expected):
+    server = spawn([f"line {n}" for n in range(1, 11)], "--line-range", "3:5")
+    assert server.client().make_request(b"z" + n.to_bytes(4, "big")) == expected
+
+
+@pytest.mark.parametrize("line_number, expected", [(2, [b"OK remaining=3", b"line 2"]), (5, [b"OK remaining=0", b"line 5"]), (6, [b"ERR 404"])])
+def test_get_with_hint_sends_how_many_lines_remain(spawn, line_number, expected):
+    server = spawn([f"line {n}" for n in range(1, 6)])
+    assert server.client().make_request(b"v" + line_number.to_bytes(4, "big")) == expected
+
+
+@pytest.mark.parametrize("line_number, expected", [(3, [b"OK remaining=2", b"line 3"]), (5, [b"OK remaining=0", b"line 5"]), (6, [b"ERR 421"])])
+def test_get_with_hint_counts_the_lines_left_in_the_window(spawn, line_number, expected):
+    server = spawn([f"line {n}" for n in range(1, 11)], "--line-range", "3:5")
+    assert server.client().make_request(b"v" + line_number.to_bytes(4, "big")) == expected
+
+
+def test_get_with_crc_sends_the_crc_of_the_line(spawn):
+    server = spawn(b"first line\n\n  spaced  \r\nlast without a delimiter")
+    for line_number in range(1, 5):
+        client = server.client()
+        client.s.sendall(b"4" + line_number.to_bytes(4, "big") + bytes([checksum(b"4" + line_number.to_bytes(4, "big"))]) + b"\n" + Client.QUIT_FRAME)
+        client.s.settimeout(5)
+        received = b""
+        while chunk := client.s.recv(1024):
+            received += chunk
+        header, body = received.split(b"\r\n", 1)
+        assert header == f"OK crc={zlib.crc32(body):08x}".encode()
+        assert body == [b"first line\n", b"\n", b"  spaced  \r\n", b"last without a delimiter"][line_number - 1]
+
+
+CHECKSUMS = {
+    0: lambda frame: b"",
+    1: lambda frame: bytes([checksum(frame)]),
+    2: lambda frame: binascii.crc_hqx(frame, 0).to_bytes(2, "big"),
+    3: lambda frame: zlib.crc32(frame).to_bytes(4, "big"),
+}
+
+
+@pytest.mark.parametrize("algorithm, name", [(0, b"none"), (1, b"sum"), (2, b"crc16"), (3, b"crc32")])
+def test_hello_picks_the_checksum_of_the_frames_after_it(spawn, algorithm, name):
+    server = spawn(["first line", "second line"])
+    client = server.client()
+    assert client.make_request(b"a" + algorithm.to_bytes(4, "big"), quit=False) == [b"OK", name]
+    get = b"0\x00\x00\x00\x02"
+    frames = [get + CHECKSUMS[algorithm](get)]
+    expected = b"OK\r\nsecond line\n"
+    if algorithm != 0:
+        # the checksum of a frame for another line, or one that's been flipped, doesn't match
+        frames.append(b"0\x00\x00\x00\x01" + CHECKSUMS[algorithm](get))
+        frames.append(get + bytes(b ^ 0x01 for b in CHECKSUMS[algorithm](get)))
+        expected += b"ERR\r\nERR\r\n"
+    quit = b"1\x00\x00\x00\x00"
+    frames.append(quit + CHECKSUMS[algorithm](quit))
+    client.s.sendall(b"".join(frame + b"\n" for frame in frames))
+    client.s.settimeout(5)
+    received = b""
+    while chunk := client.s.recv(1024):
+        received += chunk
+    assert received == expected
+
+
+def test_json_mode_answers_gets_with_objects(spawn):
+    server = spawn(["first line", 'a "quoted" line', "last line"])
+    client = server.client()
+    assert client.make_request(b"j\x00\x00\x00\x01", quit=False, until=b"OK") == [b"OK"]
+    client.s.sendall(b"0\x00\x00\x00\x02" + bytes([checksum(b"0\x00\x00\x00\x02")]) + b"\n")
+    assert json.loads(client.recvuntil(b"}")[0]) == {"status": "ok", "line": 2, "content": 'a "quoted" line'}
+    client.s.sendall(b"0\x00\x00\x00\x04" + bytes([checksum(b"0\x00\x00\x00\x04")]) + b"\n")
+    error = json.loads(client.recvuntil(b"}")[0])
+    assert error["status"] == "error" and error["code"] == 404 and error["message"]
+    assert client.make_request(b"j\x00\x00\x00\x00", quit=False, until=b"OK") == [b"OK"]
+    assert client.make_request(b"0\x00\x00\x00\x02") == [b"OK", b'a "quoted" line']
+
+
+def test_bounds_are_the_first_and_last_lines(spawn):
+    server = spawn([f"line {n}" for n in range(1, 101)])
+    bounds = b"b\x00\x00\x00\x00"
+    assert server.client().make_request(bounds, until=b"END") == [b"OK", b"line 1", b"line 100", b"END"]
+    client = server.client()
+    assert client.make_request(b"L\x00\x00\x00:", quit=False, until=b"OK") == [b"OK"]
+    assert client.make_request(bounds, until=b"END") == [b"OK", b"1:line 1", b"100:line 100", b"END"]
+
+
+def test_bounds_of_a_single_line_file(spawn):
+    bounds = b"b\x00\x00\x00\x00"
+    server = spawn(b"only line")
+    assert server.client().make_request(bounds, until=b"END") == [b"OK", b"only line", b"only line", b"END"]
+    server = spawn(b"")
+    assert server.client().make_request(bounds, until=b"END") == [b"ERR 404"]
+
+
+def test_stats_count_the_requests_of_each_connection(spawn):
+    server = spawn([f"line {n}" for n in range(1, 11)])
+    first, second = server.client(), server.client()
+    for line_number in (1, 2, 3):
+        assert first.make_request(b"0" + line_number.to_bytes(4, "big"), quit=False)[0] == b"OK"
+    assert second.make_request(b"0\x00\x00\x00\x05", quit=False)[0] == b"OK"
+    resp = first.make_request(b"s\x00\x00\x00\x00", quit=False, until=b"END")
+    assert resp[0] == b"OK" and resp[-1] == b"END"
+    stats = {}
+    for row in resp[1:-1]:
+        conn_id, addr, requests, connected_since = row.decode().split(" ")
+        host, port = addr.rsplit(":", 1)
+        stats[(host, int(port))] = int(requests)
+        assert re.fullmatch(r"\d{4}-\d\d-\d\dT\d\d:\d\d:\d\d[+-]\d\d:\d\d", connected_since)
+    # the STATS itself is one of the first connection's requests
+    assert stats == {first.s.getsockname(): 4, second.s.getsockname(): 1}
+    first.quit()
+    second.quit()
+
+
+def test_line_numbers_prefix_a_range_including_the_last_line(spawn):
+    server = spawn(["one", "two", "three"])
+    client = server.client()
+    assert client.make_request(b"L\x00\x00\x00:", quit=False, until=b"OK") == [b"OK"]
+    get_range = b"G" + (2).to_bytes(4, "big") + (3).to_bytes(4, "big")
+    assert client.make_request(get_range, quit=False, until=b"END") == [b"OK", b"2:two", b"3:three", b"END"]
+    assert client.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"OK", b"1:one"]
+    # the separator is configurable and a separator of 0 turns the prefix off
+    assert client.make_request(b"L\x00\x00\x00|", quit=False, until=b"OK") == [b"OK"]
+    assert client.make_request(b"0\x00\x00\x00\x03", quit=False) == [b"OK", b"3|three"]
+    assert client.make_request(b"L\x00\x00\x00\x00", quit=False, until=b"OK") == [b"OK"]
+    assert client.make_request(get_range, until=b"END") == [b"OK", b"two", b"three", b"END"]
+
+
+def test_sequential_connection_ids(spawn):
+    server = spawn(["only line"], "--sequential-conn-ids", "--log-level", "debug")
+    for _ in range(2):
+        server.client().make_request(b"0\x00\x00\x00\x01")
+    ids = [line.split(" - ")[0].split()[-1] for line in server.log().splitlines() if " - GET 1" in line]
+    first = int(ids[0].removeprefix("conn-"))
+    assert ids == [f"conn-{first}", f"conn-{first + 1}"]
+
+
+def test_verify_index_ok(spawn):
+    server = spawn(["aa", "bb", "cc"])
+    assert server.client().make_request(b"V\x00\x00\x00\x00") == [b"OK", b"ok"]
+
+
+def test_verify_index_detects_a_corrupted_entry(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_text("aa\nbb\ncc\n")
+    # the index format followed by a msgpack map of line number to offset where line 3 should start at 6
+    (tmp_path / "db.txt.index").write_bytes(bytes([2, 0x84, 1, 0, 2, 3, 3, 7, 4, 9]))
+    server = Server(db_file)
+    try:
+        assert server.client().make_request(b"V\x00\x00\x00\x00") == [b"OK", b"mismatch at line 3"]
+    finally:
+        server.kill()
+
+
+def test_connection_keeps_serving_the_file_it_opened_after_it_is_replaced(spawn):
+    server = spawn(["old line 1", "old line 2", "old line 3"])
+    client = server.client()
+    assert client.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"OK", b"old line 1"]
+    replacement = server.db_file.with_name("replacement.txt")
+    replacement.write_text("new line 1\nnew line 2\nnew line 3\n")
+    replacement.replace(server.db_file)
+    assert client.make_request(b"0\x00\x00\x00\x02", quit=False) == [b"OK", b"old line 2"]
+    assert client.make_request(b"0\x00\x00\x00\x03") == [b"OK", b"old line 3"]
+
+
+def test_reload_is_followed_only_by_the_connections_that_asked_to(spawn):
+    server = spawn(["old line 1", "old line 2", "old line 3"])
+    snapshot = server.client()
+    following = server.client()
+    # 0x100 on top of the sum that connections start with
+    assert following.make_request(b"a\x00\x00\x01\x01", quit=False) == [b"OK", b"sum follow"]
+    for client in (snapshot, following):
+        assert client.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"OK", b"old line 1"]
+    replacement = server.db_file.with_name("replacement.txt")
+    replacement.write_text("new line 1\nnew line 2\nnew line 3\nnew line 4\n")
+    replacement.replace(server.db_file)
+    server.proc.send_signal(signal.SIGHUP)
+    server.wait_for_log("Reloaded")
+    assert following.make_request(b"0\x00\x00\x00\x02", quit=False) == [b"OK", b"new line 2"]
+    assert following.make_request(b"0\x00\x00\x00\x04") == [b"OK", b"new line 4"]
+    assert snapshot.make_request(b"0\x00\x00\x00\x02", quit=False) == [b"OK", b"old line 2"]
+    assert snapshot.make_request(b"0\x00\x00\x00\x04") == [b"ERR 404"]
+    # connections made after the reload get the new file
+    assert server.client().make_request(b"0\x00\x00\x00\x04") == [b"OK", b"new line 4"]
+
+
+def test_failed_reload_keeps_serving_the_file_from_before(spawn):
+    server = spawn(["old line 1", "old line 2"])
+    client = server.client()
+    assert client.make_request(b"a\x00\x00\x01\x01", quit=False) == [b"OK", b"sum follow"]
+    assert client.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"OK", b"old line 1"]
+    server.db_file.unlink()
+    server.proc.send_signal(signal.SIGHUP)
+    server.wait_for_log("Could not reload")
+    assert client.make_request(b"0\x00\x00\x00\x02") == [b"OK", b"old line 2"]
+
+
+
+def open_handles(server: Server) -> int:
+    """How many of the server's descriptors are open on its data file."""
+    fds = Path(f"/proc/{server.proc.pid}/fd")
+    return sum(1 for fd in fds.iterdir() if os.path.realpath(fd) == str(server.db_file))
+
+
+@pytest.mark.skipif(not Path("/proc/self/fd").exists(), reason="needs /proc to count descriptors")
+def test_idle_connection_releases_the_data_file(spawn):
+    server = spawn(["line 1", "line 2", "line 3"], "--release-file-idle-ms", "200")
+    client = server.client()
+    assert client.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"OK", b"line 1"]
+    assert open_handles(server) >= 1
+    time.sleep(0.5)
+    assert open_handles(server) == 0
+    # the connection is still open, and the next GET opens the file again
+    assert client.make_request(b"0\x00\x00\x00\x02", quit=False) == [b"OK", b"line 2"]
+    assert open_handles(server) == 1
+    # a file put in its place once it's been released isn't served in its place
+    time.sleep(0.5)
+    replacement = server.db_file.with_name("replacement.txt")
+    replacement.write_text("new line 1\nnew line 2\nnew line 3\n")
+    replacement.replace(server.db_file)
+    assert client.make_request(b"0\x00\x00\x00\x03") == [b"ERR 500"]
+    assert "was replaced after the session opened it" in server.log()
+
+
+
+@pytest.mark.parametrize("args", [[], ["--no-zero-copy"]])
+def test_range_from_a_replaced_file_is_only_an_error(spawn, args):
+    server = spawn(["line 1", "line 2", "line 3"], "--release-file-idle-ms", "100", *args)
+    client = server.client()
+    assert client.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"OK", b"line 1"]
+    time.sleep(0.3)
+    replacement = server.db_file.with_name("replacement.txt")
+    replacement.write_text("new line 1\nnew line 2\nnew line 3\n")
+    replacement.replace(server.db_file)
+    client.s.sendall(b"G\x00\x00\x00\x01\x00\x00\x00\x03" + bytes([checksum(b"G\x00\x00\x00\x01\x00\x00\x00\x03")]) + b"\n" + Client.QUIT_FRAME)
+    client.s.settimeout(5)
+    received = b""
+    while chunk := client.s.recv(1024):
+        received += chunk
+    # no OK goes ahead of the error
+    assert received == b"ERR 500\r\n"
+
+
+@pytest.mark.skipif(not Path("/proc/self/fd").exists(), reason="needs /proc to count descriptors")
+def test_exists_answers_from_the_index(spawn):
+    server = spawn(["line 1", "line 2", "line 3"], "--release-file-idle-ms", "100")
+    client = server.client()
+    assert client.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"OK", b"line 1"]
+    time.sleep(0.3)
+    assert open_handles(server) == 0
+    for line_number, exists in [(1, b"true"), (3, b"true"), (4, b"false"), (0, b"false")]:
+        request = b"8" + line_number.to_bytes(4, "big")
+        response = b"OK " + exists
+        assert client.make_request(request, until=response, quit=False) == [response]
+    # the released file wasn't opened again to answer them
+    assert open_handles(server) == 0
+    windowed = spawn([f"line {n}" for n in range(1, 11)], "--line-range", "3:5").client()
+    assert windowed.make_request(b"8\x00\x00\x00\x02", until=b"OK false") == [b"OK false"]
+
+
+@pytest.mark.skipif(not Path("/dev/full").exists(), reason="needs a device that is always full")
+def test_index_that_cant_be_written_whole_is_an_error(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_text("aa\nbb\ncc\n")
+    # every write to the index fails as it would on a full disk
+    (tmp_path / "db.txt.index.tmp").symlink_to("/dev/full")
+    proc = subprocess.Popen(
+        [str(BINARY), str(db_file), "--port", str(free_port())],
+        cwd=tmp_path,
+        stdout=subprocess.DEVNULL,
+        stderr=subprocess.PIPE,
+    )
+    _, stderr = proc.communicate(timeout=10)
+    assert proc.returncode != 0
+    assert b"could not save the index" in stderr and b"No space left on device" in stderr
+    assert not (tmp_path / "db.txt.index").exists()
+    assert not (tmp_path / "db.txt.index.tmp").exists()
+    server = Server(db_file)
+    try:
+        assert "Creating a new index for the database file" in server.log()
+        assert server.client().make_request(b"0\x00\x00\x00\x02") == [b"OK", b"bb"]
+    finally:
+        server.kill()
+
+
+def test_fsynced_index_is_saved_whole_and_loaded_again(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_text("aa\nbb\ncc\n")
+    index_file = tmp_path / "db.txt.index"
+    server = Server(db_file, "--fsync-index")
+    server.kill()
+    assert "Flushed the index to disk." in server.log()
+    # the index is format 2 followed by the map, and the temporary file was renamed into place
+    blob = index_file.read_bytes()
+    assert blob[0] == 2
+    assert unpack_offsets(blob[1:]) == {1: 0, 2: 3, 3: 6, 4: 9}
+    assert not (tmp_path / "db.txt.index.tmp").exists()
+    server = Server(db_file, "--fsync-index")
+    try:
+        assert f"Loading the saved index from file: {index_file}" in server.log()
+        assert server.client().make_request(b"0\x00\x00\x00\x02") == [b"OK", b"bb"]
+    finally:
+        server.kill()
+
+
+@pytest.mark.parametrize(
+    "saved_index",
+    [
+        # saved before the format was written, and with line 3 at the wrong offset
+        bytes([0x84, 1, 0, 2, 3, 3, 7, 4, 9]),
+        # a format from some other release
+        bytes([99, 0x84, 1, 0, 2, 3, 3, 7, 4, 9]),
+        b"not msgpack at all",
+    ],
+)
+def test_incompatible_saved_index_is_rebuilt(tmp_path, saved_index):
+    db_file = tmp_path / "db.txt"
+    db_file.write_text("aa\nbb\ncc\n")
+    index_file = tmp_path / "db.txt.index"
+    index_file.write_bytes(saved_index)
+    server = Server(db_file)
+    try:
+        assert f"Could not load the saved index from file {index_file}, rebuilding it" in server.log()
+        assert server.client().make_request(b"V\x00\x00\x00\x00") == [b"OK", b"ok"]
+        assert server.client().make_request(b"0\x00\x00\x00\x03") == [b"OK", b"cc"]
+        assert index_file.read_bytes()[0] == 2
+    finally:
+        server.kill()
+
+
+def test_index_saved_for_a_longer_file_is_rebuilt(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_text("aa\nbb\ncc\ndd\n")
+    index_file = tmp_path / "db.txt.index"
+    Server(db_file).kill()
+    # the same name reused for a shorter file keeps the longer file's index
+    db_file.write_text("aa\nbb\n")
+    server = Server(db_file)
+    try:
+        assert f"The saved index in file {index_file} ends at byte 12 but the data file is 6 bytes" in server.log()
+        assert server.client().make_request(b"C\x00\x00\x00\x00") == [b"OK", b"2"]
+        assert unpack_offsets(index_file.read_bytes()[1:]) == {1: 0, 2: 3, 3: 6}
+    finally:
+        server.kill()
+
+
+def read_syscalls(server: Server) -> int:
+    """The number of read syscalls the server process has made so far."""
+    io = Path(f"/proc/{server.proc.pid}/io").read_text()
+    return next(int(line.split()[1]) for line in io.splitlines() if line.startswith("syscr:"))
+
+
+def test_larger_read_buffer_makes_fewer_reads_of_a_long_line(spawn):
+    long_line = "x" * (4 * 1024 * 1024)
+    reads = []
+    for args in [[], ["--read-buffer-size", str(1024 * 1024)]]:
+        server = spawn([long_line, "short"], *args)
+        client = server.client()
+        before = read_syscalls(server)
+        frame = b"0\x00\x00\x00\x01"
+        client.s.sendall(frame + bytes([checksum(frame)]) + b"\n")
+        expected = len(b"OK\r\n") + len(long_line) + 1
+        received = 0
+        while received < expected:
+            received += len(client.s.recv(1024 * 1024))
+        reads.append(read_syscalls(server) - before)
+        client.quit()
+    default_reads, large_buffer_reads = reads
+    assert default_reads >= 4 * 1024 * 1024 // (8 * 1024)
+    assert large_buffer_reads * 10 < default_reads
+
+
+@pytest.mark.parametrize("args, first_line", [([], b"\xef\xbb\xbffirst"), (["--skip-bom"], b"first")])
+def test_byte_order_mark(spawn, args, first_line):
+    server = spawn(b"\xef\xbb\xbffirst\nsecond\n", *args)
+    assert server.client().make_request(b"0\x00\x00\x00\x01") == [b"OK", first_line]
+    assert server.client().make_request(b"0\x00\x00\x00\x02") == [b"OK", b"second"]
+    assert server.client().make_request(prefixed(b"I", first_line), until=b"END") == [b"OK", b"1", b"END"]
+    assert server.client().make_request(b"V\x00\x00\x00\x00") == [b"OK", b"ok"]
+
+
+def test_saved_index_is_rebuilt_when_the_bom_setting_changes(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_bytes(b"\xef\xbb\xbffirst\nsecond\n")
+    Server(db_file).kill()
+    server = Server(db_file, "--skip-bom")
+    try:
+        assert "doesn't start at byte 3, rebuilding it" in server.log()
+        assert server.client().make_request(b"0\x00\x00\x00\x01") == [b"OK", b"first"]
+    finally:
+        server.kill()
+
+
+def test_get_over_ipv6(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_text("first\nsecond\n")
+    server = Server(db_file, "--bind-address", "::1", host="::1")
+    try:
+        assert server.client().make_request(b"0\x00\x00\x00\x02") == [b"OK", b"second"]
+        # without --dual-stack an IPv6 listener doesn't take IPv4 connections
+        with pytest.raises(ConnectionRefusedError):
+            Client(server.port, "127.0.0.1")
+    finally:
+        server.kill()
+
+
+def test_dual_stack_takes_both_families(tmp_path):
+    db_file = tmp_path / "db.txt"
+    db_file.write_text("first\nsecond\n")
+    server = Server(db_file, "--bind-address", "::", "--dual-stack", host="::1")
+    try:
+        assert server.client().make_request(b"0\x00\x00\x00\x01") == [b"OK", b"first"]
+        assert Client(server.port, "127.0.0.1").make_request(b"0\x00\x00\x00\x02") == [b"OK", b"second"]
+    finally:
+        server.kill()
+
+
+def test_unreadable_database_file_fails_the_index_build(tmp_path):
+    # a directory opens but every read of it fails, which used to leave an empty index, so it isn't
+    # opened at all
+    db_dir = tmp_path / "db.txt"
+    db_dir.mkdir()
+    started = subprocess.run(
+        [str(BINARY), str(db_dir), "--port", str(free_port())], cwd=tmp_path, capture_output=True, timeout=10
+    )
+    assert started.returncode != 0
+    assert f"{db_dir} is not a regular file, it's a directory" in started.stderr.decode()
+
+
+@pytest.mark.skipif(not hasattr(os, "mkfifo"), reason="needs named pipes")
+def test_named_pipe_is_not_served(tmp_path):
+    # opening a pipe blocks until something writes to it, so this would hang if it were opened
+    fifo = tmp_path / "db.txt"
+    os.mkfifo(fifo)
+    started = subprocess.run(
+        [str(BINARY), str(fifo), "--port", str(free_port())], cwd=tmp_path, capture_output=True, timeout=10
+    )
+    assert started.returncode != 0
+    assert f"{fifo} is not a regular file, it's a named pipe" in started.stderr.decode()
+    assert not (tmp_path / "db.txt.index").exists()
+
+
+def test_write_timeout_disconnects_a_client_that_stops_reading(spawn):
+    server = spawn(["x" * 1023] * 1024, "--write-timeout-ms", "200")
+    stalled = Client(server.port)
+    # ask for far more than the socket buffers can hold and never read any of it
+    stalled.s.sendall((b"A\x00\x00\x00\x00" + bytes([checksum(b"A")]) + b"\n") * 32)
+    server.wait_for_log("Timed out writing to the client after 200ms")
+    # the stalled client didn't wedge the server
+    assert server.client().make_request(b"0\x00\x00\x00\x01")[0] == b"OK"
+    stalled.s.close()
+
+
+@pytest.mark.parametrize("args", [[], ["--pipeline-window", "4"]])
+def test_pipelined_gets_respond_in_request_order(spawn, args):
+    lines = [f"line {n}" for n in range(1, 9)]
+    server = spawn(lines, *args)
+    requested = [3, 1, 8, 99, 2, 7, 5, 4, 6]
+    frames = [b"0" + n.to_bytes(4, "big") for n in requested]
+    # a non-GET in the middle of the batch is answered in its place too
+    frames.insert(5, b"C\x00\x00\x00\x00")
+    client = server.client()
+    client.s.sendall(b"".join(frame + bytes([checksum(frame)]) + b"\n" for frame in frames))
+    expected = [b"OK\r\nline 3\n", b"OK\r\nline 1\n", b"OK\r\nline 8\n", b"ERR 404\r\n", b"OK\r\nline 2\n"]
+    expected += [b"OK\r\n8\r\n", b"OK\r\nline 7\n", b"OK\r\nline 5\n", b"OK\r\nline 4\n", b"OK\r\nline 6\n"]
+    data = b""
+    while len(data) < len(b"".join(expected)):
+        chunk = client.s.recv(65536)
+        assert chunk
+        data += chunk
+    client.quit()
+    assert data == b"".join(expected)
+
+
+@pytest.mark.parametrize("max_reads", [1, 2])
+def test_max_concurrent_reads_caps_the_reads_in_flight(spawn, max_reads):
+    lines = [f"line {n}" for n in range(1, 10)]
+    server = spawn(lines, "--max-concurrent-reads", str(max_reads), "--pipeline-window", "8", "--log-level", "debug")
+    # line 10 would put the delimiter in its frame
+    requested = [n % 9 + 1 for n in range(200)]
+    frames = b"".join(b"0" + n.to_bytes(4, "big") + bytes([checksum(b"0" + n.to_bytes(4, "big"))]) + b"\n" for n in requested)
+    expected = b"".join(f"OK\r\nline {n}\n".encode() for n in requested)
+    responses = []
+
+    def pipeline():
+        client = server.client()
+        client.s.sendall(frames)
+        data = b""
+        while len(data) < len(expected):
+            chunk = client.s.recv(65536)
+            if not chunk:
+                break
+            data += chunk
+        client.quit()
+        responses.append(data)
+
+    threads = [threading.Thread(target=pipeline) for _ in range(8)]
+    for thread in threads:
+        thread.start()
+    for thread in threads:
+        thread.join(timeout=10)
+    assert responses == [expected] * len(threads)
+    peaks = [int(peak) for peak in re.findall(r"The most reads in flight at once is now (\d+)\.", server.log())]
+    assert peaks and max(peaks) <= max_reads
+
+
+LOG_LINES = ["2024-01-01 INFO up", "2024-01-01 WARN disk", "2024-01-02 INFO up", "2024", "2024-01-01"]
+
+
+@pytest.mark.parametrize(
+    "prefix, expected",
+    [
+        (b"2024-01-01", [b"1:2024-01-01 INFO up", b"2:2024-01-01 WARN disk", b"5:2024-01-01"]),
+        (b"2023", []),
+        # line 4 is shorter than the prefix so it can't start with it
+        (b"2024-01-02 INFO", [b"3:2024-01-02 INFO up"]),
+    ],
+)
+def test_filter_prefix(spawn, prefix, expected):
+    server = spawn(LOG_LINES)
+    resp = server.client().make_request(prefixed(b"B", prefix), until=b"END")
+    assert resp == [b"OK", *expected, b"END"]
+
+
+def test_filter_prefix_over_the_response_limit(spawn):
+    server = spawn(LOG_LINES, "--max-response-size", "32")
+    assert server.client().make_request(prefixed(b"B", b"2024"), until=b"END") == [b"ERR 413"]
+
+
+@pytest.mark.parametrize(
+    "offset, expected",
+    [
+        (0, [b"OK", b"1:first"]),
+        (6, [b"OK", b"2:second"]),
+        # the middle of a line and its delimiter are both in the line
+        (9, [b"OK", b"2:second"]),
+        (12, [b"OK", b"2:second"]),
+        (13, [b"OK", b"3:third"]),
+        (18, [b"OK", b"3:third"]),
+        (19, [b"ERR 404"]),
+        (2**40, [b"ERR 404"]),
+    ],
+)
+def test_line_at_byte(spawn, offset, expected):
+    server = spawn(["first", "second", "third"])
+    assert server.client().make_request(b"Y" + offset.to_bytes(8, "big")) == expected
+
+
+@pytest.mark.parametrize(
+    "prefix, count",
+    [(b"2024-01-01", 3), (b"2024", 5), (b"2023", 0), (b"2024-01-02 INFO", 1), (b"", 5)],
+)
+def test_count_prefix(spawn, prefix, count):
+    server = spawn(LOG_LINES)
+    assert server.client().make_request(prefixed(b"T", prefix)) == [b"OK", str(count).encode()]
+
+
+@pytest.mark.parametrize(
+    "start, end, expected",
+    [
+        # first\n is 0..6, second\n 6..13, third\n 13..19 and fourth\n 19..26
+        (0, 26, [b"1:first", b"2:second", b"3:third", b"4:fourth"]),
+        (6, 19, [b"2:second", b"3:third"]),
+        # second and fourth are cut by the ends of the range
+        (7, 25, [b"3:third"]),
+        (7, 18, []),
+        (3, 2**40, [b"2:second", b"3:third", b"4:fourth"]),
+        (26, 2**40, []),
+    ],
+)
+def test_get_byte_range(spawn, start, end, expected):
+    server = spawn(["first", "second", "third", "fourth"])
+    frame = b"E" + start.to_bytes(8, "big") + end.to_bytes(8, "big")
+    assert server.client().make_request(frame, until=b"END") == [b"OK", *expected, b"END"]
+
+
+@pytest.mark.parametrize(
+    "start, end, expected",
+    [
+        # the range is clipped to lines 2 and 3
+        (0, 26, [b"OK", b"2:second", b"3:third", b"END"]),
+        (7, 25, [b"OK", b"3:third", b"END"]),
+        # lines that are all in another shard
+        (0, 6, [b"ERR 421"]),
+        (19, 26, [b"ERR 421"]),
+        (7, 18, [b"OK", b"END"]),
+    ],
+)
+def test_get_byte_range_only_sends_the_lines_in_the_window(spawn, start, end, expected):
+    server = spawn(["first", "second", "third", "fourth"], "--line-range", "2:3")
+    frame = b"E" + start.to_bytes(8, "big") + end.to_bytes(8, "big")
+    assert server.client().make_request(frame, until=b"END") == expected
+
+
+@pytest.mark.parametrize("args", [[], ["--find-cache"]])
+def test_find_exact_lines(spawn, args):
+    server = spawn(["apple", "banana", "apple", "apple pie"], *args)
+    client = server.client()
+    assert client.make_request(prefixed(b"I", b"banana"), quit=False, until=b"END") == [b"OK", b"2", b"END"]
+    assert client.make_request(prefixed(b"I", b"apple"), quit=False, until=b"END") == [b"OK", b"1", b"3", b"END"]
+    assert client.make_request(prefixed(b"I", b"cherry"), until=b"END") == [b"OK", b"END"]
+
+
+@pytest.mark.parametrize("args", [[], ["--find-cache"]])
+def test_scans_only_find_lines_in_the_window(spawn, args):
+    server = spawn(["apple", "banana", "apple", "apple pie", "apple", "banana"], "--line-range", "2:4", *args)
+    client = server.client()
+    assert client.make_request(prefixed(b"I", b"apple"), quit=False, until=b"END") == [b"OK", b"3", b"END"]
+    assert client.make_request(prefixed(b"I", b"banana"), quit=False, until=b"END") == [b"OK", b"2", b"END"]
+    assert client.make_request(prefixed(b"B", b"apple"), quit=False, until=b"END") == [b"OK", b"3:apple", b"4:apple pie", b"END"]
+    assert client.make_request(prefixed(b"T", b"apple"), quit=False) == [b"OK", b"2"]
+    assert client.make_request(prefixed(b"R", b"^b"), until=b"END") == [b"OK", b"2", b"END"]
+    # a window past the end of the file has no lines to find
+    past = spawn(["apple", "banana"], "--line-range", "5:9", *args).client()
+    assert past.make_request(prefixed(b"T", b""), quit=False) == [b"OK", b"0"]
+    assert past.make_request(prefixed(b"I", b"apple"), until=b"END") == [b"OK", b"END"]
+
+
+def test_empty_file_has_no_lines(spawn):
+    server = spawn(b"")
+    client = server.client()
+    assert client.make_request(b"C\x00\x00\x00\x00", quit=False) == [b"OK", b"0"]
+    assert client.make_request(b"0\x00\x00\x00\x01", quit=False) == [b"ERR 404"]
+    assert client.make_request(b"A\x00\x00\x00\x00", until=b"END") == [b"OK", b"END"]
+
+
+def test_get_past_the_last_line(spawn):
+    server = spawn(["first", "last"])
+    client = server.client()
+    assert client.make_request(b"C\x00\x00\x00\x00", quit=False) == [b"OK", b"2"]
+    assert client.make_request(b"0\x00\x00\x00\x02", quit=False) == [b"OK", b"last"]
+    assert client.make_request(b"0\x00\x00\x00\x03") == [b"ERR 404"]
+
+
+def test_trace_pairs_a_request_with_its_response(spawn):
+    server = spawn(["only line"], "--trace-requests")
+    server.client().make_request(b"0\x00\x00\x00\x01")
+    server.wait_for_log(" - GET 1")
+    conn_id = next(line for line in server.log().splitlines() if line.endswith(" - GET 1")).split()[-4]
+    server.wait_for_log(f"{conn_id} #1 response")
+    lines = server.log().splitlines()
+    request = next(line for line in lines if f"[TRACE] {conn_id} #1 request" in line)
+    response = next(line for line in lines if f"[TRACE] {conn_id} #1 response" in line)
+    assert request.endswith("#1 request 7 bytes: 0\\x00\\x00\\x00\\x011\\n")
+    response_len = len(b"OK\r\nonly line\n")
+    assert f"#1 response {response_len} bytes in " in response